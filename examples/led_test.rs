@@ -0,0 +1,76 @@
+use std::thread;
+use std::time::Duration;
+
+use flexi_logger::{colored_with_thread, Logger, WriteMode};
+use log::{error, info};
+
+use rpi_cube::hal::{Backend, DevMem, Simulated};
+use rpi_cube::timing::WS2812B;
+use rpi_cube::{LedConfig, LedDriver};
+
+// how many LEDs are actually in each channel
+const CHAN_LED_COUNT: usize = 2;
+
+fn main() {
+    let _logger = Logger::try_with_str("info")
+        .unwrap()
+        .write_mode(WriteMode::Direct)
+        .format(colored_with_thread)
+        .use_utc()
+        .start()
+        .unwrap();
+
+    // --simulate runs everything against an in-process register file so the
+    // pipeline can be exercised off a Pi
+    let result = if std::env::args().any(|arg| arg == "--simulate") {
+        run(Simulated::new())
+    } else {
+        // check if running as root and exit if not
+        if !is_root() {
+            error!("You need to be root to run this program.");
+            std::process::exit(1);
+        }
+
+        DevMem::new().and_then(run)
+    };
+
+    if let Err(err) = result {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run<B: Backend>(backend: B) -> rpi_cube::Result<()> {
+    let timing = WS2812B.solve_for(backend.platform().soc.smi_source_hz())?;
+    info!("{}", timing);
+
+    let config = LedConfig::builder()
+        .leds_per_channel(CHAN_LED_COUNT)
+        .timing(&timing)
+        .build()?;
+    let mut leds = LedDriver::with_backend(backend, config)?;
+
+    info!("Starting LED test...");
+
+    loop {
+        info!("BLUE/RED");
+        for chan in 0..leds.nchans() {
+            leds.channel_mut(chan).copy_from_slice(&[0x0000FF, 0xFF0000]);
+        }
+        leds.show()?;
+
+        thread::sleep(Duration::from_secs(1));
+
+        info!("RED/BLUE");
+        for chan in 0..leds.nchans() {
+            leds.channel_mut(chan).copy_from_slice(&[0xFF0000, 0x0000FF]);
+        }
+        leds.show()?;
+
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
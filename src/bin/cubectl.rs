@@ -0,0 +1,72 @@
+//! Sends a command to the LED daemon.
+//!
+//! ```text
+//! cubectl [--socket PATH] status
+//! cubectl [--socket PATH] blank
+//! cubectl [--socket PATH] reload
+//! cubectl [--socket PATH] brightness LEVEL
+//! cubectl [--socket PATH] effect NAME [KEY=VALUE ...]
+//! ```
+//!
+//! Effect settings are JSON values where they parse as one and strings
+//! otherwise, e.g. `cubectl effect breathe color=#FF8000 period=2`.
+
+use serde_json::{Map, Value};
+
+use rpi_cube::daemon::{Client, Command, Response, DEFAULT_SOCKET};
+
+const USAGE: &str = "usage: cubectl [--socket PATH] status | blank | reload | brightness LEVEL | effect NAME [KEY=VALUE ...]";
+
+fn main() {
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    let socket = match args.iter().position(|arg| arg == "--socket") {
+        Some(n) if n + 1 < args.len() => {
+            let socket = args.remove(n + 1);
+            args.remove(n);
+            socket
+        }
+        Some(_) => exit(USAGE),
+        None => DEFAULT_SOCKET.to_string(),
+    };
+
+    let command = parse(&args).unwrap_or_else(|err| exit(&err));
+    let response = Client::connect(&socket)
+        .and_then(|mut client| client.send(&command))
+        .unwrap_or_else(|err| exit(&format!("{}: {}", socket, err)));
+
+    match response {
+        Response::Ok => {}
+        Response::Status(status) => println!("{}", serde_json::to_string_pretty(&status).unwrap()),
+        Response::Error { message } => exit(&message),
+    }
+}
+
+fn parse(args: &[String]) -> Result<Command, String> {
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    match args[..] {
+        ["status"] => Ok(Command::Status),
+        ["blank"] => Ok(Command::Blank),
+        ["reload"] => Ok(Command::Reload),
+        ["brightness", level] => {
+            let brightness = level.parse().map_err(|_| format!("invalid brightness {:?}", level))?;
+            Ok(Command::Brightness { brightness })
+        }
+        ["effect", name, ref settings @ ..] => {
+            let mut effect = Map::new();
+            effect.insert("name".to_string(), Value::from(name));
+            for setting in settings {
+                let (key, value) = setting.split_once('=').ok_or_else(|| format!("expected KEY=VALUE, got {:?}", setting))?;
+                let value = serde_json::from_str(value).unwrap_or_else(|_| Value::from(value));
+                effect.insert(key.to_string(), value);
+            }
+            let effect = serde_json::from_value(Value::Object(effect)).map_err(|err| err.to_string())?;
+            Ok(Command::Effect { effect })
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn exit(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
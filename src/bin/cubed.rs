@@ -0,0 +1,74 @@
+//! The LED daemon, `cubed [--simulate] [config]`.
+//!
+//! Owns the SMI, DMA and GPIO and takes commands on the control socket set
+//! in the config (`/etc/rpi-cube.json` by default) until SIGINT or SIGTERM.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use flexi_logger::{colored_with_thread, Logger, WriteMode};
+use log::{error, info};
+
+use rpi_cube::daemon::{Daemon, DEFAULT_CONFIG};
+use rpi_cube::hal::{Backend, DevMem, Simulated};
+
+static RUNNING: AtomicBool = AtomicBool::new(true);
+
+extern "C" fn stop(_signal: libc::c_int) {
+    RUNNING.store(false, Ordering::Relaxed);
+}
+
+fn main() {
+    let _logger = Logger::try_with_env_or_str("info")
+        .unwrap()
+        .write_mode(WriteMode::Direct)
+        .format(colored_with_thread)
+        .use_utc()
+        .start()
+        .unwrap();
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let simulate = args.iter().any(|arg| arg == "--simulate");
+    let config = args.iter().find(|arg| !arg.starts_with("--")).map_or(DEFAULT_CONFIG, |arg| arg.as_str());
+
+    // leave the loop on the next frame so the LEDs are blanked and the
+    // socket removed on the way out
+    unsafe {
+        libc::signal(libc::SIGINT, stop as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, stop as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+
+    // --simulate runs everything against an in-process register file so the
+    // daemon can be tried off a Pi
+    let result = if simulate {
+        run(Simulated::new(), config)
+    } else {
+        if !is_root() {
+            error!("You need to be root to run this program.");
+            std::process::exit(1);
+        }
+
+        DevMem::new().and_then(|backend| run(backend, config))
+    };
+
+    if let Err(err) = result {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run<B: Backend>(backend: B, config: &str) -> rpi_cube::Result<()> {
+    let mut daemon = Daemon::new(backend, config)?;
+    info!(
+        "{} channels of {} LEDs at {} fps",
+        daemon.leds().nchans(),
+        daemon.leds().nleds(),
+        daemon.config().fps
+    );
+    daemon.run(&RUNNING)?;
+    info!("stopped");
+    Ok(())
+}
+
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
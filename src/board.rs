@@ -0,0 +1,233 @@
+use std::fs;
+use std::ops::Range;
+
+use rpi_mailbox::{get_board_revision, Mailbox};
+use thiserror::Error;
+
+use crate::error::Result;
+use crate::PERIPHERAL_BUS_ADDRESS;
+
+const DT_RANGES: &str = "/proc/device-tree/soc/ranges";
+const CPUINFO: &str = "/proc/cpuinfo";
+
+// revision code fields
+const REV_NEW_STYLE: u32       = 1 << 23;
+const REV_PROCESSOR_SHIFT: u32 = 12;
+const REV_PROCESSOR_MASK: u32  = 0xf;
+const REV_TYPE_SHIFT: u32      = 4;
+const REV_TYPE_MASK: u32       = 0xff;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum BoardError {
+    #[error("unsupported board revision {0:#x}")]
+    UnsupportedRevision(u32),
+    #[error("unsupported SoC (processor {processor} in revision {revision:#x})")]
+    UnsupportedSoc { revision: u32, processor: u32 },
+    #[error("malformed device tree ranges")]
+    InvalidRanges,
+    #[error("can't find the board revision")]
+    NoRevision,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Soc {
+    /// Pi 1 and Zero.
+    Bcm2835,
+    /// Pi 2.
+    Bcm2836,
+    /// Pi 3 and Zero 2 W (and the later Pi 2).
+    Bcm2837,
+    /// Pi 4 and 400.
+    Bcm2711,
+}
+
+impl Soc {
+    fn from_revision(revision: u32) -> std::result::Result<Self, BoardError> {
+        // old style revision codes were only ever used on the Pi 1
+        if revision & REV_NEW_STYLE == 0 {
+            return match revision & 0xffff {
+                0x0002..=0x0015 => Ok(Soc::Bcm2835),
+                _ => Err(BoardError::UnsupportedRevision(revision)),
+            };
+        }
+
+        match (revision >> REV_PROCESSOR_SHIFT) & REV_PROCESSOR_MASK {
+            0 => Ok(Soc::Bcm2835),
+            1 => Ok(Soc::Bcm2836),
+            2 => Ok(Soc::Bcm2837),
+            3 => Ok(Soc::Bcm2711),
+            processor => Err(BoardError::UnsupportedSoc { revision, processor }),
+        }
+    }
+
+    /// Where the peripherals are in the ARM physical address space.
+    pub fn peripheral_base(self) -> usize {
+        match self {
+            Soc::Bcm2835 => 0x20000000,
+            Soc::Bcm2836 | Soc::Bcm2837 => 0x3F000000,
+            Soc::Bcm2711 => 0xFE000000,
+        }
+    }
+
+    /// Alias uncached (`MEM_FLAG_DIRECT`) VC memory is seen through on the
+    /// bus, the physical address is the bus address without it.
+    pub fn dram_bus_alias(self) -> usize {
+        // the same on every SoC, the BCM2711 only adds the 40-bit addresses
+        // of the DMA4 engines which aren't used here
+        0xC0000000
+    }
+
+    /// Frequency of PLLD_PER, the clock the SMI clock is divided down from.
+    pub fn smi_source_hz(self) -> u32 {
+        match self {
+            Soc::Bcm2711 => 750_000_000,
+            _ => 500_000_000,
+        }
+    }
+
+    /// DMA channels with the legacy register layout and 32-bit control
+    /// blocks that `Dma` drives. Channel 15 lives in a register block of its
+    /// own and channels 11-14 of the BCM2711 are DMA4 engines.
+    ///
+    /// There's no 40-bit DMA4 support, so on the BCM2711 transfers only
+    /// reach the first 1GB of RAM. The buffers all come from the firmware
+    /// (`VcMem`), which allocates below that, but memory above 1GB on a
+    /// 4B or CM4 can't be a DMA source or destination.
+    pub fn dma_channels(self) -> Range<u8> {
+        match self {
+            Soc::Bcm2711 => 0..11,
+            _ => 0..15,
+        }
+    }
+}
+
+/// The SoC the driver is running on and where its peripherals live.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Platform {
+    pub soc: Soc,
+    /// Board revision code, see the Raspberry Pi documentation.
+    pub revision: u32,
+    pub peripheral_base: usize,
+    pub dram_bus_alias: usize,
+}
+
+impl Platform {
+    /// Works out the platform from the board revision (from the mailbox, or
+    /// `/proc/cpuinfo` if that isn't available), taking the peripheral base
+    /// from the device tree when there is one.
+    pub fn detect() -> Result<Self> {
+        let revision = match Mailbox::new("/dev/vcio") {
+            Ok(mb) => get_board_revision(&mb)?,
+            Err(_) => {
+                let cpuinfo = fs::read_to_string(CPUINFO)?;
+                parse_cpuinfo_revision(&cpuinfo).ok_or(BoardError::NoRevision)?
+            }
+        };
+
+        let mut platform = Platform::from_revision(revision)?;
+        if let Ok(ranges) = fs::read(DT_RANGES) {
+            platform.peripheral_base = parse_ranges(&ranges)?;
+        }
+
+        Ok(platform)
+    }
+
+    pub fn from_revision(revision: u32) -> std::result::Result<Self, BoardError> {
+        Ok(Platform::from_soc(Soc::from_revision(revision)?, revision))
+    }
+
+    pub fn from_soc(soc: Soc, revision: u32) -> Self {
+        Platform {
+            soc,
+            revision,
+            peripheral_base: soc.peripheral_base(),
+            dram_bus_alias: soc.dram_bus_alias(),
+        }
+    }
+
+    /// Board type field of a new style revision code, e.g. 0x12 for the
+    /// Zero 2 W.
+    pub fn board_type(&self) -> Option<u32> {
+        (self.revision & REV_NEW_STYLE != 0)
+            .then_some((self.revision >> REV_TYPE_SHIFT) & REV_TYPE_MASK)
+    }
+}
+
+/// Peripheral base address from the device tree's `/soc/ranges`.
+///
+/// The first range maps the peripheral bus address to the ARM physical
+/// address, which is a single cell on the older SoCs and two on the
+/// BCM2711 (where the top cell is 0).
+pub fn parse_ranges(ranges: &[u8]) -> std::result::Result<usize, BoardError> {
+    let cells = ranges
+        .chunks_exact(4)
+        .map(|cell| u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+        .collect::<Vec<_>>();
+
+    match cells.as_slice() {
+        [bus, 0, base, ..] if *bus as usize == PERIPHERAL_BUS_ADDRESS && *base != 0 => {
+            Ok(*base as usize)
+        }
+        [bus, base, ..] if *bus as usize == PERIPHERAL_BUS_ADDRESS && *base != 0 => {
+            Ok(*base as usize)
+        }
+        _ => Err(BoardError::InvalidRanges),
+    }
+}
+
+/// Board revision from the `Revision` line of `/proc/cpuinfo`.
+pub fn parse_cpuinfo_revision(cpuinfo: &str) -> Option<u32> {
+    cpuinfo
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim() == "Revision")
+        .and_then(|(_, value)| u32::from_str_radix(value.trim(), 16).ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_device_tree_ranges() {
+        let bcm2835 = include_bytes!("../tests/fixtures/board/ranges-bcm2835.bin");
+        let bcm2837 = include_bytes!("../tests/fixtures/board/ranges-bcm2837.bin");
+        let bcm2711 = include_bytes!("../tests/fixtures/board/ranges-bcm2711.bin");
+
+        assert_eq!(parse_ranges(bcm2835), Ok(0x20000000));
+        assert_eq!(parse_ranges(bcm2837), Ok(0x3F000000));
+        assert_eq!(parse_ranges(bcm2711), Ok(0xFE000000));
+        assert_eq!(parse_ranges(&bcm2837[..6]), Err(BoardError::InvalidRanges));
+    }
+
+    #[test]
+    fn picks_platform_from_revision() {
+        let zero2w = include_str!("../tests/fixtures/board/cpuinfo-zero2w.txt");
+        let pi4 = include_str!("../tests/fixtures/board/cpuinfo-pi4.txt");
+        let pi1 = include_str!("../tests/fixtures/board/cpuinfo-pi1b.txt");
+
+        let revision = parse_cpuinfo_revision(zero2w).unwrap();
+        let platform = Platform::from_revision(revision).unwrap();
+        assert_eq!(platform.soc, Soc::Bcm2837);
+        assert_eq!(platform.peripheral_base, 0x3F000000);
+        assert_eq!(platform.board_type(), Some(0x12));
+
+        let platform = Platform::from_revision(parse_cpuinfo_revision(pi4).unwrap()).unwrap();
+        assert_eq!(platform.soc, Soc::Bcm2711);
+        assert_eq!(platform.peripheral_base, 0xFE000000);
+        assert_eq!(platform.soc.smi_source_hz(), 750_000_000);
+        assert!(!platform.soc.dma_channels().contains(&11));
+
+        let platform = Platform::from_revision(parse_cpuinfo_revision(pi1).unwrap()).unwrap();
+        assert_eq!(platform.soc, Soc::Bcm2835);
+        assert_eq!(platform.soc.smi_source_hz(), 500_000_000);
+        assert_eq!(platform.board_type(), None);
+
+        // a Pi 5
+        assert_eq!(
+            Platform::from_revision(0xc04170),
+            Err(BoardError::UnsupportedSoc { revision: 0xc04170, processor: 4 })
+        );
+        assert_eq!(parse_cpuinfo_revision("processor\t: 0\n"), None);
+    }
+}
@@ -0,0 +1,226 @@
+/// Colour temperature of white that `ColorCorrection::temperature` leaves as
+/// is, the blackbody approximation used comes out at full white here.
+pub const NEUTRAL_TEMPERATURE: u32 = 6600;
+
+/// Correction applied to every pixel of a channel before it's encoded.
+///
+/// Each component goes through the gamma curve first and is then scaled by
+/// the white point and the gain, the white component of RGBW pixels only
+/// gets the gamma curve and its own gain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorCorrection {
+    /// Exponent of the brightness curve, 1.0 is linear and 2.2-2.8 suits
+    /// WS2812s.
+    pub gamma: f32,
+    /// Level of each of red, green and blue in white, see `temperature`.
+    pub white_point: [f32; 3],
+    /// Scale of each of red, green, blue and white.
+    pub gain: [f32; 4],
+}
+
+impl Default for ColorCorrection {
+    fn default() -> Self {
+        ColorCorrection {
+            gamma: 1.0,
+            white_point: [1.0; 3],
+            gain: [1.0; 4],
+        }
+    }
+}
+
+impl ColorCorrection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn gamma(mut self, gamma: f32) -> Self {
+        self.gamma = gamma;
+        self
+    }
+
+    /// Sets the white point to the colour of a blackbody at `kelvin`, lower
+    /// is warmer.
+    pub fn temperature(mut self, kelvin: u32) -> Self {
+        self.white_point = blackbody(kelvin);
+        self
+    }
+
+    pub fn white_point(mut self, r: f32, g: f32, b: f32) -> Self {
+        self.white_point = [r, g, b];
+        self
+    }
+
+    pub fn gain(mut self, r: f32, g: f32, b: f32, w: f32) -> Self {
+        self.gain = [r, g, b, w];
+        self
+    }
+
+    /// Scales all four gains by `brightness`.
+    pub fn brightness(mut self, brightness: f32) -> Self {
+        for gain in self.gain.iter_mut() {
+            *gain *= brightness;
+        }
+        self
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.gamma.is_finite()
+            && self.gamma > 0.0
+            && self.white_point.iter().chain(self.gain.iter()).all(|v| v.is_finite() && *v >= 0.0)
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == ColorCorrection::default()
+    }
+}
+
+// RGB of a blackbody at `kelvin`, after Tanner Helland's fit of the CIE data
+fn blackbody(kelvin: u32) -> [f32; 3] {
+    let t = kelvin.clamp(1000, 40000) as f32 / 100.0;
+
+    let r = if t <= 66.0 {
+        255.0
+    } else {
+        329.69873 * (t - 60.0).powf(-0.13320476)
+    };
+    let g = if t <= 66.0 {
+        99.4708 * t.ln() - 161.11957
+    } else {
+        288.12216 * (t - 60.0).powf(-0.07551485)
+    };
+    let b = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.51773 * (t - 10.0).ln() - 305.0448
+    };
+
+    [r, g, b].map(|c| c.clamp(0.0, 255.0) / 255.0)
+}
+
+/// Lookup tables built from a `ColorCorrection`, one per component of a
+/// `0xWWRRGGBB` pixel.
+///
+/// There is also a 16-bit version of each curve for `0xWWWWRRRRGGGGBBBB`
+/// pixels, see `apply16`, which is what the dithering works from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColorLut {
+    // indexed by the byte of the pixel, white is table 0 and blue table 3
+    tables: [[u8; 256]; 4],
+    // the curves at every 257th 16-bit level (the 8-bit levels), interpolated
+    // in between
+    curves: [[u16; 256]; 4],
+    identity: bool,
+}
+
+impl ColorLut {
+    pub fn new(correction: &ColorCorrection) -> Self {
+        let ColorCorrection { gamma, white_point, gain } = *correction;
+        let scales = [gain[3], white_point[0] * gain[0], white_point[1] * gain[1], white_point[2] * gain[2]];
+
+        let level = |input: f32, scale: f32| (input.powf(gamma) * scale).clamp(0.0, 1.0);
+
+        let mut tables = [[0; 256]; 4];
+        let mut curves = [[0; 256]; 4];
+        for ((table, curve), scale) in tables.iter_mut().zip(curves.iter_mut()).zip(scales) {
+            for (n, entry) in table.iter_mut().enumerate() {
+                *entry = (level(n as f32 / 255.0, scale) * 255.0).round() as u8;
+            }
+            for (n, entry) in curve.iter_mut().enumerate() {
+                *entry = (level(n as f32 / 255.0, scale) * 65535.0).round() as u16;
+            }
+        }
+
+        let identity = tables
+            .iter()
+            .all(|table| table.iter().enumerate().all(|(n, &entry)| entry as usize == n));
+        ColorLut { tables, curves, identity }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.identity
+    }
+
+    #[inline]
+    pub fn apply(&self, color: u32) -> u32 {
+        if self.identity {
+            return color;
+        }
+
+        let [w, r, g, b] = color.to_be_bytes();
+        u32::from_be_bytes([
+            self.tables[0][w as usize],
+            self.tables[1][r as usize],
+            self.tables[2][g as usize],
+            self.tables[3][b as usize],
+        ])
+    }
+
+    /// Like `apply` for a pixel with 16 bits per component.
+    pub fn apply16(&self, color: u64) -> u64 {
+        if self.identity {
+            return color;
+        }
+
+        (0..4).fold(0, |out, n| {
+            let level = (color >> (48 - n * 16)) as u16;
+            (out << 16) | self.curve(n, level) as u64
+        })
+    }
+
+    fn curve(&self, table: usize, level: u16) -> u16 {
+        let (index, frac) = ((level / 257) as usize, (level % 257) as i32);
+        if frac == 0 {
+            return self.curves[table][index];
+        }
+        let (lo, hi) = (self.curves[table][index] as i32, self.curves[table][index + 1] as i32);
+        (lo + (hi - lo) * frac / 257) as u16
+    }
+}
+
+impl Default for ColorLut {
+    fn default() -> Self {
+        ColorLut::new(&ColorCorrection::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn applies_gamma_and_gain() {
+        assert!(ColorLut::default().is_identity());
+        assert_eq!(ColorLut::default().apply(0x12345678), 0x12345678);
+
+        let lut = ColorLut::new(&ColorCorrection::new().gamma(2.0));
+        assert!(!lut.is_identity());
+        assert_eq!(lut.apply(0xFF000000), 0xFF000000);
+        // (128 / 255)^2 * 255 = 64.25
+        assert_eq!(lut.apply(0x00808080), 0x00404040);
+
+        let lut = ColorLut::new(&ColorCorrection::new().gain(0.5, 1.0, 0.0, 2.0));
+        assert_eq!(lut.apply(0x80FFFFFF), 0xFF80FF00);
+
+        let lut = ColorLut::new(&ColorCorrection::new().gamma(2.0));
+        let color = lut.apply16(0xFFFF_0000_8000_0101);
+        assert_eq!(color >> 32, 0xFFFF_0000);
+        assert!((color >> 16 & 0xFFFF).abs_diff(0x4000) <= 2);
+        assert_eq!(color & 0xFFFF, 1);
+    }
+
+    #[test]
+    fn warms_up_white() {
+        let neutral = ColorCorrection::new().temperature(NEUTRAL_TEMPERATURE);
+        assert!(ColorLut::new(&neutral).is_identity());
+
+        let warm = ColorLut::new(&ColorCorrection::new().temperature(3000)).apply(0x00FFFFFF);
+        let [_, r, g, b] = warm.to_be_bytes();
+        assert_eq!(r, 0xFF);
+        assert!(g < r && b < g);
+
+        assert!(!ColorCorrection::new().gamma(0.0).is_valid());
+        assert!(!ColorCorrection::new().brightness(-1.0).is_valid());
+    }
+}
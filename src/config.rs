@@ -0,0 +1,503 @@
+use thiserror::Error;
+
+use crate::color::ColorCorrection;
+use crate::encoder::BitTiming;
+use crate::pixel::{PixelFormat, WhiteMode};
+use crate::power::{PowerLimiter, PowerModel};
+use crate::timing::{LedTiming, SmiTiming};
+
+pub const DEFAULT_CHANNELS: usize = 8;    // Number of LED channels (8 or 16)
+pub const DEFAULT_LEDS: usize     = 128;  // LEDs per channel
+pub const DEFAULT_PREBITS: usize  = 0;    // Number of zero bits before LED data
+pub const DEFAULT_POSTBITS: usize = 100;  // Number of zero bits after LED data
+pub const DEFAULT_TX_BUFFERS: usize = 2;  // Front and back buffer
+
+// the DMA engine can't move more than this in one control block
+pub const MAX_BUFFER_SIZE: usize = (1 << 30) - 1;
+
+const PAGE_SIZE: usize = 0x1000;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("invalid channel count {0}, must be 8 or 16")]
+    InvalidChannelCount(usize),
+    #[error("a channel needs at least one LED")]
+    NoLeds,
+    #[error("at least one tx buffer is needed")]
+    NoTxBuffers,
+    #[error("channel {0} doesn't exist")]
+    InvalidChannel(usize),
+    #[error("invalid bit timing {0:?}")]
+    InvalidBitTiming(BitTiming),
+    #[error("invalid SMI timing {0:?}")]
+    InvalidSmiTiming(SmiTiming),
+    #[error("invalid colour correction on channel {0}")]
+    InvalidColorCorrection(usize),
+    #[error("invalid power model or budget")]
+    InvalidPowerLimit,
+    #[error("buffer of {size} bytes is too small for the layout, needs {needed}")]
+    BufferTooSmall { size: usize, needed: usize },
+    #[error("buffer of {size} bytes is larger than the {max} byte maximum")]
+    BufferTooLarge { size: usize, max: usize },
+}
+
+/// Layout of the strips hanging off the SMI data lines and of the tx buffer
+/// the frames are encoded into.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LedConfig {
+    channels: usize,
+    leds_per_channel: usize,
+    prebits: usize,
+    postbits: usize,
+    bit_timing: BitTiming,
+    smi_timing: SmiTiming,
+    formats: Vec<PixelFormat>,
+    white_mode: WhiteMode,
+    corrections: Vec<ColorCorrection>,
+    dithering: bool,
+    power: PowerLimiter,
+    buffer_size: usize,
+    tx_buffers: usize,
+}
+
+impl LedConfig {
+    pub fn builder() -> LedConfigBuilder {
+        LedConfigBuilder::default()
+    }
+
+    /// Number of channels (strips), one per SMI data line.
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn leds_per_channel(&self) -> usize {
+        self.leds_per_channel
+    }
+
+    /// Number of zero samples sent before the LED data.
+    pub fn prebits(&self) -> usize {
+        self.prebits
+    }
+
+    /// Number of zero samples sent after the LED data, this is the reset gap
+    /// that latches the frame.
+    pub fn postbits(&self) -> usize {
+        self.postbits
+    }
+
+    pub fn bit_timing(&self) -> BitTiming {
+        self.bit_timing
+    }
+
+    pub fn smi_timing(&self) -> SmiTiming {
+        self.smi_timing
+    }
+
+    pub fn pixel_format(&self, channel: usize) -> PixelFormat {
+        self.formats[channel]
+    }
+
+    pub fn white_mode(&self) -> WhiteMode {
+        self.white_mode
+    }
+
+    pub fn color_correction(&self, channel: usize) -> ColorCorrection {
+        self.corrections[channel]
+    }
+
+    /// Replaces the colour correction of a running channel, see
+    /// `LedDriver::set_color_correction`.
+    pub(crate) fn set_color_correction(&mut self, channel: usize, correction: ColorCorrection) -> Result<(), ConfigError> {
+        if !correction.is_valid() {
+            return Err(ConfigError::InvalidColorCorrection(channel));
+        }
+        *self.corrections.get_mut(channel).ok_or(ConfigError::InvalidChannel(channel))? = correction;
+        Ok(())
+    }
+
+    /// Whether frames are taken from 16-bit pixels and dithered down to the
+    /// 8 bits sent to the LEDs.
+    pub fn dithering(&self) -> bool {
+        self.dithering
+    }
+
+    /// Power model and budgets frames are limited to.
+    pub fn power_limiter(&self) -> PowerLimiter {
+        self.power
+    }
+
+    /// Data bits per LED of the widest pixel format in use, this is what the
+    /// tx buffer is sized by.
+    pub fn bits_per_pixel(&self) -> usize {
+        max_bits(&self.formats)
+    }
+
+    /// Size in bytes of one SMI sample.
+    pub fn sample_size(&self) -> usize {
+        self.channels / 8
+    }
+
+    /// Size in bytes of a full frame in the tx buffer.
+    pub fn tx_buff_size(&self) -> usize {
+        tx_buff_size(
+            self.channels,
+            self.leds_per_channel,
+            self.bits_per_pixel(),
+            self.prebits,
+            self.postbits,
+            self.bit_timing,
+        ).unwrap()
+    }
+
+    /// Size in bytes of the VC memory allocated for each tx buffer.
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Number of tx buffers frames are rotated through.
+    pub fn tx_buffers(&self) -> usize {
+        self.tx_buffers
+    }
+}
+
+impl Default for LedConfig {
+    fn default() -> Self {
+        LedConfig::builder().build().unwrap()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LedConfigBuilder {
+    channels: usize,
+    leds_per_channel: usize,
+    prebits: usize,
+    postbits: usize,
+    bit_timing: BitTiming,
+    smi_timing: SmiTiming,
+    format: PixelFormat,
+    channel_formats: Vec<(usize, PixelFormat)>,
+    white_mode: WhiteMode,
+    correction: ColorCorrection,
+    channel_corrections: Vec<(usize, ColorCorrection)>,
+    dithering: bool,
+    power: PowerLimiter,
+    buffer_size: Option<usize>,
+    tx_buffers: usize,
+}
+
+impl Default for LedConfigBuilder {
+    fn default() -> Self {
+        LedConfigBuilder {
+            channels: DEFAULT_CHANNELS,
+            leds_per_channel: DEFAULT_LEDS,
+            prebits: DEFAULT_PREBITS,
+            postbits: DEFAULT_POSTBITS,
+            bit_timing: BitTiming::default(),
+            smi_timing: SmiTiming::default(),
+            format: PixelFormat::Grb,
+            channel_formats: Vec::new(),
+            white_mode: WhiteMode::default(),
+            correction: ColorCorrection::default(),
+            channel_corrections: Vec::new(),
+            dithering: false,
+            power: PowerLimiter::default(),
+            buffer_size: None,
+            tx_buffers: DEFAULT_TX_BUFFERS,
+        }
+    }
+}
+
+impl LedConfigBuilder {
+    pub fn channels(mut self, channels: usize) -> Self {
+        self.channels = channels;
+        self
+    }
+
+    pub fn leds_per_channel(mut self, leds: usize) -> Self {
+        self.leds_per_channel = leds;
+        self
+    }
+
+    /// Length of the reset gaps before and after the LED data, in SMI samples
+    /// (pulses) rather than whole LED bits.
+    pub fn reset_bits(mut self, prebits: usize, postbits: usize) -> Self {
+        self.prebits = prebits;
+        self.postbits = postbits;
+        self
+    }
+
+    pub fn bit_timing(mut self, bit_timing: BitTiming) -> Self {
+        self.bit_timing = bit_timing;
+        self
+    }
+
+    pub fn smi_timing(mut self, smi_timing: SmiTiming) -> Self {
+        self.smi_timing = smi_timing;
+        self
+    }
+
+    /// Takes the SMI settings, bit layout and reset gap from timings solved
+    /// for a chip, see `ChipTiming::solve`.
+    pub fn timing(mut self, timing: &LedTiming) -> Self {
+        self.bit_timing = timing.bit;
+        self.smi_timing = timing.smi;
+        self.postbits = timing.reset_samples;
+        self
+    }
+
+    /// Pixel format of every channel that doesn't have its own set with
+    /// `channel_format`, defaults to GRB.
+    pub fn pixel_format(mut self, format: PixelFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn channel_format(mut self, channel: usize, format: PixelFormat) -> Self {
+        self.channel_formats.push((channel, format));
+        self
+    }
+
+    pub fn white_mode(mut self, white_mode: WhiteMode) -> Self {
+        self.white_mode = white_mode;
+        self
+    }
+
+    /// Gamma, white point and gain of every channel that doesn't have its
+    /// own set with `channel_correction`, defaults to none at all.
+    pub fn color_correction(mut self, correction: ColorCorrection) -> Self {
+        self.correction = correction;
+        self
+    }
+
+    pub fn channel_correction(mut self, channel: usize, correction: ColorCorrection) -> Self {
+        self.channel_corrections.push((channel, correction));
+        self
+    }
+
+    /// Temporal dithering of 16-bit pixels, see `LedDriver::set_pixel16`.
+    /// It relies on a high frame rate to hide the flicker between levels.
+    pub fn dithering(mut self, dithering: bool) -> Self {
+        self.dithering = dithering;
+        self
+    }
+
+    /// Current drawn per LED, the default is a WS2812B's 20mA per colour.
+    pub fn power_model(mut self, model: PowerModel) -> Self {
+        self.power.model = model;
+        self
+    }
+
+    /// Limit on the estimated draw of each channel, in mA.
+    pub fn channel_power_budget(mut self, ma: f32) -> Self {
+        self.power.channel_budget_ma = Some(ma);
+        self
+    }
+
+    /// Limit on the estimated draw of all channels together, in mA.
+    pub fn power_budget(mut self, ma: f32) -> Self {
+        self.power.budget_ma = Some(ma);
+        self
+    }
+
+    /// Size of the tx buffer to allocate, by default just enough for the
+    /// layout rounded up to a whole page.
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = Some(size);
+        self
+    }
+
+    /// Number of tx buffers, with more than one the next frame is encoded
+    /// while the previous one is still being sent. 1 sends every frame
+    /// synchronously.
+    pub fn tx_buffers(mut self, count: usize) -> Self {
+        self.tx_buffers = count;
+        self
+    }
+
+    pub fn build(self) -> Result<LedConfig, ConfigError> {
+        if self.channels != 8 && self.channels != 16 {
+            return Err(ConfigError::InvalidChannelCount(self.channels));
+        }
+        if self.leds_per_channel == 0 {
+            return Err(ConfigError::NoLeds);
+        }
+        if self.tx_buffers == 0 {
+            return Err(ConfigError::NoTxBuffers);
+        }
+
+        let timing = self.bit_timing;
+        if timing.t0_pulses == 0
+            || timing.t0_pulses >= timing.t1_pulses
+            || timing.t1_pulses >= timing.npulses
+        {
+            return Err(ConfigError::InvalidBitTiming(timing));
+        }
+        if !self.smi_timing.is_valid() {
+            return Err(ConfigError::InvalidSmiTiming(self.smi_timing));
+        }
+
+        let mut formats = vec![self.format; self.channels];
+        for &(channel, format) in self.channel_formats.iter() {
+            *formats
+                .get_mut(channel)
+                .ok_or(ConfigError::InvalidChannel(channel))? = format;
+        }
+
+        let mut corrections = vec![self.correction; self.channels];
+        for &(channel, correction) in self.channel_corrections.iter() {
+            *corrections
+                .get_mut(channel)
+                .ok_or(ConfigError::InvalidChannel(channel))? = correction;
+        }
+        if let Some(channel) = corrections.iter().position(|c| !c.is_valid()) {
+            return Err(ConfigError::InvalidColorCorrection(channel));
+        }
+        if !self.power.is_valid() {
+            return Err(ConfigError::InvalidPowerLimit);
+        }
+
+        let needed = tx_buff_size(
+            self.channels,
+            self.leds_per_channel,
+            max_bits(&formats),
+            self.prebits,
+            self.postbits,
+            timing,
+        ).unwrap_or(usize::MAX);
+        if needed > MAX_BUFFER_SIZE {
+            return Err(ConfigError::BufferTooLarge {
+                size: needed,
+                max: MAX_BUFFER_SIZE,
+            });
+        }
+
+        let buffer_size = match self.buffer_size {
+            Some(size) if size < needed => {
+                return Err(ConfigError::BufferTooSmall { size, needed });
+            }
+            Some(size) => size,
+            None => (needed + PAGE_SIZE - 1) & !(PAGE_SIZE - 1),
+        };
+        if buffer_size > MAX_BUFFER_SIZE {
+            return Err(ConfigError::BufferTooLarge {
+                size: buffer_size,
+                max: MAX_BUFFER_SIZE,
+            });
+        }
+
+        Ok(LedConfig {
+            channels: self.channels,
+            leds_per_channel: self.leds_per_channel,
+            prebits: self.prebits,
+            postbits: self.postbits,
+            bit_timing: timing,
+            smi_timing: self.smi_timing,
+            formats,
+            white_mode: self.white_mode,
+            corrections,
+            dithering: self.dithering,
+            power: self.power,
+            buffer_size,
+            tx_buffers: self.tx_buffers,
+        })
+    }
+}
+
+// None if the layout is too big to even be sized
+fn tx_buff_size(
+    channels: usize,
+    leds: usize,
+    bits: usize,
+    prebits: usize,
+    postbits: usize,
+    timing: BitTiming,
+) -> Option<usize> {
+    bits
+        .checked_mul(timing.npulses)?
+        .checked_mul(leds)?
+        .checked_add(prebits)?
+        .checked_add(postbits)?
+        .checked_mul(channels / 8)
+}
+
+fn max_bits(formats: &[PixelFormat]) -> usize {
+    formats.iter().map(|format| format.bits()).max().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sizes_buffer_for_layout() {
+        let config = LedConfig::builder()
+            .channels(16)
+            .leds_per_channel(100)
+            .reset_bits(4, 50)
+            .build()
+            .unwrap();
+
+        assert_eq!(config.tx_buff_size(), (4 + 100 * 24 * 3 + 50) * 2);
+        assert_eq!(config.buffer_size(), 0x4000);
+    }
+
+    #[test]
+    fn takes_timing_from_chip_profile() {
+        let timing = crate::timing::SK6812.solve().unwrap();
+        let config = LedConfig::builder().timing(&timing).build().unwrap();
+
+        assert_eq!(config.bit_timing(), timing.bit);
+        assert_eq!(config.smi_timing(), timing.smi);
+        assert_eq!(config.postbits(), timing.reset_samples);
+        assert_eq!(
+            LedConfig::builder().smi_timing(SmiTiming { strobe: 0, ..timing.smi }).build(),
+            Err(ConfigError::InvalidSmiTiming(SmiTiming { strobe: 0, ..timing.smi }))
+        );
+    }
+
+    #[test]
+    fn sizes_buffer_for_widest_format() {
+        let config = LedConfig::builder()
+            .leds_per_channel(10)
+            .channel_format(3, PixelFormat::Grbw)
+            .build()
+            .unwrap();
+
+        assert_eq!(config.pixel_format(0), PixelFormat::Grb);
+        assert_eq!(config.pixel_format(3), PixelFormat::Grbw);
+        assert_eq!(config.bits_per_pixel(), 32);
+        assert_eq!(config.tx_buff_size(), 10 * 32 * 3 + 100);
+    }
+
+    #[test]
+    fn rejects_layouts_that_dont_fit() {
+        assert_eq!(
+            LedConfig::builder().channels(12).build(),
+            Err(ConfigError::InvalidChannelCount(12))
+        );
+        assert_eq!(LedConfig::builder().leds_per_channel(0).build(), Err(ConfigError::NoLeds));
+        assert_eq!(LedConfig::builder().tx_buffers(0).build(), Err(ConfigError::NoTxBuffers));
+        assert_eq!(
+            LedConfig::builder()
+                .channel_correction(2, ColorCorrection::new().gamma(-1.0))
+                .build(),
+            Err(ConfigError::InvalidColorCorrection(2))
+        );
+        assert_eq!(
+            LedConfig::builder().power_budget(f32::NAN).build(),
+            Err(ConfigError::InvalidPowerLimit)
+        );
+        assert_eq!(
+            LedConfig::builder().leds_per_channel(100).buffer_size(0x1000).build(),
+            Err(ConfigError::BufferTooSmall { size: 0x1000, needed: 100 * 24 * 3 + 100 })
+        );
+        assert_eq!(
+            LedConfig::builder().channel_format(8, PixelFormat::Rgbw).build(),
+            Err(ConfigError::InvalidChannel(8))
+        );
+        assert!(matches!(
+            LedConfig::builder().leds_per_channel(usize::MAX).build(),
+            Err(ConfigError::BufferTooLarge { .. })
+        ));
+    }
+}
@@ -0,0 +1,299 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::MAX_CHANNELS;
+
+#[derive(Error, Debug)]
+pub enum LayoutError {
+    #[error("invalid cube size {0:?}")]
+    InvalidSize([usize; 3]),
+    #[error("layer {layer} rotated by {rotate} degrees, must be 0, 90, 180 or 270")]
+    InvalidRotation { layer: usize, rotate: u16 },
+    #[error("layer {0} is rotated by a quarter turn but isn't square")]
+    NonSquareRotation(usize),
+    #[error("layer {0} is outside the cube")]
+    InvalidLayer(usize),
+    #[error("layers per channel must be at least 1")]
+    NoLayersPerChannel,
+    #[error("the layout needs {0} channels, there are only 16 data lines")]
+    TooManyChannels(usize),
+    #[error("can't parse layout: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+/// How the LEDs of one layer are wired relative to the cube's x and y axes,
+/// the flips are applied before the rotation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LayerTransform {
+    /// Quarter turns clockwise, in degrees.
+    pub rotate: u16,
+    pub flip_x: bool,
+    pub flip_y: bool,
+}
+
+/// Transform of a single layer in place of the layout's default one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LayerOverride {
+    pub z: usize,
+    #[serde(flatten)]
+    pub transform: LayerTransform,
+}
+
+/// Description of how the voxels of a cube are wired to the channels, e.g.
+///
+/// ```json
+/// {
+///     "size": [8, 8, 8],
+///     "serpentine": true,
+///     "layers": [{ "z": 1, "rotate": 180 }]
+/// }
+/// ```
+///
+/// The cube is built from horizontal (x, y) layers stacked along z, each
+/// channel drives `layers_per_channel` consecutive layers starting from the
+/// bottom one on `first_channel`. Within a layer LEDs are chained along x,
+/// row after row, with every other row running backwards if `serpentine`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CubeLayout {
+    /// Number of voxels along x, y and z.
+    pub size: [usize; 3],
+    #[serde(default = "default_layers_per_channel")]
+    pub layers_per_channel: usize,
+    #[serde(default)]
+    pub first_channel: usize,
+    #[serde(default)]
+    pub serpentine: bool,
+    /// Transform of every layer without an override.
+    #[serde(default)]
+    pub transform: LayerTransform,
+    #[serde(default)]
+    pub layers: Vec<LayerOverride>,
+}
+
+fn default_layers_per_channel() -> usize {
+    1
+}
+
+impl CubeLayout {
+    /// One layer per channel, wired the same way.
+    pub fn new(x: usize, y: usize, z: usize) -> Self {
+        CubeLayout {
+            size: [x, y, z],
+            layers_per_channel: 1,
+            first_channel: 0,
+            serpentine: false,
+            transform: LayerTransform::default(),
+            layers: Vec::new(),
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, LayoutError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn layer_transform(&self, z: usize) -> LayerTransform {
+        self.layers
+            .iter()
+            .rev()
+            .find(|layer| layer.z == z)
+            .map_or(self.transform, |layer| layer.transform)
+    }
+}
+
+/// Lookup table from voxel coordinates to (channel, index) positions, built
+/// once from a `CubeLayout`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CubeMap {
+    size: [usize; 3],
+    nchans: usize,
+    nleds: usize,
+    // (channel, index) of every voxel, x first then y then z
+    positions: Vec<(usize, usize)>,
+}
+
+impl CubeMap {
+    pub fn new(layout: &CubeLayout) -> Result<Self, LayoutError> {
+        let [sx, sy, sz] = layout.size;
+        if sx == 0 || sy == 0 || sz == 0 {
+            return Err(LayoutError::InvalidSize(layout.size));
+        }
+        if layout.layers_per_channel == 0 {
+            return Err(LayoutError::NoLayersPerChannel);
+        }
+        if let Some(layer) = layout.layers.iter().find(|layer| layer.z >= sz) {
+            return Err(LayoutError::InvalidLayer(layer.z));
+        }
+        let nchans = layout.first_channel + sz.div_ceil(layout.layers_per_channel);
+        if nchans > MAX_CHANNELS {
+            return Err(LayoutError::TooManyChannels(nchans));
+        }
+
+        let mut positions = Vec::with_capacity(sx * sy * sz);
+        for z in 0..sz {
+            let transform = layout.layer_transform(z);
+            match transform.rotate {
+                0 | 180 => {}
+                90 | 270 if sx == sy => {}
+                90 | 270 => return Err(LayoutError::NonSquareRotation(z)),
+                rotate => return Err(LayoutError::InvalidRotation { layer: z, rotate }),
+            }
+
+            let channel = layout.first_channel + z / layout.layers_per_channel;
+            let base = (z % layout.layers_per_channel) * sx * sy;
+            for y in 0..sy {
+                for x in 0..sx {
+                    let (col, row) = wire_position(x, y, sx, sy, transform);
+                    let col = if layout.serpentine && row % 2 == 1 { sx - 1 - col } else { col };
+                    positions.push((channel, base + row * sx + col));
+                }
+            }
+        }
+
+        Ok(CubeMap {
+            size: layout.size,
+            nchans,
+            nleds: layout.layers_per_channel.min(sz) * sx * sy,
+            positions,
+        })
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, LayoutError> {
+        CubeMap::new(&CubeLayout::from_json(json)?)
+    }
+
+    /// Number of voxels along x, y and z.
+    pub fn size(&self) -> [usize; 3] {
+        self.size
+    }
+
+    /// Number of channels the cube needs, counting those before
+    /// `first_channel`.
+    pub fn channels(&self) -> usize {
+        self.nchans
+    }
+
+    /// Number of LEDs on the longest channel.
+    pub fn leds_per_channel(&self) -> usize {
+        self.nleds
+    }
+
+    /// (channel, index) of the LED at a voxel, `None` outside the cube.
+    #[inline]
+    pub fn position(&self, x: usize, y: usize, z: usize) -> Option<(usize, usize)> {
+        let [sx, sy, sz] = self.size;
+        if x >= sx || y >= sy || z >= sz {
+            return None;
+        }
+        Some(self.positions[x + sx * (y + sy * z)])
+    }
+
+    /// Every voxel as `([x, y, z], (channel, index))`.
+    pub fn iter(&self) -> impl Iterator<Item = ([usize; 3], (usize, usize))> + '_ {
+        let [sx, sy, _] = self.size;
+        self.positions
+            .iter()
+            .enumerate()
+            .map(move |(n, &position)| ([n % sx, n / sx % sy, n / (sx * sy)], position))
+    }
+}
+
+// column and row of voxel (x, y) in the wiring of a layer
+fn wire_position(x: usize, y: usize, sx: usize, sy: usize, transform: LayerTransform) -> (usize, usize) {
+    let x = if transform.flip_x { sx - 1 - x } else { x };
+    let y = if transform.flip_y { sy - 1 - y } else { y };
+    match transform.rotate {
+        90 => (sy - 1 - y, x),
+        180 => (sx - 1 - x, sy - 1 - y),
+        270 => (y, sx - 1 - x),
+        _ => (x, y),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn maps_serpentine_layers() {
+        let map = CubeMap::from_json(include_str!("../tests/fixtures/cube/8x8x8.json")).unwrap();
+        assert_eq!(map.size(), [8, 8, 8]);
+        assert_eq!(map.channels(), 8);
+        assert_eq!(map.leds_per_channel(), 64);
+
+        assert_eq!(map.position(0, 0, 0), Some((0, 0)));
+        assert_eq!(map.position(7, 0, 0), Some((0, 7)));
+        // the second row runs back
+        assert_eq!(map.position(7, 1, 0), Some((0, 8)));
+        assert_eq!(map.position(0, 1, 0), Some((0, 15)));
+        // layer 1 is turned around, its last row runs backwards too
+        assert_eq!(map.position(0, 0, 1), Some((1, 56)));
+        assert_eq!(map.position(8, 0, 0), None);
+
+        // every LED gets exactly one voxel
+        let mut positions = map.iter().map(|(_, position)| position).collect::<Vec<_>>();
+        positions.sort();
+        positions.dedup();
+        assert_eq!(positions.len(), 512);
+    }
+
+    #[test]
+    fn chains_layers_and_rotates() {
+        let mut layout = CubeLayout::new(4, 4, 3);
+        layout.layers_per_channel = 2;
+        layout.first_channel = 1;
+        layout.transform.rotate = 90;
+        layout.layers.push(LayerOverride {
+            z: 2,
+            transform: LayerTransform { flip_x: true, ..Default::default() },
+        });
+        let map = CubeMap::new(&layout).unwrap();
+        assert_eq!(map.channels(), 3);
+        assert_eq!(map.leds_per_channel(), 32);
+
+        // a quarter turn puts x along the rows
+        assert_eq!(map.position(0, 0, 0), Some((1, 3)));
+        assert_eq!(map.position(1, 0, 0), Some((1, 7)));
+        assert_eq!(map.position(0, 0, 1), Some((1, 19)));
+        assert_eq!(map.position(0, 0, 2), Some((2, 3)));
+        assert_eq!(CubeLayout::from_json(&layout.to_json()).unwrap(), layout);
+
+        layout.size = [4, 2, 3];
+        assert!(matches!(CubeMap::new(&layout), Err(LayoutError::NonSquareRotation(0))));
+        layout.transform.rotate = 45;
+        assert!(matches!(
+            CubeMap::new(&layout),
+            Err(LayoutError::InvalidRotation { layer: 0, rotate: 45 })
+        ));
+        assert!(matches!(
+            CubeLayout::from_json(r#"{ "size": [8, 8, 8], "wiring": 1 }"#),
+            Err(LayoutError::Parse(_))
+        ));
+        assert!(matches!(
+            CubeMap::new(&CubeLayout::new(4, 4, 17)),
+            Err(LayoutError::TooManyChannels(17))
+        ));
+    }
+
+    #[test]
+    fn sets_voxels_on_the_driver() {
+        let config = crate::LedConfig::builder().leds_per_channel(4).build().unwrap();
+        let mut leds = crate::LedDriver::with_backend(crate::hal::Simulated::new(), config).unwrap();
+
+        // wired from the last channel on, the top layer has nowhere to go
+        let mut layout = CubeLayout::new(2, 2, 2);
+        layout.first_channel = 7;
+        let map = CubeMap::new(&layout).unwrap();
+        leds.set_voxel(&map, 1, 1, 0, 0xFF);
+        leds.set_voxel(&map, 1, 1, 1, 0xFF);
+        let (channel, index) = map.position(1, 1, 0).unwrap();
+        assert_eq!(leds.pixel(channel, index), 0xFF);
+        assert_eq!(leds.channel(7).iter().filter(|&&pixel| pixel == 0xFF).count(), 1);
+    }
+}
@@ -0,0 +1,218 @@
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer};
+
+use super::{DaemonError, Effect};
+use crate::color::ColorCorrection;
+use crate::config::{LedConfig, DEFAULT_CHANNELS, DEFAULT_LEDS};
+use crate::error::Result;
+use crate::pixel::PixelFormat;
+use crate::timing::ChipTiming;
+
+pub const DEFAULT_CONFIG: &str = "/etc/rpi-cube.json";
+pub const DEFAULT_SOCKET: &str = "/run/rpi-cube.sock";
+
+/// The daemon's config file, e.g.
+///
+/// ```json
+/// {
+///     "socket_mode": "660",
+///     "channels": 8,
+///     "leds_per_channel": 64,
+///     "chip": "WS2812B",
+///     "gamma": 2.8,
+///     "effect": { "name": "rainbow" },
+///     "e131": { "first_universe": 1 },
+///     "wled_udp": {}
+/// }
+/// ```
+///
+/// Everything is optional. The socket, LED layout, chip, pixel format,
+/// power budget and network listeners are only read at startup, the rest is
+/// applied again on reload.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Path of the control socket.
+    pub socket: PathBuf,
+    /// Permissions of the control socket, in octal like `chmod` takes them.
+    #[serde(deserialize_with = "octal")]
+    pub socket_mode: u32,
+    pub channels: usize,
+    pub leds_per_channel: usize,
+    /// Name of one of the `timing::CHIPS`.
+    pub chip: String,
+    pub pixel_format: PixelFormat,
+    pub gamma: f32,
+    /// Overall current limit in mA.
+    pub power_budget: Option<f32>,
+    pub fps: f32,
+    /// 0.0 to 1.0.
+    pub brightness: f32,
+    /// What to show at startup.
+    pub effect: Effect,
+    /// Network receivers to run, none unless set.
+    pub e131: Option<ListenerConfig>,
+    pub artnet: Option<ListenerConfig>,
+    pub ddp: Option<ListenerConfig>,
+    pub opc: Option<ListenerConfig>,
+    /// WLED realtime UDP.
+    pub wled_udp: Option<ListenerConfig>,
+    /// WLED JSON API.
+    pub wled_http: Option<ListenerConfig>,
+}
+
+/// Where a network receiver listens.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// The protocol's port on every interface unless set.
+    pub address: Option<SocketAddr>,
+    /// Universe of the first channel, for E1.31 and Art-Net.
+    pub first_universe: u16,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig {
+            address: None,
+            first_universe: 1,
+        }
+    }
+}
+
+impl ListenerConfig {
+    pub fn address(&self, port: u16) -> SocketAddr {
+        self.address.unwrap_or((Ipv4Addr::UNSPECIFIED, port).into())
+    }
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            socket: PathBuf::from(DEFAULT_SOCKET),
+            socket_mode: 0o660,
+            channels: DEFAULT_CHANNELS,
+            leds_per_channel: DEFAULT_LEDS,
+            chip: "WS2812B".to_string(),
+            pixel_format: PixelFormat::Grb,
+            gamma: 1.0,
+            power_budget: None,
+            fps: 60.0,
+            brightness: 1.0,
+            effect: Effect::default(),
+            e131: None,
+            artnet: None,
+            ddp: None,
+            opc: None,
+            wled_udp: None,
+            wled_http: None,
+        }
+    }
+}
+
+fn octal<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<u32, D::Error> {
+    let mode = String::deserialize(deserializer)?;
+    u32::from_str_radix(&mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid socket mode {:?}", mode)))
+}
+
+impl DaemonConfig {
+    pub fn load(path: impl AsRef<Path>) -> std::result::Result<Self, DaemonError> {
+        DaemonConfig::from_json(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_json(json: &str) -> std::result::Result<Self, DaemonError> {
+        let config: DaemonConfig = serde_json::from_str(json)?;
+        if ChipTiming::by_name(&config.chip).is_none() {
+            return Err(DaemonError::UnknownChip(config.chip));
+        }
+        if !(config.fps.is_finite() && config.fps > 0.0) {
+            return Err(DaemonError::InvalidSetting("fps"));
+        }
+        if !(0.0..=1.0).contains(&config.brightness) {
+            return Err(DaemonError::InvalidSetting("brightness"));
+        }
+        if !config.correction(config.brightness).is_valid() {
+            return Err(DaemonError::InvalidSetting("gamma"));
+        }
+        if !config.effect.is_valid() {
+            return Err(DaemonError::InvalidSetting("effect"));
+        }
+        Ok(config)
+    }
+
+    /// The driver config for a board whose SMI runs off `source_hz`.
+    pub fn led_config(&self, source_hz: u32) -> Result<LedConfig> {
+        let chip = ChipTiming::by_name(&self.chip).ok_or_else(|| DaemonError::UnknownChip(self.chip.clone()))?;
+        let mut builder = LedConfig::builder()
+            .channels(self.channels)
+            .leds_per_channel(self.leds_per_channel)
+            .timing(&chip.solve_for(source_hz)?)
+            .pixel_format(self.pixel_format)
+            .color_correction(self.correction(self.brightness));
+        if let Some(budget) = self.power_budget {
+            builder = builder.power_budget(budget);
+        }
+        Ok(builder.build()?)
+    }
+
+    pub fn correction(&self, brightness: f32) -> ColorCorrection {
+        ColorCorrection::new().gamma(self.gamma).brightness(brightness)
+    }
+
+    /// The first setting that differs from `other` and can't change without
+    /// a restart.
+    pub fn restart_needed(&self, other: &DaemonConfig) -> Option<&'static str> {
+        [
+            ("socket", self.socket != other.socket || self.socket_mode != other.socket_mode),
+            ("LED layout", (self.channels, self.leds_per_channel) != (other.channels, other.leds_per_channel)),
+            ("chip", !self.chip.eq_ignore_ascii_case(&other.chip)),
+            ("pixel format", self.pixel_format != other.pixel_format),
+            ("power budget", self.power_budget != other.power_budget),
+            ("network listeners", self.listeners() != other.listeners()),
+        ]
+        .into_iter()
+        .find_map(|(setting, changed)| changed.then_some(setting))
+    }
+
+    fn listeners(&self) -> [&Option<ListenerConfig>; 6] {
+        [&self.e131, &self.artnet, &self.ddp, &self.opc, &self.wled_udp, &self.wled_http]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn loads_config() {
+        let config = DaemonConfig::load("tests/fixtures/daemon/rpi-cube.json").unwrap();
+        assert_eq!(config.socket_mode, 0o660);
+        assert_eq!((config.channels, config.leds_per_channel), (8, 64));
+        assert_eq!(config.effect, Effect::Rainbow { speed: 0.1 });
+        assert_eq!(config.socket, PathBuf::from(DEFAULT_SOCKET));
+        assert_eq!(config.e131.as_ref().unwrap().address(crate::net::e131::PORT), "0.0.0.0:5568".parse().unwrap());
+        assert_eq!(config.wled_http.as_ref().unwrap().address, Some("0.0.0.0:8080".parse().unwrap()));
+        assert_eq!(config.opc, None);
+
+        let led_config = config.led_config(crate::timing::SMI_SOURCE_HZ).unwrap();
+        assert_eq!(led_config.leds_per_channel(), 64);
+        assert_eq!(led_config.color_correction(0), ColorCorrection::new().gamma(2.8).brightness(0.5));
+
+        let other = DaemonConfig { chip: "sk6812".to_string(), ..config.clone() };
+        assert_eq!(config.restart_needed(&other), Some("chip"));
+        let other = DaemonConfig { ddp: Some(ListenerConfig::default()), ..config.clone() };
+        assert_eq!(config.restart_needed(&other), Some("network listeners"));
+        assert!(matches!(DaemonConfig::from_json(r#"{ "chip": "APA102" }"#), Err(DaemonError::UnknownChip(_))));
+        assert!(DaemonConfig::from_json(r#"{ "socket_mode": "999" }"#).is_err());
+        assert!(matches!(
+            DaemonConfig::from_json(r#"{ "effect": { "name": "rainbow", "speed": 0 } }"#),
+            Err(DaemonError::InvalidSetting("effect"))
+        ));
+    }
+}
@@ -0,0 +1,186 @@
+use std::f32::consts::TAU;
+
+use serde::{Deserialize, Serialize};
+
+/// What the daemon shows, worked out per LED from its place in the display
+/// (channel after channel) and the time since the daemon started.
+///
+/// Colours are `"#RRGGBB"` (or `"#WWRRGGBB"`) strings or plain numbers, e.g.
+/// `{ "name": "breathe", "color": "#FF8000", "period": 2.0 }`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "name", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Effect {
+    /// Every LED one colour.
+    Solid {
+        #[serde(with = "hex")]
+        color: u32,
+    },
+    /// The colour wheel spread over the display, going round `speed` times a
+    /// second.
+    Rainbow {
+        #[serde(default = "default_speed")]
+        speed: f32,
+    },
+    /// `color` fading in and out every `period` seconds.
+    Breathe {
+        #[serde(with = "hex")]
+        color: u32,
+        #[serde(default = "default_period")]
+        period: f32,
+    },
+    /// A single LED of `color` running through the display at `speed` LEDs a
+    /// second.
+    Chase {
+        #[serde(with = "hex")]
+        color: u32,
+        #[serde(default = "default_chase_speed")]
+        speed: f32,
+    },
+    /// Every other LED `color` and the rest `other`, swapping every `period`
+    /// seconds.
+    Alternate {
+        #[serde(with = "hex")]
+        color: u32,
+        #[serde(with = "hex")]
+        other: u32,
+        #[serde(default = "default_alternate_period")]
+        period: f32,
+    },
+}
+
+fn default_speed() -> f32 {
+    0.2
+}
+
+fn default_period() -> f32 {
+    4.0
+}
+
+fn default_chase_speed() -> f32 {
+    30.0
+}
+
+fn default_alternate_period() -> f32 {
+    1.0
+}
+
+impl Default for Effect {
+    fn default() -> Self {
+        Effect::Solid { color: 0 }
+    }
+}
+
+impl Effect {
+    /// Whether every speed and period is a positive number.
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Effect::Solid { .. } => true,
+            Effect::Rainbow { speed } | Effect::Chase { speed, .. } => speed.is_finite() && speed > 0.0,
+            Effect::Breathe { period, .. } | Effect::Alternate { period, .. } => period.is_finite() && period > 0.0,
+        }
+    }
+
+    /// Colour of LED `n` of `count` at `t` seconds.
+    pub fn pixel(&self, n: usize, count: usize, t: f32) -> u32 {
+        match *self {
+            Effect::Solid { color } => color,
+            Effect::Rainbow { speed } => hue(n as f32 / count as f32 + t * speed),
+            Effect::Breathe { color, period } => dim(color, (1.0 - (TAU * t / period).cos()) / 2.0),
+            Effect::Chase { color, speed } => {
+                if n == (t * speed) as usize % count {
+                    color
+                } else {
+                    0
+                }
+            }
+            Effect::Alternate { color, other, period } => {
+                // a tiny period swaps more often than fits in a usize
+                if (n % 2 + (t / period) as usize % 2).is_multiple_of(2) {
+                    color
+                } else {
+                    other
+                }
+            }
+        }
+    }
+}
+
+// fully saturated colour `hue` turns round the colour wheel from red
+fn hue(hue: f32) -> u32 {
+    let h = hue.rem_euclid(1.0) * 6.0;
+    let x = ((1.0 - (h % 2.0 - 1.0).abs()) * 255.0) as u32;
+    let (r, g, b) = match h as u32 {
+        0 => (255, x, 0),
+        1 => (x, 255, 0),
+        2 => (0, 255, x),
+        3 => (0, x, 255),
+        4 => (x, 0, 255),
+        _ => (255, 0, x),
+    };
+    (r << 16) | (g << 8) | b
+}
+
+fn dim(color: u32, level: f32) -> u32 {
+    u32::from_be_bytes(color.to_be_bytes().map(|c| (c as f32 * level) as u8))
+}
+
+// colours as "#RRGGBB" strings, numbers are taken too
+mod hex {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(color: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("#{:06X}", color))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Color {
+            Number(u32),
+            Hex(String),
+        }
+
+        match Color::deserialize(deserializer)? {
+            Color::Number(color) => Ok(color),
+            Color::Hex(hex) => {
+                let digits = hex.strip_prefix('#').unwrap_or(&hex);
+                match digits.len() {
+                    6 | 8 if digits.chars().all(|c| c.is_ascii_hexdigit()) => {
+                        Ok(u32::from_str_radix(digits, 16).unwrap())
+                    }
+                    _ => Err(D::Error::custom(format!("invalid colour {:?}", hex))),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_and_renders_effects() {
+        let effect: Effect = serde_json::from_str(r##"{ "name": "alternate", "color": "#0000FF", "other": 16711680 }"##).unwrap();
+        assert_eq!(effect, Effect::Alternate { color: 0x0000FF, other: 0xFF0000, period: 1.0 });
+        assert_eq!(serde_json::from_str::<Effect>(&serde_json::to_string(&effect).unwrap()).unwrap(), effect);
+        assert_eq!([effect.pixel(0, 2, 0.5), effect.pixel(1, 2, 0.5)], [0x0000FF, 0xFF0000]);
+        assert_eq!(effect.pixel(0, 2, 1.5), 0xFF0000);
+
+        let rainbow = Effect::Rainbow { speed: 1.0 };
+        assert_eq!(rainbow.pixel(0, 3, 0.0), 0xFF0000);
+        assert_eq!(rainbow.pixel(1, 3, 0.0), 0x00FF00);
+        assert_eq!(rainbow.pixel(0, 3, 2.0 / 3.0), 0x0000FF);
+
+        assert!(serde_json::from_str::<Effect>(r#"{ "name": "solid", "color": "red" }"#).is_err());
+        assert!(serde_json::from_str::<Effect>(r#"{ "name": "solid", "color": 0, "speed": 1 }"#).is_err());
+
+        let fast = Effect::Alternate { color: 1, other: 2, period: 1e-30 };
+        assert!(fast.is_valid());
+        assert_eq!(fast.pixel(1, 2, 1.0), 1);
+        assert!(!Effect::Breathe { color: 1, period: 0.0 }.is_valid());
+        assert!(!Effect::Chase { color: 1, speed: -1.0 }.is_valid());
+        assert!(!Effect::Rainbow { speed: f32::NAN }.is_valid());
+    }
+}
@@ -0,0 +1,442 @@
+//! Long running daemon that owns the LEDs and takes commands over a Unix
+//! domain socket.
+//!
+//! The daemon is the only process that needs root and `/dev/mem`, everything
+//! else talks to it through the control socket (see `Client`) with one JSON
+//! `Command` per line, answered by one JSON `Response` per line. Every client
+//! connection is read on a thread of its own, commands are handed to the
+//! render loop in between frames so only that touches the `LedDriver`.
+//!
+//! The `net` receivers set in the config are polled in between frames too,
+//! writing straight to the `LedDriver`. Their frames take the place of the
+//! `Effect` while they keep coming, and once the WLED JSON API changes the
+//! `WledState` that is shown until the next `effect` command.
+
+mod config;
+mod effect;
+mod network;
+mod protocol;
+
+use std::fs::{self, DirBuilder, Permissions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+use thiserror::Error;
+
+pub use config::{DaemonConfig, ListenerConfig, DEFAULT_CONFIG, DEFAULT_SOCKET};
+pub use effect::Effect;
+pub use protocol::{Client, Command, Response, Status};
+
+use crate::driver::LedDriver;
+use crate::error::Result;
+use crate::hal::Backend;
+use network::Listeners;
+
+#[derive(Error, Debug)]
+pub enum DaemonError {
+    #[error("can't read config: {0}")]
+    Io(#[from] io::Error),
+    #[error("can't parse config: {0}")]
+    Config(#[from] serde_json::Error),
+    #[error("unknown chip {0:?}")]
+    UnknownChip(String),
+    #[error("invalid {0}")]
+    InvalidSetting(&'static str),
+    #[error("the {0} changed, restart the daemon to apply it")]
+    RestartNeeded(&'static str),
+    #[error("another daemon is listening on {0}")]
+    SocketInUse(PathBuf),
+    #[error("invalid message: {0}")]
+    Protocol(serde_json::Error),
+    #[error("message longer than {0} bytes")]
+    MessageTooLong(u64),
+    #[error("the daemon closed the connection")]
+    Disconnected,
+}
+
+// a command and where to send its response
+type Request = (Command, Sender<Response>);
+
+// longest command line taken from a client
+const MAX_LINE_LEN: u64 = 64 * 1024;
+
+pub struct Daemon<B: Backend> {
+    leds: LedDriver<B>,
+    config_path: PathBuf,
+    config: DaemonConfig,
+    requests: Receiver<Request>,
+    listeners: Listeners,
+    effect: Effect,
+    brightness: f32,
+    blank: bool,
+    started: Instant,
+    frames: u64,
+}
+
+impl<B: Backend> Daemon<B> {
+    /// Loads the config at `config_path`, sets up the LEDs on `backend` and
+    /// starts listening on the control socket.
+    ///
+    /// A socket left behind by a daemon that didn't shut down cleanly is
+    /// replaced, one that another daemon still answers on is an error.
+    pub fn new(backend: B, config_path: impl Into<PathBuf>) -> Result<Self> {
+        let config_path = config_path.into();
+        let config = DaemonConfig::load(&config_path)?;
+        let led_config = config.led_config(backend.platform().soc.smi_source_hz())?;
+        let leds = LedDriver::with_backend(backend, led_config)?;
+        let listeners = Listeners::new(&config)?;
+        let requests = listen(&config.socket, config.socket_mode)?;
+        info!("listening on {}", config.socket.display());
+
+        Ok(Daemon {
+            leds,
+            config_path,
+            effect: config.effect,
+            brightness: config.brightness,
+            config,
+            requests,
+            listeners,
+            blank: false,
+            started: Instant::now(),
+            frames: 0,
+        })
+    }
+
+    pub fn leds(&self) -> &LedDriver<B> {
+        &self.leds
+    }
+
+    pub fn config(&self) -> &DaemonConfig {
+        &self.config
+    }
+
+    /// Sends frames at the configured rate until `running` is cleared, then
+    /// blanks the LEDs.
+    pub fn run(&mut self, running: &AtomicBool) -> Result<()> {
+        let mut next = Instant::now();
+        while running.load(Ordering::Relaxed) {
+            self.step(next)?;
+            // don't try to catch up after a slow frame
+            next = (next + Duration::from_secs_f32(1.0 / self.config.fps)).max(Instant::now());
+        }
+        self.leds.fill(0);
+        self.leds.show()
+    }
+
+    /// Handles commands until `deadline`, then the network receivers, and
+    /// renders a frame and starts sending it unless they have one.
+    pub fn step(&mut self, deadline: Instant) -> Result<()> {
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            match self.requests.recv_timeout(timeout) {
+                Ok((command, reply)) => {
+                    // the client may have gone already
+                    let _ = reply.send(self.handle(command));
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::other("control socket listener stopped").into())
+                }
+            }
+        }
+
+        let t = self.started.elapsed().as_secs_f32();
+        // blanking leaves the packets waiting until the next effect
+        let shown = match self.blank {
+            true => false,
+            false => self.listeners.poll(Instant::now(), &mut self.leds)? || self.listeners.render_wled(t, &mut self.leds)?,
+        };
+        if !shown {
+            let (nchans, nleds) = (self.leds.nchans(), self.leds.nleds());
+            for channel in 0..nchans {
+                for (index, pixel) in self.leds.channel_mut(channel).iter_mut().enumerate() {
+                    *pixel = match self.blank {
+                        true => 0,
+                        false => self.effect.pixel(channel * nleds + index, nchans * nleds, t),
+                    };
+                }
+            }
+            self.leds.present()?;
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn handle(&mut self, command: Command) -> Response {
+        debug!("command {:?}", command);
+        let result = match command {
+            Command::Effect { effect } if !effect.is_valid() => Err(DaemonError::InvalidSetting("effect").into()),
+            Command::Effect { effect } => {
+                self.effect = effect;
+                self.blank = false;
+                self.listeners.show_effect();
+                Ok(())
+            }
+            Command::Brightness { brightness } => self.set_brightness(brightness),
+            Command::Blank => {
+                self.blank = true;
+                Ok(())
+            }
+            Command::Status => return Response::Status(self.status()),
+            Command::Reload => self.reload(),
+        };
+        match result {
+            Ok(()) => Response::Ok,
+            Err(err) => Response::Error { message: err.to_string() },
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            effect: self.effect,
+            brightness: self.brightness,
+            blank: self.blank,
+            channels: self.leds.nchans(),
+            leds_per_channel: self.leds.nleds(),
+            fps: self.config.fps,
+            frames: self.frames,
+            power_ma: self.leds.power().total_ma(),
+            uptime_secs: self.started.elapsed().as_secs(),
+        }
+    }
+
+    fn set_brightness(&mut self, brightness: f32) -> Result<()> {
+        if !(0.0..=1.0).contains(&brightness) {
+            return Err(DaemonError::InvalidSetting("brightness").into());
+        }
+        for channel in 0..self.leds.nchans() {
+            self.leds.set_color_correction(channel, self.config.correction(brightness))?;
+        }
+        self.brightness = brightness;
+        Ok(())
+    }
+
+    fn reload(&mut self) -> Result<()> {
+        let config = DaemonConfig::load(&self.config_path)?;
+        if let Some(setting) = self.config.restart_needed(&config) {
+            return Err(DaemonError::RestartNeeded(setting).into());
+        }
+        info!("reloaded {}", self.config_path.display());
+
+        self.config = config;
+        self.effect = self.config.effect;
+        self.blank = false;
+        self.listeners.show_effect();
+        self.set_brightness(self.config.brightness)
+    }
+}
+
+impl<B: Backend> Drop for Daemon<B> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.config.socket);
+    }
+}
+
+fn listen(socket: &Path, mode: u32) -> Result<Receiver<Request>> {
+    if UnixStream::connect(socket).is_ok() {
+        return Err(DaemonError::SocketInUse(socket.to_path_buf()).into());
+    }
+    match fs::remove_file(socket) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+
+    let listener = bind(socket, mode)?;
+    let (sender, requests) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let sender = sender.clone();
+                    thread::spawn(move || {
+                        if let Err(err) = serve(stream, sender) {
+                            debug!("control client dropped: {}", err);
+                        }
+                    });
+                }
+                Err(err) => debug!("control socket accept failed: {}", err),
+            }
+        }
+    });
+    Ok(requests)
+}
+
+// binds in a directory only we can get into and moves the socket into place
+// once it has its permissions, so no one can connect before that
+fn bind(socket: &Path, mode: u32) -> io::Result<UnixListener> {
+    let dir = socket.with_file_name(format!(".rpi-cube.{}", std::process::id()));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let bound = dir.join("sock");
+    let result = UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, Permissions::from_mode(mode))?;
+        fs::rename(&bound, socket)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&bound);
+    fs::remove_dir(&dir)?;
+    result
+}
+
+fn serve(stream: UnixStream, sender: Sender<Request>) -> io::Result<()> {
+    let (reply, response) = mpsc::channel();
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if (&mut reader).take(MAX_LINE_LEN).read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && line.len() as u64 == MAX_LINE_LEN {
+            let response = Response::Error { message: DaemonError::MessageTooLong(MAX_LINE_LEN).to_string() };
+            return write_response(&mut writer, &response);
+        }
+
+        let response = match serde_json::from_str(&line) {
+            Ok(command) => {
+                if sender.send((command, reply.clone())).is_err() {
+                    return Ok(());
+                }
+                match response.recv() {
+                    Ok(response) => response,
+                    Err(_) => return Ok(()),
+                }
+            }
+            Err(err) => Response::Error { message: DaemonError::Protocol(err).to_string() },
+        };
+        write_response(&mut writer, &response)?;
+    }
+}
+
+fn write_response(writer: &mut impl Write, response: &Response) -> io::Result<()> {
+    let mut line = serde_json::to_string(response)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hal::Simulated;
+
+    #[test]
+    fn takes_commands_over_the_socket() {
+        let dir = std::env::temp_dir().join(format!("rpi-cube-daemon-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("control.sock");
+        let config_path = dir.join("config.json");
+        let config = format!(r#"{{ "socket": {:?}, "leds_per_channel": 4, "socket_mode": "600" }}"#, socket);
+        fs::write(&config_path, config).unwrap();
+
+        let mut daemon = Daemon::new(Simulated::new(), &config_path).unwrap();
+        let client = thread::spawn({
+            let socket = socket.clone();
+            move || {
+                let mut client = Client::connect(&socket).unwrap();
+                let effect = Effect::Alternate { color: 0x0000FF, other: 0xFF0000, period: 1000.0 };
+                let stopped = Effect::Breathe { color: 0, period: 0.0 };
+                let responses = [
+                    Command::Effect { effect },
+                    Command::Brightness { brightness: 2.0 },
+                    Command::Status,
+                    Command::Effect { effect: stopped },
+                ]
+                    .iter()
+                    .map(|command| client.send(command).unwrap())
+                    .collect::<Vec<_>>();
+                let mut raw = UnixStream::connect(&socket).unwrap();
+                raw.write_all(b"{\"command\": \"dance\"}\n").unwrap();
+                let mut line = String::new();
+                BufReader::new(raw).read_line(&mut line).unwrap();
+
+                // a client can't make the daemon buffer without end
+                let mut raw = UnixStream::connect(&socket).unwrap();
+                raw.write_all(&[b' '; MAX_LINE_LEN as usize]).unwrap();
+                let mut long = String::new();
+                BufReader::new(raw).read_to_string(&mut long).unwrap();
+                (responses, line, long)
+            }
+        });
+        while !client.is_finished() {
+            daemon.step(Instant::now() + Duration::from_millis(5)).unwrap();
+        }
+        let (responses, line, long) = client.join().unwrap();
+
+        assert_eq!(responses[0], Response::Ok);
+        assert!(matches!(responses[1], Response::Error { .. }));
+        let Response::Status(status) = &responses[2] else { panic!("{:?}", responses[2]) };
+        assert_eq!((status.channels, status.leds_per_channel, status.brightness), (8, 4, 1.0));
+        assert!(matches!(responses[3], Response::Error { .. }));
+        assert!(line.contains("\"result\":\"error\""), "{}", line);
+        assert!(long.contains("longer than"), "{}", long);
+        assert_eq!(fs::metadata(&socket).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(daemon.leds().channel(7), [0x0000FF, 0xFF0000, 0x0000FF, 0xFF0000]);
+        assert!(matches!(Daemon::new(Simulated::new(), &config_path), Err(crate::Error::Daemon(DaemonError::SocketInUse(_)))));
+
+        // the layout only changes on restart, the rest is reloaded
+        assert_eq!(daemon.handle(Command::Blank), Response::Ok);
+        fs::write(&config_path, format!(r#"{{ "socket": {:?}, "leds_per_channel": 4, "socket_mode": "600", "brightness": 0.25 }}"#, socket)).unwrap();
+        assert_eq!(daemon.handle(Command::Reload), Response::Ok);
+        assert_eq!(daemon.status().brightness, 0.25);
+        assert!(!daemon.status().blank);
+        fs::write(&config_path, format!(r#"{{ "socket": {:?}, "socket_mode": "600" }}"#, socket)).unwrap();
+        assert!(matches!(daemon.handle(Command::Reload), Response::Error { .. }));
+
+        drop(daemon);
+        assert!(!socket.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn shows_network_frames() {
+        let dir = std::env::temp_dir().join(format!("rpi-cube-daemon-net-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("config.json");
+        // ports that were free a moment ago
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let http = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let config = format!(
+            r#"{{ "socket": {:?}, "leds_per_channel": 4, "wled_udp": {{ "address": "{}" }}, "wled_http": {{ "address": "{}" }} }}"#,
+            dir.join("control.sock"),
+            udp,
+            http
+        );
+        fs::write(&config_path, config).unwrap();
+        let mut daemon = Daemon::new(Simulated::new(), &config_path).unwrap();
+        let step = |daemon: &mut Daemon<Simulated>| daemon.step(Instant::now() + Duration::from_millis(5)).unwrap();
+
+        // the WLED state replaces the effect once the app sets it
+        step(&mut daemon);
+        assert_eq!(daemon.leds().channel(0), [0; 4]);
+        let body = r#"{"on":true,"bri":255,"seg":[{"col":[[0,0,255]]}]}"#;
+        let mut stream = std::net::TcpStream::connect(http).unwrap();
+        write!(stream, "POST /json/state HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        stream.read_to_string(&mut String::new()).unwrap();
+        step(&mut daemon);
+        assert_eq!(daemon.leds().channel(0), [0x0000FF; 4]);
+
+        // realtime packets win until their timeout of a second runs out
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(&[crate::net::wled::DRGB, 1, 255, 0, 0], udp).unwrap();
+        step(&mut daemon);
+        assert_eq!(daemon.leds().channel(0), [0xFF0000, 0x0000FF, 0x0000FF, 0x0000FF]);
+        step(&mut daemon);
+        assert_eq!(daemon.leds().channel(0)[0], 0xFF0000);
+        thread::sleep(Duration::from_millis(1100));
+        step(&mut daemon);
+        assert_eq!(daemon.leds().channel(0), [0x0000FF; 4]);
+
+        assert_eq!(daemon.handle(Command::Effect { effect: Effect::Solid { color: 0x00FF00 } }), Response::Ok);
+        step(&mut daemon);
+        assert_eq!(daemon.leds().channel(7), [0x00FF00; 4]);
+
+        drop(daemon);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
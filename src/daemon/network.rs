@@ -0,0 +1,195 @@
+use std::io;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use super::{DaemonConfig, ListenerConfig};
+use crate::error::{Error, Result};
+use crate::net::artnet::{self, ArtNetNode};
+use crate::net::ddp::{self, DdpReceiver};
+use crate::net::e131::{self, E131Receiver};
+use crate::net::opc::{self, OpcServer};
+use crate::net::wled::{self, WledInfo, WledRealtime, WledServer, WledState};
+use crate::net::{PixelSink, UniverseMap};
+
+// how long network frames keep the daemon's effect off after the last one,
+// as long as WLED waits by default
+const FRAME_TIMEOUT: Duration = Duration::from_millis(2500);
+
+// most packets taken from a receiver per frame, so a flood of them can't
+// stall the render loop
+const MAX_PACKETS: usize = 64;
+
+/// The `net` receivers set in the config, polled by the render loop.
+pub(super) struct Listeners {
+    e131: Option<E131Receiver>,
+    artnet: Option<ArtNetNode>,
+    ddp: Option<DdpReceiver>,
+    opc: Option<OpcServer>,
+    wled_udp: Option<WledRealtime>,
+    wled_http: Option<WledServer>,
+    // when a receiver other than WLED's last presented a frame
+    last_frame: Option<Instant>,
+    // the WLED state last seen and whether it's shown instead of the effect
+    wled_state: WledState,
+    wled: bool,
+}
+
+impl Listeners {
+    pub fn new(config: &DaemonConfig) -> Result<Self> {
+        let (nchans, nleds) = (config.channels, config.leds_per_channel);
+        let components = if config.pixel_format.has_white() { 4 } else { 3 };
+        let map = |listener: &ListenerConfig| UniverseMap::contiguous(listener.first_universe, nchans, nleds, components);
+
+        let e131 = config
+            .e131
+            .as_ref()
+            .map(|listener| -> Result<_> {
+                let receiver = E131Receiver::bind(listener.address(e131::PORT), map(listener)?)?;
+                if let Err(err) = receiver.join_multicast(Ipv4Addr::UNSPECIFIED) {
+                    warn!("E1.31: can't join the multicast groups, unicast only: {}", err);
+                }
+                receiver.set_nonblocking(true)?;
+                info!("E1.31 on {}", receiver.local_addr()?);
+                Ok(receiver)
+            })
+            .transpose()?;
+        let artnet = config
+            .artnet
+            .as_ref()
+            .map(|listener| -> Result<_> {
+                let node = ArtNetNode::bind(listener.address(artnet::PORT), map(listener)?)?;
+                node.set_nonblocking(true)?;
+                info!("Art-Net on {}", node.local_addr()?);
+                Ok(node)
+            })
+            .transpose()?;
+        let ddp = config
+            .ddp
+            .as_ref()
+            .map(|listener| -> Result<_> {
+                let receiver = DdpReceiver::bind(listener.address(ddp::PORT), components)?;
+                receiver.set_nonblocking(true)?;
+                info!("DDP on {}", receiver.local_addr()?);
+                Ok(receiver)
+            })
+            .transpose()?;
+        let opc = config
+            .opc
+            .as_ref()
+            .map(|listener| -> Result<_> {
+                let server = OpcServer::bind(listener.address(opc::PORT))?;
+                info!("OPC on {}", server.local_addr());
+                Ok(server)
+            })
+            .transpose()?;
+        let wled_udp = config
+            .wled_udp
+            .as_ref()
+            .map(|listener| -> Result<_> {
+                let receiver = WledRealtime::bind(listener.address(wled::UDP_PORT))?;
+                receiver.set_nonblocking(true)?;
+                info!("WLED realtime on {}", receiver.local_addr()?);
+                Ok(receiver)
+            })
+            .transpose()?;
+        let wled_http = config
+            .wled_http
+            .as_ref()
+            .map(|listener| -> Result<_> {
+                let server = WledServer::bind(listener.address(wled::HTTP_PORT), WledInfo::new("rpi-cube", nchans * nleds))?;
+                info!("WLED JSON API on {}", server.local_addr());
+                Ok(server)
+            })
+            .transpose()?;
+
+        Ok(Listeners {
+            e131,
+            artnet,
+            ddp,
+            opc,
+            wled_udp,
+            wled_http,
+            last_frame: None,
+            wled_state: WledState::default(),
+            wled: false,
+        })
+    }
+
+    /// Handles what came in since the last call, the receivers present
+    /// frames on `sink` as they complete them. Returns true while frames
+    /// keep coming, the daemon's effect stays off until then.
+    pub fn poll(&mut self, now: Instant, sink: &mut impl PixelSink) -> Result<bool> {
+        let mut presented = false;
+        if let Some(receiver) = &mut self.e131 {
+            presented |= drain("E1.31", || receiver.recv(sink))?;
+        }
+        if let Some(node) = &mut self.artnet {
+            presented |= drain("Art-Net", || node.recv(sink))?;
+        }
+        if let Some(receiver) = &mut self.ddp {
+            presented |= drain("DDP", || receiver.recv(sink))?;
+        }
+        if let Some(server) = &mut self.opc {
+            presented |= server.try_recv(sink)?;
+        }
+        if presented {
+            self.last_frame = Some(now);
+        }
+
+        // WLED senders say themselves how long they stay live
+        let wled_live = match &mut self.wled_udp {
+            Some(receiver) => {
+                drain("WLED", || receiver.recv(sink))?;
+                receiver.is_live(now)
+            }
+            None => false,
+        };
+        let live = wled_live || self.last_frame.is_some_and(|at| now.duration_since(at) < FRAME_TIMEOUT);
+        if let Some(server) = &self.wled_http {
+            server.set_live(live);
+        }
+        Ok(live)
+    }
+
+    /// Shows the WLED state on `sink` at `t` seconds once the JSON API has
+    /// changed it, returns true if it did.
+    pub fn render_wled(&mut self, t: f32, sink: &mut impl PixelSink) -> Result<bool> {
+        let Some(server) = &self.wled_http else {
+            return Ok(false);
+        };
+        let state = server.state();
+        if state != self.wled_state {
+            self.wled_state = state;
+            self.wled = true;
+        }
+        if self.wled {
+            server.render(t, sink)?;
+        }
+        Ok(self.wled)
+    }
+
+    /// Goes back to the daemon's effect until the WLED state changes again.
+    pub fn show_effect(&mut self) {
+        self.wled = false;
+    }
+}
+
+// handles the packets waiting on a non-blocking receiver, a failed receive
+// is logged rather than stopping the daemon
+fn drain(protocol: &str, mut recv: impl FnMut() -> Result<bool>) -> Result<bool> {
+    let mut presented = false;
+    for _ in 0..MAX_PACKETS {
+        match recv() {
+            Ok(frame) => presented |= frame,
+            Err(Error::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(Error::Io(err)) => {
+                debug!("{}: receive failed: {}", protocol, err);
+                break;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(presented)
+}
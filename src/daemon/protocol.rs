@@ -0,0 +1,75 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::{DaemonError, Effect};
+use crate::error::Result;
+
+/// A request to the daemon, one JSON object per line, e.g.
+/// `{ "command": "brightness", "brightness": 0.5 }`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Command {
+    /// Shows `effect` from the next frame on, ending a blank.
+    Effect { effect: Effect },
+    /// 0.0 to 1.0, on top of the gamma.
+    Brightness { brightness: f32 },
+    /// Turns every LED off until the next effect.
+    Blank,
+    Status,
+    /// Reads the config file again and goes back to its effect and
+    /// brightness.
+    Reload,
+}
+
+/// The daemon's answer to a `Command`, one JSON object per line.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "lowercase")]
+pub enum Response {
+    Ok,
+    Status(Status),
+    Error { message: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    pub effect: Effect,
+    pub brightness: f32,
+    pub blank: bool,
+    pub channels: usize,
+    pub leds_per_channel: usize,
+    pub fps: f32,
+    /// Frames sent since the daemon started.
+    pub frames: u64,
+    /// Estimated draw of the last frame, after power limiting.
+    pub power_ma: f32,
+    pub uptime_secs: u64,
+}
+
+/// Connection to a daemon's control socket.
+pub struct Client {
+    stream: BufReader<UnixStream>,
+}
+
+impl Client {
+    pub fn connect(socket: impl AsRef<Path>) -> Result<Self> {
+        Ok(Client {
+            stream: BufReader::new(UnixStream::connect(socket)?),
+        })
+    }
+
+    /// Sends `command` and waits for the answer.
+    pub fn send(&mut self, command: &Command) -> Result<Response> {
+        let mut line = serde_json::to_string(command).map_err(DaemonError::Protocol)?;
+        line.push('\n');
+        self.stream.get_mut().write_all(line.as_bytes())?;
+
+        line.clear();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err(DaemonError::Disconnected.into());
+        }
+        Ok(serde_json::from_str(&line).map_err(DaemonError::Protocol)?)
+    }
+}
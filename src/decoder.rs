@@ -0,0 +1,241 @@
+use thiserror::Error;
+
+use crate::config::LedConfig;
+use crate::encoder::BitTiming;
+use crate::pixel::PixelFormat;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("malformed bit cell on channel {channel}, led {led} bit {bit}: {high} high pulses")]
+    MalformedBit {
+        channel: usize,
+        led: usize,
+        bit: usize,
+        high: usize,
+    },
+    #[error("pulse after the falling edge on channel {channel}, led {led} bit {bit}")]
+    Glitch { channel: usize, led: usize, bit: usize },
+    #[error("data on channel {channel} ends part way through led {led} after {bits} bits")]
+    Truncated { channel: usize, led: usize, bits: usize },
+}
+
+/// Turns an encoded SMI buffer back into per-channel pixel values, the
+/// inverse of `Encoder::encode`.
+///
+/// Decoding starts after the configured prebits gap, a channel's data ends at
+/// the first bit cell where its line stays low, i.e. the reset gap after the
+/// LED data.
+pub struct Decoder {
+    nchans: usize,
+    timing: BitTiming,
+    prebits: usize,
+    formats: Vec<PixelFormat>,
+}
+
+impl Decoder {
+    pub fn new(config: &LedConfig) -> Self {
+        Decoder {
+            nchans: config.channels(),
+            timing: config.bit_timing(),
+            prebits: config.prebits(),
+            formats: (0..config.channels()).map(|chan| config.pixel_format(chan)).collect(),
+        }
+    }
+
+    /// Decodes `buf` into one `0xWWRRGGBB` pixel list per channel, using each
+    /// channel's pixel format.
+    pub fn decode(&self, buf: &[u8]) -> Result<Vec<Vec<u32>>, DecodeError> {
+        let npulses = self.timing.npulses;
+        let nsamples = buf.len() / (self.nchans / 8);
+
+        let mut channels = vec![Vec::new(); self.nchans];
+        let mut words = vec![0u32; self.nchans];
+        // cell each channel's data ended at
+        let mut ends = vec![None; self.nchans];
+        let mut cell = 0;
+        while ends.iter().any(Option::is_none) {
+            let off = self.prebits + cell * npulses;
+            if off + npulses > nsamples {
+                break;
+            }
+
+            let samples = (off..off + npulses)
+                .map(|n| self.read_sample(buf, n))
+                .collect::<Vec<_>>();
+
+            for (channel, word) in words.iter_mut().enumerate() {
+                let format = self.formats[channel];
+                let high = samples.iter().take_while(|&&s| s & (1 << channel) != 0).count();
+                let glitch = samples[high..].iter().any(|&s| s & (1 << channel) != 0);
+
+                let (led, bit) = (cell / format.bits(), cell % format.bits());
+                if ends[channel].is_some() {
+                    // nothing but low cells after the reset
+                    if high > 0 || glitch {
+                        return Err(DecodeError::Glitch { channel, led, bit });
+                    }
+                    continue;
+                }
+                if glitch {
+                    return Err(DecodeError::Glitch { channel, led, bit });
+                }
+                if high == 0 {
+                    ends[channel] = Some(cell);
+                    continue;
+                }
+
+                *word <<= 1;
+                if high == self.timing.t1_pulses {
+                    *word |= 1;
+                } else if high != self.timing.t0_pulses {
+                    return Err(DecodeError::MalformedBit { channel, led, bit, high });
+                }
+
+                if bit == format.bits() - 1 {
+                    channels[channel].push(format.from_wire(*word));
+                    *word = 0;
+                }
+            }
+
+            cell += 1;
+        }
+
+        for (channel, end) in ends.iter().enumerate() {
+            let bits = self.formats[channel].bits();
+            let end = end.unwrap_or(cell);
+            if end % bits != 0 {
+                return Err(DecodeError::Truncated {
+                    channel,
+                    led: end / bits,
+                    bits: end % bits,
+                });
+            }
+        }
+
+        Ok(channels)
+    }
+
+    #[inline(always)]
+    fn read_sample(&self, buf: &[u8], n: usize) -> u32 {
+        match self.nchans {
+            8 => buf[n] as u32,
+            _ => u16::from_le_bytes([buf[n * 2], buf[n * 2 + 1]]) as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::Rng;
+
+    use super::*;
+    use crate::encoder::Encoder;
+
+    const FORMATS: [PixelFormat; 4] =
+        [PixelFormat::Rgb, PixelFormat::Grb, PixelFormat::Rgbw, PixelFormat::Wgrb];
+
+    #[test]
+    fn decodes_what_the_encoder_wrote() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..200 {
+            let nchans = if rng.gen() { 8 } else { 16 };
+            let nleds = rng.gen_range(1..20);
+            let timing = [
+                BitTiming::default(),
+                BitTiming { npulses: 4, t0_pulses: 1, t1_pulses: 3 },
+                BitTiming { npulses: 5, t0_pulses: 2, t1_pulses: 4 },
+            ][rng.gen_range(0..3)];
+
+            let formats = (0..nchans)
+                .map(|_| FORMATS[rng.gen_range(0..FORMATS.len())])
+                .collect::<Vec<_>>();
+
+            let channels = (0..rng.gen_range(1..=nchans))
+                .map(|chan| {
+                    let max = if formats[chan].has_white() { u32::MAX } else { 0xFFFFFF };
+                    (0..rng.gen_range(0..=nleds))
+                        .map(|_| rng.gen_range(0..=max))
+                        .collect::<Vec<u32>>()
+                })
+                .collect::<Vec<_>>();
+            let slices = channels.iter().map(|c| c.as_slice()).collect::<Vec<_>>();
+
+            let config = formats
+                .iter()
+                .enumerate()
+                .fold(LedConfig::builder(), |builder, (chan, &format)| {
+                    builder.channel_format(chan, format)
+                })
+                .channels(nchans)
+                .leds_per_channel(nleds)
+                .reset_bits(rng.gen_range(0..10), rng.gen_range(1..100))
+                .bit_timing(timing)
+                .build()
+                .unwrap();
+            let mut encoder = Encoder::new(&config);
+            let longest = channels.iter().map(|c| c.len()).max().unwrap();
+            let mut buf = vec![0; encoder.tx_buff_size(longest)];
+            encoder.encode(&mut buf, &slices).unwrap();
+
+            let decoded = Decoder::new(&config).decode(&buf).unwrap();
+            for (chan, leds) in decoded.iter().enumerate() {
+                let mut expected = channels.get(chan).cloned().unwrap_or_default();
+                expected.resize(longest, 0);
+                assert_eq!(leds, &expected);
+            }
+        }
+    }
+
+    #[test]
+    fn flags_malformed_bit_cells() {
+        let config = LedConfig::default();
+        let mut encoder = Encoder::new(&config);
+        let mut buf = vec![0; encoder.tx_buff_size(2)];
+        encoder.encode(&mut buf, &[&[0xFFFFFF, 0x000000]]).unwrap();
+        let decoder = Decoder::new(&config);
+
+        // stretch a 1 bit on D0 into the last pulse of its cell
+        let mut stretched = buf.clone();
+        stretched[encoder.led_tx_offset(0) + 5] |= 1;
+        assert_eq!(
+            decoder.decode(&stretched),
+            Err(DecodeError::MalformedBit { channel: 0, led: 0, bit: 1, high: 3 })
+        );
+
+        // a pulse after the falling edge of a 0 bit
+        let mut glitched = buf.clone();
+        glitched[encoder.led_tx_offset(1) + 2] |= 1 << 3;
+        assert_eq!(
+            decoder.decode(&glitched),
+            Err(DecodeError::Glitch { channel: 3, led: 1, bit: 0 })
+        );
+
+        // data that stops part way through an LED
+        let truncated = &buf[..encoder.led_tx_offset(1) + 3 * BitTiming::default().npulses];
+        assert_eq!(
+            decoder.decode(truncated),
+            Err(DecodeError::Truncated { channel: 0, led: 1, bits: 3 })
+        );
+    }
+
+    #[test]
+    fn decodes_mixed_pixel_formats() {
+        let config = LedConfig::builder()
+            .channel_format(1, PixelFormat::Rgbw)
+            .channel_format(2, PixelFormat::Brg)
+            .build()
+            .unwrap();
+        let mut encoder = Encoder::new(&config);
+        let mut buf = vec![0; encoder.tx_buff_size(2)];
+        let channels: [&[u32]; 3] = [
+            &[0x112233, 0x445566],
+            &[0x44112233, 0xFF000000],
+            &[0x112233, 0x000001],
+        ];
+        encoder.encode(&mut buf, &channels).unwrap();
+
+        let decoded = Decoder::new(&config).decode(&buf).unwrap();
+        assert_eq!(&decoded[..3], &channels);
+        assert_eq!(decoded[3], &[0, 0]);
+    }
+}
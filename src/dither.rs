@@ -0,0 +1,105 @@
+/// Widens a `0xWWRRGGBB` pixel to 16 bits per component,
+/// `0xWWWWRRRRGGGGBBBB`.
+pub fn widen(color: u32) -> u64 {
+    color
+        .to_be_bytes()
+        .iter()
+        .fold(0, |wide, &c| (wide << 16) | (c as u64 * 257))
+}
+
+/// Rounds a 16 bits per component pixel to the nearest `0xWWRRGGBB`.
+pub fn narrow(color: u64) -> u32 {
+    (0..4).fold(0, |narrow, n| {
+        let level = (color >> (48 - n * 16)) as u16 as u32;
+        (narrow << 8) | ((level + 128) / 257)
+    })
+}
+
+/// Temporal dithering of 16 bits per component frames down to the 8 bits
+/// the LEDs take, in the style of the FadeCandy firmware.
+///
+/// Every LED keeps the rounding error of each component from the frames it
+/// was sent and adds it to the next one, so at 60+ fps a level between two
+/// 8-bit steps comes out as a mix of both that averages to the right value.
+#[derive(Clone, Debug, Default)]
+pub struct Dither {
+    // per channel, per LED, the error of white, red, green and blue so far
+    residuals: Vec<Vec<[i16; 4]>>,
+}
+
+impl Dither {
+    pub fn new(nchans: usize, nleds: usize) -> Self {
+        Dither {
+            residuals: vec![vec![[0; 4]; nleds]; nchans],
+        }
+    }
+
+    /// Forgets the errors carried over, e.g. after a jump in the animation.
+    pub fn reset(&mut self) {
+        for leds in self.residuals.iter_mut() {
+            leds.fill([0; 4]);
+        }
+    }
+
+    /// Dithers LED `index` of `channel` to 8 bits per component.
+    pub fn apply(&mut self, channel: usize, index: usize, color: u64) -> u32 {
+        if self.residuals.len() <= channel {
+            self.residuals.resize(channel + 1, Vec::new());
+        }
+        let leds = &mut self.residuals[channel];
+        if leds.len() <= index {
+            leds.resize(index + 1, [0; 4]);
+        }
+
+        let residual = &mut leds[index];
+        (0..4).fold(0, |out, n| {
+            let level = (color >> (48 - n * 16)) as u16 as i32;
+            (out << 8) | quantize(level, &mut residual[n]) as u32
+        })
+    }
+}
+
+fn quantize(level: i32, residual: &mut i16) -> u8 {
+    // fully off stays off, a dithered single step flickers visibly
+    if level == 0 {
+        *residual = 0;
+        return 0;
+    }
+
+    let target = level + *residual as i32;
+    let out = ((target + 128) / 257).clamp(0, 255);
+    *residual = (target - out * 257).clamp(-257, 257) as i16;
+    out as u8
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn widens_and_narrows() {
+        assert_eq!(widen(0x00FF8001), 0x0000_FFFF_8080_0101);
+        assert_eq!(narrow(0x0000_FFFF_8080_0101), 0x00FF8001);
+        assert_eq!(narrow(0x0000_0080_017F_0000), 0x00000100);
+    }
+
+    #[test]
+    fn averages_to_the_16_bit_level() {
+        let mut dither = Dither::new(1, 2);
+
+        // half way between 1 and 2, and a quarter of the way from 0x80 to 0x81
+        let color = 0x0000_0180_8080 + 64;
+        let frames = (0..64).map(|_| dither.apply(0, 1, color)).collect::<Vec<_>>();
+
+        let green = frames.iter().map(|c| c >> 8 & 0xFF).collect::<Vec<_>>();
+        assert!(green.iter().all(|&g| g == 1 || g == 2));
+        assert_eq!(green.iter().sum::<u32>(), 96);
+
+        let blue = frames.iter().map(|c| c & 0xFF).sum::<u32>();
+        assert_eq!(blue, 0x80 * 64 + 16);
+        assert!(frames.iter().all(|c| c >> 16 == 0));
+
+        dither.reset();
+        assert_eq!(dither.apply(0, 0, widen(0x00112233)), 0x00112233);
+    }
+}
@@ -2,6 +2,8 @@ use std::ops::DerefMut;
 
 use log::debug;
 
+use crate::error::Result;
+use crate::hal::Backend;
 use crate::vc_mem::VcMem;
 
 pub struct DmaControlBlock {
@@ -9,13 +11,13 @@ pub struct DmaControlBlock {
 }
 
 impl DmaControlBlock {
-    pub fn new() -> Self {
-        let mut memory = VcMem::new(0x20, 0x20);
+    pub fn new(backend: &impl Backend) -> Result<Self> {
+        let mut memory = backend.alloc_vc_mem(0x20, 0x20)?;
         memory.fill(0);
 
-        DmaControlBlock {
+        Ok(DmaControlBlock {
             memory,
-        }
+        })
     }
 
     pub fn busaddr(&self) -> usize {
@@ -0,0 +1,274 @@
+use crate::dma::DMA_CB_DEST_INC;
+use crate::error::{Error, Result};
+use crate::hal::Backend;
+use crate::vc_mem::VcMem;
+
+// size and alignment of a control block
+const CB_SIZE: usize = 0x20;
+
+// word offsets within a control block
+const CB_TI: usize        = 0;
+const CB_SRCE_AD: usize   = 1;
+const CB_DEST_AD: usize   = 2;
+const CB_TXFR_LEN: usize  = 3;
+const CB_STRIDE: usize    = 4;
+const CB_NEXTCONBK: usize = 5;
+
+/// Contents of a single control block, the link to the next block is
+/// managed by the `DmaChain` it's in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ControlBlock {
+    pub transfer_info: u32,
+    pub source: u32,
+    pub destination: u32,
+    pub length: u32,
+    pub stride: u32,
+}
+
+impl ControlBlock {
+    pub fn new(transfer_info: u32, source: u32, destination: u32, length: u32) -> Self {
+        ControlBlock {
+            transfer_info,
+            source,
+            destination,
+            length,
+            stride: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DmaChainBuilder {
+    blocks: Vec<ControlBlock>,
+    looped: bool,
+    // destination and length of a gathered block that ran off the end of
+    // the bus address space
+    overflow: Option<(u32, u32)>,
+}
+
+impl DmaChainBuilder {
+    pub fn block(mut self, block: ControlBlock) -> Self {
+        self.blocks.push(block);
+        self
+    }
+
+    /// Adds one block per `(bus address, length)` source, copying them one
+    /// after the other to `destination`. The destination only moves along
+    /// between blocks if `transfer_info` has `DMA_CB_DEST_INC` set, a
+    /// peripheral FIFO gets everything written to the same address.
+    pub fn gather(mut self, transfer_info: u32, sources: &[(u32, u32)], destination: u32) -> Self {
+        let mut destination = destination;
+        for &(source, length) in sources {
+            self.blocks.push(ControlBlock::new(transfer_info, source, destination, length));
+            if transfer_info & DMA_CB_DEST_INC as u32 != 0 {
+                match destination.checked_add(length) {
+                    Some(next) => destination = next,
+                    None => {
+                        self.overflow.get_or_insert((destination, length));
+                    }
+                }
+            }
+        }
+        self
+    }
+
+    /// Links the last block back to the first so the chain runs until it's
+    /// stopped with `DmaChain::set_looped(false)`.
+    pub fn looped(mut self, looped: bool) -> Self {
+        self.looped = looped;
+        self
+    }
+
+    pub fn build(self, backend: &impl Backend) -> Result<DmaChain> {
+        if self.blocks.is_empty() {
+            return Err(Error::EmptyDmaChain);
+        }
+        if let Some((destination, length)) = self.overflow {
+            return Err(Error::DmaAddressOverflow { destination, length });
+        }
+
+        let mut memory = backend.alloc_vc_mem((self.blocks.len() * CB_SIZE) as u32, CB_SIZE as u32)?;
+        memory.fill(0);
+
+        let mut chain = DmaChain {
+            memory,
+            nblocks: self.blocks.len(),
+            looped: self.looped,
+        };
+        for (n, block) in self.blocks.iter().enumerate() {
+            chain.set_block(n, block);
+            chain.link(n);
+        }
+
+        Ok(chain)
+    }
+}
+
+/// A list of control blocks in VC memory, each linked to the next.
+pub struct DmaChain {
+    memory: VcMem,
+    nblocks: usize,
+    looped: bool,
+}
+
+impl DmaChain {
+    pub fn builder() -> DmaChainBuilder {
+        DmaChainBuilder::default()
+    }
+
+    /// Bus address of the first control block, this is what the DMA channel
+    /// is started at.
+    pub fn busaddr(&self) -> usize {
+        self.memory.busaddr()
+    }
+
+    /// Number of control blocks.
+    pub fn len(&self) -> usize {
+        self.nblocks
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nblocks == 0
+    }
+
+    /// Number of bytes moved by one pass through the chain.
+    pub fn transfer_length(&self) -> usize {
+        (0..self.nblocks).map(|n| self.block(n).length as usize).sum()
+    }
+
+    pub fn block(&self, n: usize) -> ControlBlock {
+        ControlBlock {
+            transfer_info: self.read(n, CB_TI),
+            source: self.read(n, CB_SRCE_AD),
+            destination: self.read(n, CB_DEST_AD),
+            length: self.read(n, CB_TXFR_LEN),
+            stride: self.read(n, CB_STRIDE),
+        }
+    }
+
+    /// Replaces the contents of block `n`, its link to the next block is
+    /// left as is.
+    pub fn set_block(&mut self, n: usize, block: &ControlBlock) {
+        self.write(n, CB_TI, block.transfer_info);
+        self.write(n, CB_SRCE_AD, block.source);
+        self.write(n, CB_DEST_AD, block.destination);
+        self.write(n, CB_TXFR_LEN, block.length);
+        self.write(n, CB_STRIDE, block.stride);
+    }
+
+    pub fn is_looped(&self) -> bool {
+        self.looped
+    }
+
+    /// Links the last block to the first (or to nothing), a running looped
+    /// chain stops at the end of the pass it's in.
+    pub fn set_looped(&mut self, looped: bool) {
+        self.looped = looped;
+        self.link(self.nblocks - 1);
+    }
+
+    fn link(&mut self, n: usize) {
+        let next = if n + 1 < self.nblocks {
+            self.block_busaddr(n + 1) as u32
+        } else if self.looped {
+            self.block_busaddr(0) as u32
+        } else {
+            0
+        };
+        self.write(n, CB_NEXTCONBK, next);
+    }
+
+    fn block_busaddr(&self, n: usize) -> usize {
+        self.busaddr() + n * CB_SIZE
+    }
+
+    fn read(&self, n: usize, word: usize) -> u32 {
+        assert!(n < self.nblocks);
+        unsafe {
+            self.memory.as_ptr()
+                .byte_add(n * CB_SIZE + word * 4)
+                .cast::<u32>()
+                .read_volatile()
+        }
+    }
+
+    fn write(&mut self, n: usize, word: usize, value: u32) {
+        assert!(n < self.nblocks);
+        unsafe {
+            self.memory.as_mut_ptr()
+                .byte_add(n * CB_SIZE + word * 4)
+                .cast::<u32>()
+                .write_volatile(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dma::{Dma, DMA_CB_SRCE_INC};
+    use crate::hal::Simulated;
+
+    #[test]
+    fn gathers_buffers_into_one() {
+        let backend = Simulated::new();
+        let parts = (0..3u8)
+            .map(|n| {
+                let mut part = backend.alloc_vc_mem(0x10 * (n as u32 + 1), 4).unwrap();
+                part.fill(n + 1);
+                part
+            })
+            .collect::<Vec<_>>();
+        let mut dest = backend.alloc_vc_mem(0x100, 4).unwrap();
+        dest.fill(0);
+
+        let ti = (DMA_CB_SRCE_INC | DMA_CB_DEST_INC) as u32;
+        let sources = parts
+            .iter()
+            .map(|part| (part.busaddr() as u32, part.len() as u32))
+            .collect::<Vec<_>>();
+        let chain = DmaChain::builder()
+            .gather(ti, &sources, dest.busaddr() as u32)
+            .build(&backend)
+            .unwrap();
+        assert_eq!(chain.len(), 3);
+        assert_eq!(chain.transfer_length(), 0x60);
+        assert_eq!(chain.block(2).destination, dest.busaddr() as u32 + 0x30);
+
+        let mut dma = Dma::new(&backend, 5).unwrap();
+        dma.enable();
+        dma.start_chain(&chain);
+        dma.wait().unwrap();
+
+        assert!(dest[..0x10].iter().all(|&b| b == 1));
+        assert!(dest[0x10..0x30].iter().all(|&b| b == 2));
+        assert!(dest[0x30..0x60].iter().all(|&b| b == 3));
+        assert!(dest[0x60..].iter().all(|&b| b == 0));
+
+        let overflow = DmaChain::builder().gather(ti, &[(0, 0x20), (0x20, 0x20)], u32::MAX - 0x10).build(&backend);
+        assert!(matches!(overflow, Err(Error::DmaAddressOverflow { length: 0x20, .. })));
+    }
+
+    #[test]
+    fn links_blocks_and_loops() {
+        let backend = Simulated::new();
+        let mut chain = DmaChain::builder()
+            .block(ControlBlock::new(0, 0x1000, 0x2000, 4))
+            .block(ControlBlock::new(0, 0x3000, 0x4000, 4))
+            .looped(true)
+            .build(&backend)
+            .unwrap();
+
+        let next = |chain: &DmaChain, n: usize| chain.read(n, CB_NEXTCONBK) as usize;
+        assert_eq!(next(&chain, 0), chain.busaddr() + CB_SIZE);
+        assert_eq!(next(&chain, 1), chain.busaddr());
+
+        chain.set_looped(false);
+        assert_eq!(next(&chain, 1), 0);
+
+        chain.set_block(0, &ControlBlock::new(1, 2, 3, 8));
+        assert!(matches!(DmaChain::builder().build(&backend), Err(Error::EmptyDmaChain)));
+        assert_eq!(chain.block(0), ControlBlock::new(1, 2, 3, 8));
+        assert_eq!(next(&chain, 0), chain.busaddr() + CB_SIZE);
+    }
+}
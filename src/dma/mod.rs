@@ -1,25 +1,56 @@
 mod cb;
+mod chain;
 
-use std::fs::OpenOptions;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub use cb::DmaControlBlock;
-use log::debug;
-use memmap2::{MmapMut, MmapOptions};
+pub use chain::{ControlBlock, DmaChain, DmaChainBuilder};
 use once_cell::sync::OnceCell;
+use thiserror::Error;
 
-use crate::{r, rwm, w, DMA_BASE_ADDRESS};
+use crate::error::{self, Error};
+use crate::hal::{Backend, RegisterBackend};
+use crate::DMA_OFFSET;
 
-const DMA_CS: usize        = 0x00;
-const DMA_CONBLK_AD: usize = 0x04;
+pub(crate) const DMA_CS: usize        = 0x00;
+pub(crate) const DMA_CONBLK_AD: usize = 0x04;
+// the channel loads these from the control block, they're never written
+// directly but are handy when debugging a transfer
+#[allow(dead_code)]
 const DMA_TI: usize        = 0x08;
+#[allow(dead_code)]
 const DMA_SRCE_AD: usize   = 0x0c;
+#[allow(dead_code)]
 const DMA_DEST_AD: usize   = 0x10;
+#[allow(dead_code)]
 const DMA_TXFR_LEN: usize  = 0x14;
+#[allow(dead_code)]
 const DMA_STRIDE: usize    = 0x18;
+#[allow(dead_code)]
 const DMA_NEXTCONBK: usize = 0x1c;
-const DMA_DEBUG: usize     = 0x20;
+pub(crate) const DMA_DEBUG: usize     = 0x20;
 const DMA_ENABLE: usize = 0xff0;
 
+// DMA_CS bits
+pub(crate) const DMA_CS_ACTIVE: u32 = 1 << 0;
+pub(crate) const DMA_CS_END: u32    = 1 << 1;
+pub(crate) const DMA_CS_ERROR: u32  = 1 << 8;
+pub(crate) const DMA_CS_RESET: u32  = 1 << 31;
+
+// DMA_DEBUG error bits
+pub const DMA_DEBUG_READ_LAST_NOT_SET: u32 = 1 << 0;
+pub const DMA_DEBUG_FIFO_ERROR: u32        = 1 << 1;
+pub const DMA_DEBUG_READ_ERROR: u32        = 1 << 2;
+const DMA_DEBUG_ERRORS: u32 = 0x7;
+
+// how long waits sleep between looking at the channel, a frame takes a few ms
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_micros(50);
+
 // DMA register values
 pub const DMA_WAIT_RESP: usize   = 1 << 3;
 pub const DMA_CB_DEST_INC: usize = 1 << 4;
@@ -28,112 +59,193 @@ pub const DMA_CB_SRCE_INC: usize = 1 << 8;
 pub const DMA_SRCE_DREQ: usize   = 1 << 10;
 pub const fn dma_priority(n: usize) -> usize { n << 16 }
 
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum DmaError {
+    #[error("DMA transfer didn't finish within {0:?}")]
+    Timeout(Duration),
+    /// The channel flagged an error, `debug` holds its `DMA_DEBUG_*` bits.
+    #[error("DMA channel {channel} stopped with an error (debug {debug:#x})")]
+    Transfer { channel: u8, debug: u32 },
+}
 
-pub struct Dma {
+pub struct Dma<B: Backend> {
     channel: u8,
-    channel_base: *mut u32,
-    dma_map: MmapMut,
+    dma_regs: B::Registers,
     control_block: DmaControlBlock,
     reset: OnceCell<()>,
 }
 
-impl Dma {
-    pub fn new(channel: u8) -> Self {
-        let devmem = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open("/dev/mem")
-            .expect("Failed to open /dev/mem");
-
-        assert!(channel < 15);
-
-        let mut dma_map = unsafe {
-            MmapOptions::new()
-                .offset(DMA_BASE_ADDRESS as u64)
-                .len(0xa000)
-                .map_mut(&devmem)
-                .expect("Failed to map DMA memory")
-        };
-
-        let control_block = DmaControlBlock::new();
+impl<B: Backend> Dma<B> {
+    pub fn new(backend: &B, channel: u8) -> error::Result<Self> {
+        if !backend.platform().soc.dma_channels().contains(&channel) {
+            return Err(Error::InvalidDmaChannel(channel));
+        }
 
-        let channel_base = unsafe {
-            dma_map
-                .as_mut_ptr()
-                .byte_add(channel as usize * 0x100)
-                .cast::<u32>()
-        };
+        let dma_regs = backend.map_registers(DMA_OFFSET, 0xa000)?;
+        let control_block = DmaControlBlock::new(backend)?;
 
-        Dma {
+        Ok(Dma {
             channel,
-            channel_base,
-            dma_map,
+            dma_regs,
             control_block,
             reset: OnceCell::new(),
-        }
+        })
     }
 
     pub fn get_cb(&mut self) -> &mut DmaControlBlock {
         &mut self.control_block
     }
 
-    pub fn enable(&mut self) {
-        let reg = unsafe {
-            self.dma_map
-                .as_mut_ptr()
-                .byte_add(DMA_ENABLE)
-                .cast::<u32>()
-        };
+    // offset of one of this channel's registers within the DMA block
+    fn channel_reg(&self, reg: usize) -> usize {
+        self.channel as usize * 0x100 + reg
+    }
 
-        rwm(reg, |value| *value |= 1 << self.channel);
+    pub fn enable(&mut self) {
+        self.dma_regs.modify(DMA_ENABLE, |value| *value |= 1 << self.channel);
 
         // reset the dma peripheral once
         self.reset.get_or_init(|| {
-            let reg = unsafe {
-                self.channel_base.byte_add(DMA_CS)
-            };
-
-            rwm(reg, |value| *value |= 1 << 31);
+            self.dma_regs.modify(self.channel_reg(DMA_CS), |value| *value |= DMA_CS_RESET);
         });
     }
 
     pub fn disable(&mut self) {
-        let reg = unsafe {
-            self.dma_map
-                .as_mut_ptr()
-                .byte_add(DMA_ENABLE)
-                .cast::<u32>()
-        };
-
-        rwm(reg, |value| *value &= !(1 << self.channel));
+        self.dma_regs.modify(DMA_ENABLE, |value| *value &= !(1 << self.channel));
     }
 
     pub fn reset(&mut self) {
-        let reg = unsafe {
-            self.channel_base.byte_add(DMA_CS)
-        };
-
-        rwm(reg, |value| *value |= 1 << 31);
+        self.dma_regs.modify(self.channel_reg(DMA_CS), |value| *value |= DMA_CS_RESET);
     }
 
+    /// Starts the transfer described by this channel's own control block.
     pub fn start(&mut self) {
-        unsafe {
-            let cbad = self.channel_base.byte_add(DMA_CONBLK_AD);
-            let addr = self.control_block.busaddr();
-            w(cbad, addr as u32);
+        self.start_at(self.control_block.busaddr());
+    }
 
-            let cs = self.channel_base.byte_add(DMA_CS);
-            w(cs, 2); // clear end flag
+    /// Starts the channel at the first block of `chain`, the chain has to
+    /// stay alive until the transfer is done.
+    pub fn start_chain(&mut self, chain: &DmaChain) {
+        self.start_at(chain.busaddr());
+    }
 
-            let debug = self.channel_base.byte_add(DMA_DEBUG);
-            w(debug, 7); // clear error bits
+    fn start_at(&mut self, addr: usize) {
+        self.dma_regs.write(self.channel_reg(DMA_CONBLK_AD), addr as u32);
+        self.dma_regs.write(self.channel_reg(DMA_CS), DMA_CS_END); // clear end flag
+        self.dma_regs.write(self.channel_reg(DMA_DEBUG), 7); // clear error bits
+        self.dma_regs.write(self.channel_reg(DMA_CS), DMA_CS_ACTIVE); // start transfer
+    }
 
-            w(cs, 1); // start transfer
-        };
+    /// True while the channel is still working through its control blocks.
+    pub fn is_active(&self) -> bool {
+        self.dma_regs.read(self.channel_reg(DMA_CS)) & DMA_CS_ACTIVE != 0
+    }
+
+    /// Checks on the transfer without blocking, errors are reported as soon
+    /// as the channel flags them.
+    pub fn poll(&self) -> Poll<Result<(), DmaError>> {
+        let cs = self.dma_regs.read(self.channel_reg(DMA_CS));
+        let debug = self.dma_regs.read(self.channel_reg(DMA_DEBUG)) & DMA_DEBUG_ERRORS;
+        if cs & DMA_CS_ERROR != 0 || debug != 0 {
+            return Poll::Ready(Err(DmaError::Transfer { channel: self.channel, debug }));
+        }
+
+        if cs & DMA_CS_ACTIVE != 0 {
+            Poll::Pending
+        } else {
+            Poll::Ready(Ok(()))
+        }
     }
 
-    pub fn wait(&self) {
-        let addr = unsafe { self.channel_base.byte_add(DMA_CS) };
-        while r(addr) & 1 != 0 {}
+    /// Blocks until the transfer is done, sleeping between checks rather
+    /// than spinning.
+    pub fn wait(&self) -> Result<(), DmaError> {
+        self.wait_until(None, Duration::MAX)
     }
-}
\ No newline at end of file
+
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), DmaError> {
+        self.wait_until(Instant::now().checked_add(timeout), timeout)
+    }
+
+    pub fn wait_deadline(&self, deadline: Instant) -> Result<(), DmaError> {
+        self.wait_until(Some(deadline), deadline.saturating_duration_since(Instant::now()))
+    }
+
+    // `timeout` is only what gets reported if the deadline passes
+    fn wait_until(&self, deadline: Option<Instant>, timeout: Duration) -> Result<(), DmaError> {
+        loop {
+            if let Poll::Ready(result) = self.poll() {
+                return result;
+            }
+
+            let now = Instant::now();
+            match deadline {
+                Some(deadline) if now >= deadline => return Err(DmaError::Timeout(timeout)),
+                Some(deadline) => thread::sleep(POLL_INTERVAL.min(deadline - now)),
+                None => thread::sleep(POLL_INTERVAL),
+            }
+        }
+    }
+
+    /// Future that resolves once the transfer is done.
+    ///
+    /// There's no DMA interrupt to wake it, so while the transfer is running
+    /// it's woken to check again every `POLL_INTERVAL`, see `wake_after`.
+    pub fn completion(&self) -> Completion<'_, B> {
+        Completion { dma: self }
+    }
+}
+
+pub struct Completion<'a, B: Backend> {
+    dma: &'a Dma<B>,
+}
+
+impl<B: Backend> Future for Completion<'_, B> {
+    type Output = Result<(), DmaError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let poll = self.dma.poll();
+        if poll.is_pending() {
+            wake_after(cx.waker(), POLL_INTERVAL);
+        }
+        poll
+    }
+}
+
+// wakers and when to wake them, handed to the timer thread
+static TIMER: OnceCell<Sender<(Instant, Waker)>> = OnceCell::new();
+
+/// Wakes `waker` once `delay` has passed, from a timer thread shared by every
+/// future waiting on a transfer.
+pub(crate) fn wake_after(waker: &Waker, delay: Duration) {
+    let timer = TIMER.get_or_init(|| {
+        let (timer, wakeups) = mpsc::channel();
+        thread::spawn(move || run_timer(wakeups));
+        timer
+    });
+    // the thread never exits, so this can't fail
+    let _ = timer.send((Instant::now() + delay, waker.clone()));
+}
+
+fn run_timer(wakeups: Receiver<(Instant, Waker)>) {
+    let mut pending: Vec<(Instant, Waker)> = Vec::new();
+    loop {
+        let received = match pending.iter().map(|(at, _)| *at).min() {
+            Some(at) => wakeups.recv_timeout(at.saturating_duration_since(Instant::now())),
+            None => wakeups.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(wakeup) => pending.push(wakeup),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let now = Instant::now();
+        pending.retain(|(at, waker)| {
+            if *at <= now {
+                waker.wake_by_ref();
+            }
+            *at > now
+        });
+    }
+}
@@ -0,0 +1,416 @@
+use std::future;
+use std::task::Poll;
+use std::time::Duration;
+
+use crate::color::ColorCorrection;
+use crate::config::LedConfig;
+use crate::cube::CubeMap;
+use crate::dma;
+use crate::dither::{self, Dither};
+use crate::encoder::Encoder;
+use crate::error::Result;
+use crate::gpio::{Gpio, GpioMode};
+use crate::power::PowerEstimate;
+use crate::hal::{Backend, DevMem};
+use crate::mapping::PixelMap;
+use crate::smi::Smi;
+use crate::vc_mem::VcMem;
+use crate::{DMA_CHAN, LED_D0_PIN};
+
+/// High level driver for up to 16 parallel LED strips.
+///
+/// Pixels are set per channel (strip) as `0xWWRRGGBB` and are only sent out
+/// to the strips by `present` or `show`, the white byte is only used by
+/// channels with a 4 component pixel format.
+///
+/// With `LedConfigBuilder::dithering` frames are taken from a second set of
+/// pixels with 16 bits per component instead (`0xWWWWRRRRGGGGBBBB`, see
+/// `set_pixel16`), which are dithered down to 8 bits over successive frames.
+/// `set_pixel` and `fill` write to both sets.
+///
+/// Frames are encoded into a rotating set of tx buffers (see
+/// `LedConfigBuilder::tx_buffers`), so the next frame can be encoded while
+/// the previous one is still being clocked out. A buffer is never written to
+/// while the DMA is reading it and the DMA is only pointed at a buffer once
+/// the whole frame has been encoded into it.
+pub struct LedDriver<B: Backend> {
+    smi: Smi<B>,
+    // only held so the data pins go back to inputs when the driver is dropped
+    _gpio: Gpio<B>,
+    encoder: Encoder,
+    tx_buffs: Vec<VcMem>,
+    // buffer the next frame is encoded into
+    back: usize,
+    // buffer the DMA is (or may still be) reading from
+    in_flight: Option<usize>,
+    pixels: Vec<Vec<u32>>,
+    // only allocated with dithering on
+    pixels16: Vec<Vec<u64>>,
+    dither: Dither,
+    // estimated draw of the last frame presented
+    power: PowerEstimate,
+    backend: B,
+    config: LedConfig,
+}
+
+impl LedDriver<DevMem> {
+    /// Sets up the strips described by `config` on the real hardware.
+    pub fn new(config: LedConfig) -> Result<Self> {
+        Self::with_backend(DevMem::new()?, config)
+    }
+}
+
+impl<B: Backend> LedDriver<B> {
+    pub fn with_backend(backend: B, config: LedConfig) -> Result<Self> {
+        let encoder = Encoder::new(&config);
+
+        let mut gpio = Gpio::new(&backend)?;
+        for chan in 0..config.channels() {
+            gpio.configure_pin(LED_D0_PIN + chan, GpioMode::Alt1)?;
+        }
+
+        let mut smi = Smi::new(&backend, config.channels(), &config.smi_timing(), DMA_CHAN as u8)?;
+
+        let tx_buffs = (0..config.tx_buffers())
+            .map(|_| backend.alloc_vc_mem(config.buffer_size() as u32, 0x1000))
+            .collect::<Result<Vec<_>>>()?;
+        smi.setup_transfer(&tx_buffs[0], 0..config.tx_buff_size())?;
+
+        Ok(LedDriver {
+            smi,
+            _gpio: gpio,
+            encoder,
+            tx_buffs,
+            back: 0,
+            in_flight: None,
+            pixels: vec![vec![0; config.leds_per_channel()]; config.channels()],
+            pixels16: match config.dithering() {
+                true => vec![vec![0; config.leds_per_channel()]; config.channels()],
+                false => Vec::new(),
+            },
+            dither: Dither::new(config.channels(), config.leds_per_channel()),
+            power: PowerEstimate::default(),
+            backend,
+            config,
+        })
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn config(&self) -> &LedConfig {
+        &self.config
+    }
+
+    /// Number of channels (strips).
+    pub fn nchans(&self) -> usize {
+        self.config.channels()
+    }
+
+    /// Number of LEDs on each channel.
+    pub fn nleds(&self) -> usize {
+        self.config.leds_per_channel()
+    }
+
+    pub fn pixel(&self, channel: usize, index: usize) -> u32 {
+        self.pixels[channel][index]
+    }
+
+    pub fn set_pixel(&mut self, channel: usize, index: usize, color: u32) {
+        self.pixels[channel][index] = color;
+        if let Some(leds) = self.pixels16.get_mut(channel) {
+            leds[index] = dither::widen(color);
+        }
+    }
+
+    /// Sets the LED at voxel (x, y, z) of the cube described by `map`,
+    /// voxels outside the cube or wired past the driver's channels are
+    /// ignored.
+    pub fn set_voxel(&mut self, map: &CubeMap, x: usize, y: usize, z: usize, color: u32) {
+        if let Some((channel, index)) = map.position(x, y, z) {
+            if channel < self.pixels.len() && index < self.nleds() {
+                self.set_pixel(channel, index, color);
+            }
+        }
+    }
+
+    /// Sets the LEDs of a frame in the logical space of `map`, LEDs past the
+    /// end of their channel are ignored.
+    pub fn set_frame(&mut self, map: &PixelMap, frame: &[u32]) {
+        let nleds = self.nleds();
+        for (led, &color) in map.leds().iter().zip(frame) {
+            if led.channel < self.pixels.len() && led.index < nleds {
+                self.set_pixel(led.channel, led.index, color);
+            }
+        }
+    }
+
+    /// Sets a pixel with 16 bits per component, only with dithering on.
+    pub fn set_pixel16(&mut self, channel: usize, index: usize, color: u64) {
+        assert!(self.config.dithering(), "16-bit pixels need dithering");
+        self.pixels16[channel][index] = color;
+        self.pixels[channel][index] = dither::narrow(color);
+    }
+
+    /// The 16-bit pixels of a channel, empty unless dithering is on.
+    pub fn channel16(&self, channel: usize) -> &[u64] {
+        self.pixels16.get(channel).map_or(&[], |leds| leds.as_slice())
+    }
+
+    /// Mutable 16-bit pixels of a channel, these are what a dithered frame is
+    /// made from but unlike `set_pixel16` the 8-bit pixels aren't updated.
+    pub fn channel16_mut(&mut self, channel: usize) -> &mut [u64] {
+        assert!(self.config.dithering(), "16-bit pixels need dithering");
+        &mut self.pixels16[channel]
+    }
+
+    pub fn channel(&self, channel: usize) -> &[u32] {
+        &self.pixels[channel]
+    }
+
+    /// With dithering on the frames are made from the 16-bit pixels, which
+    /// aren't updated through this.
+    pub fn channel_mut(&mut self, channel: usize) -> &mut [u32] {
+        &mut self.pixels[channel]
+    }
+
+    /// Changes the colour correction of a channel from the next frame on.
+    pub fn set_color_correction(&mut self, channel: usize, correction: ColorCorrection) -> Result<()> {
+        self.config.set_color_correction(channel, correction)?;
+        self.encoder.set_color_correction(channel, &correction);
+        Ok(())
+    }
+
+    /// Estimated current draw of the last frame sent, before and after the
+    /// power budgets (`LedConfigBuilder::power_budget`) were applied.
+    pub fn power(&self) -> &PowerEstimate {
+        &self.power
+    }
+
+    /// Sets every LED on every channel to `color`.
+    pub fn fill(&mut self, color: u32) {
+        for leds in self.pixels.iter_mut() {
+            leds.fill(color);
+        }
+        for leds in self.pixels16.iter_mut() {
+            leds.fill(dither::widen(color));
+        }
+    }
+
+    /// Encodes the current pixels into the back buffer and starts sending it
+    /// as soon as the previous frame is done, without waiting for this frame
+    /// to finish.
+    pub fn present(&mut self) -> Result<()> {
+        let back = self.back;
+        if self.in_flight == Some(back) {
+            self.wait()?;
+        }
+
+        if self.config.dithering() {
+            let channels = self.pixels16.iter().map(|leds| leds.as_slice()).collect::<Vec<_>>();
+            self.power =
+                self.encoder.encode_dithered(&mut self.tx_buffs[back], &channels, &mut self.dither)?;
+        } else {
+            let channels = self.pixels.iter().map(|leds| leds.as_slice()).collect::<Vec<_>>();
+            self.power = self.encoder.encode(&mut self.tx_buffs[back], &channels)?;
+        }
+
+        // swap at the frame boundary
+        self.wait()?;
+        self.smi.set_source(&self.tx_buffs[back], 0)?;
+        self.smi.start_transfer()?;
+
+        self.in_flight = Some(back);
+        self.back = (back + 1) % self.tx_buffs.len();
+        Ok(())
+    }
+
+    /// Encodes the current pixels and sends them out to the strips, blocking
+    /// until the transfer has finished.
+    pub fn show(&mut self) -> Result<()> {
+        self.present()?;
+        self.wait()
+    }
+
+    /// True while a frame is still being sent.
+    pub fn is_busy(&self) -> bool {
+        self.in_flight.is_some() && self.smi.is_busy()
+    }
+
+    /// Blocks until the frame in flight (if any) has been sent.
+    pub fn wait(&mut self) -> Result<()> {
+        if self.in_flight.is_some() {
+            self.smi.wait_transfer()?;
+            self.in_flight = None;
+        }
+        Ok(())
+    }
+
+    /// Like `wait` but gives up with `DmaError::Timeout` after `timeout`, the
+    /// frame is still treated as in flight after a timeout.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<()> {
+        if self.in_flight.is_some() {
+            self.smi.wait_transfer_timeout(timeout)?;
+            self.in_flight = None;
+        }
+        Ok(())
+    }
+
+    /// Checks whether the frame in flight has been sent without blocking.
+    pub fn poll(&mut self) -> Poll<Result<()>> {
+        if self.in_flight.is_none() {
+            return Poll::Ready(Ok(()));
+        }
+
+        let poll = self.smi.poll_transfer();
+        if let Poll::Ready(Ok(())) = poll {
+            self.in_flight = None;
+        }
+        poll.map_err(Into::into)
+    }
+
+    /// Async version of `wait`. There's no interrupt to wake the future, so
+    /// it's woken from a timer thread every few tens of microseconds to check
+    /// on the frame, without keeping the executor busy in between.
+    pub async fn wait_async(&mut self) -> Result<()> {
+        future::poll_fn(|cx| {
+            let poll = self.poll();
+            if poll.is_pending() {
+                dma::wake_after(cx.waker(), dma::POLL_INTERVAL);
+            }
+            poll
+        })
+        .await
+    }
+}
+
+impl<B: Backend> Drop for LedDriver<B> {
+    // the DMA must be done with the tx buffers before they are freed, an
+    // error means it already stopped
+    fn drop(&mut self) {
+        let _ = self.wait();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::Future;
+    use std::pin::pin;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Wake, Waker};
+    use std::thread;
+
+    use super::*;
+    use crate::decoder::Decoder;
+    use crate::dma::{DmaError, DMA_CS, DMA_DEBUG, DMA_DEBUG_READ_ERROR};
+    use crate::error::Error;
+    use crate::hal::Simulated;
+    use crate::smi::SMI_D;
+    use crate::{DMA_OFFSET, SMI_OFFSET};
+
+    struct Woken(AtomicBool);
+
+    impl Wake for Woken {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn rotates_through_tx_buffers() {
+        let config = LedConfig::builder().leds_per_channel(4).tx_buffers(3).build().unwrap();
+        let mut leds = LedDriver::with_backend(Simulated::new(), config).unwrap();
+
+        let mut sent = Vec::new();
+        for frame in 0..4 {
+            leds.fill(0x010101 * frame);
+            leds.present().unwrap();
+            assert_eq!(leds.back, (frame as usize + 1) % 3);
+
+            let fifo = leds.backend().take_fifo(SMI_OFFSET + SMI_D);
+            assert_eq!(fifo.len(), leds.config().tx_buff_size());
+            sent.push(fifo);
+        }
+        leds.wait().unwrap();
+        assert!(!leds.is_busy());
+
+        // every frame went out whole, from its own buffer
+        assert!(sent.windows(2).all(|frames| frames[0] != frames[1]));
+        for (frame, fifo) in sent.iter().enumerate().skip(1) {
+            let buff = &leds.tx_buffs[frame % 3];
+            assert_eq!(fifo[..], buff[..fifo.len()], "frame {}", frame);
+        }
+    }
+
+    #[test]
+    fn reports_stalls_and_errors() {
+        let backend = Simulated::new();
+        let config = LedConfig::builder().leds_per_channel(4).build().unwrap();
+        let mut leds = LedDriver::with_backend(backend.clone(), config).unwrap();
+        let cs = DMA_OFFSET + DMA_CHAN * 0x100 + DMA_CS;
+
+        backend.set_dma_stalled(true);
+        leds.present().unwrap();
+        assert!(leds.is_busy());
+        assert!(leds.poll().is_pending());
+        assert!(matches!(
+            leds.wait_timeout(Duration::from_millis(1)),
+            Err(Error::Dma(DmaError::Timeout(timeout))) if timeout == Duration::from_millis(1)
+        ));
+
+        // the future only resolves once the frame is out, and is woken to
+        // check again in the meantime
+        {
+            let woken = Arc::new(Woken(AtomicBool::new(false)));
+            let waker = Waker::from(woken.clone());
+            let mut cx = Context::from_waker(&waker);
+            let mut wait = pin!(leds.wait_async());
+            assert!(wait.as_mut().poll(&mut cx).is_pending());
+            thread::sleep(Duration::from_millis(20));
+            assert!(woken.0.load(Ordering::SeqCst));
+            backend.set_dma_stalled(false);
+            assert!(matches!(wait.as_mut().poll(&mut cx), Poll::Ready(Ok(()))));
+        }
+        assert!(!leds.is_busy());
+
+        backend.set_dma_stalled(true);
+        leds.present().unwrap();
+        backend.poke(cs + DMA_DEBUG, DMA_DEBUG_READ_ERROR);
+        assert!(matches!(
+            leds.wait(),
+            Err(Error::Dma(DmaError::Transfer { channel, debug: DMA_DEBUG_READ_ERROR }))
+                if channel == DMA_CHAN as u8
+        ));
+
+        // a frame can't be started on top of the one in flight
+        assert!(matches!(leds.smi.start_transfer(), Err(Error::TransferInProgress)));
+    }
+
+    #[test]
+    fn dithers_16_bit_pixels() {
+        let config = LedConfig::builder().leds_per_channel(2).dithering(true).build().unwrap();
+        let decoder = Decoder::new(&config);
+        let mut leds = LedDriver::with_backend(Simulated::new(), config).unwrap();
+
+        leds.set_pixel(0, 0, 0x00FF00);
+        // half way between 1 and 2 on red
+        leds.set_pixel16(1, 1, 0x0000_0180_0000_0000);
+        assert_eq!(leds.pixel(1, 1), 0x010000);
+
+        let mut reds = Vec::new();
+        for _ in 0..4 {
+            leds.show().unwrap();
+            let frame = decoder.decode(&leds.backend().take_fifo(SMI_OFFSET + SMI_D)).unwrap();
+            assert_eq!(frame[0][0], 0x00FF00);
+            reds.push(frame[1][1] >> 16);
+        }
+        assert!(reds.contains(&1) && reds.contains(&2));
+
+        // 16 idle LEDs, full green and a trace of red
+        let power = leds.power();
+        assert!(power.total_ma() > 36.0 && power.total_ma() < 36.2);
+        assert!(!power.is_limited());
+    }
+}
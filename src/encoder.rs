@@ -0,0 +1,342 @@
+use crate::color::{ColorCorrection, ColorLut};
+use crate::config::{ConfigError, LedConfig};
+use crate::dither::Dither;
+use crate::error::Result;
+use crate::mapping::PixelMap;
+use crate::pixel::{PixelFormat, WhiteMode};
+use crate::power::{PowerEstimate, PowerLimiter};
+use crate::BIT_NPULSES;
+
+/// How a single LED bit is laid out in SMI samples.
+///
+/// A bit cell is `npulses` samples long, the line is held high for the first
+/// `t0_pulses` samples of a 0 bit and the first `t1_pulses` samples of a 1
+/// bit and low for the rest of the cell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BitTiming {
+    pub npulses: usize,
+    pub t0_pulses: usize,
+    pub t1_pulses: usize,
+}
+
+impl Default for BitTiming {
+    fn default() -> Self {
+        BitTiming {
+            npulses: BIT_NPULSES,
+            t0_pulses: 1,
+            t1_pulses: 2,
+        }
+    }
+}
+
+/// Transposes per-channel pixel data into the interleaved pulse buffer that
+/// gets clocked out over the SMI data lines.
+///
+/// Every SMI sample drives all data lines at once, bit `n` of a sample is the
+/// level on D`n`. Each LED bit is made up of `timing.npulses` samples, see
+/// `BitTiming`.
+///
+/// Every channel has its own `PixelFormat`, so channels with fewer bits per
+/// LED finish early and hold their line low for the rest of the frame. Pixels
+/// go through the channel's `ColorLut` and the `PowerLimiter` before they are
+/// converted to the pixel format.
+pub struct Encoder {
+    nchans: usize,
+    timing: BitTiming,
+    prebits: usize,
+    postbits: usize,
+    formats: Vec<PixelFormat>,
+    white_mode: WhiteMode,
+    luts: Vec<ColorLut>,
+    power: PowerLimiter,
+    nbits: usize,
+    // per frame scratch space, kept to save allocating it every frame
+    corrected: Vec<Vec<u32>>,
+    corrected16: Vec<Vec<u64>>,
+    wire: Vec<Vec<u32>>,
+}
+
+impl Encoder {
+    pub fn new(config: &LedConfig) -> Self {
+        let formats = (0..config.channels())
+            .map(|chan| config.pixel_format(chan))
+            .collect();
+        let luts = (0..config.channels())
+            .map(|chan| ColorLut::new(&config.color_correction(chan)))
+            .collect();
+
+        Encoder {
+            nchans: config.channels(),
+            timing: config.bit_timing(),
+            prebits: config.prebits(),
+            postbits: config.postbits(),
+            formats,
+            white_mode: config.white_mode(),
+            luts,
+            power: config.power_limiter(),
+            nbits: config.bits_per_pixel(),
+            corrected: Vec::new(),
+            corrected16: Vec::new(),
+            wire: Vec::new(),
+        }
+    }
+
+    pub fn set_color_correction(&mut self, channel: usize, correction: &ColorCorrection) {
+        self.luts[channel] = ColorLut::new(correction);
+    }
+
+    /// Number of channels (and SMI data lines) driven by this encoder.
+    pub fn nchans(&self) -> usize {
+        self.nchans
+    }
+
+    /// Size in bytes of a single SMI sample.
+    pub fn sample_size(&self) -> usize {
+        self.nchans / 8
+    }
+
+    /// Offset in samples of the first bit of LED `n` on the channels using
+    /// the widest pixel format.
+    pub fn led_tx_offset(&self, n: usize) -> usize {
+        self.prebits + (self.nbits * self.timing.npulses * n)
+    }
+
+    /// Length in samples of the buffer needed to send `nleds` LEDs per channel.
+    pub fn tx_buff_len(&self, nleds: usize) -> usize {
+        self.led_tx_offset(nleds) + self.postbits
+    }
+
+    /// Size in bytes of the buffer needed to send `nleds` LEDs per channel.
+    pub fn tx_buff_size(&self, nleds: usize) -> usize {
+        self.tx_buff_len(nleds) * self.sample_size()
+    }
+
+    /// Encodes one pixel slice per channel into `buf`.
+    ///
+    /// `channels[n]` is sent out on D`n`, pixels are `0xWWRRGGBB` and are
+    /// shifted out MSB first in the order of the channel's pixel format.
+    /// Channels that are shorter than the longest one (or missing entirely)
+    /// are padded with black, more channels than data lines is an error.
+    ///
+    /// Returns the estimated draw of the frame.
+    pub fn encode(&mut self, buf: &mut [u8], channels: &[&[u32]]) -> Result<PowerEstimate> {
+        self.check_channels(channels.len())?;
+        self.corrected.resize_with(channels.len(), Vec::new);
+        for ((corrected, leds), lut) in self.corrected.iter_mut().zip(channels).zip(&self.luts) {
+            corrected.clear();
+            corrected.extend(leds.iter().map(|&color| lut.apply(color)));
+        }
+        Ok(self.encode_corrected(buf))
+    }
+
+    /// Like `encode` for a frame in the logical space of `map`, pixels are
+    /// sent to the LEDs `map` puts them at and unmapped LEDs are black.
+    pub fn encode_mapped(&mut self, buf: &mut [u8], map: &PixelMap, frame: &[u32]) -> Result<PowerEstimate> {
+        self.check_channels(map.channels())?;
+        self.corrected.resize_with(map.channels(), Vec::new);
+        for leds in self.corrected.iter_mut() {
+            leds.clear();
+            leds.resize(map.leds_per_channel(), 0);
+        }
+        map.scatter(frame, &mut self.corrected);
+        for (leds, lut) in self.corrected.iter_mut().zip(&self.luts) {
+            for color in leds.iter_mut() {
+                *color = lut.apply(*color);
+            }
+        }
+        Ok(self.encode_corrected(buf))
+    }
+
+    // limits and encodes the colour corrected pixels in `corrected`
+    fn encode_corrected(&mut self, buf: &mut [u8]) -> PowerEstimate {
+        let power = self.power.limit(&mut self.corrected);
+
+        self.wire.resize_with(self.corrected.len(), Vec::new);
+        for ((wire, leds), format) in self.wire.iter_mut().zip(&self.corrected).zip(&self.formats) {
+            wire.clear();
+            wire.extend(leds.iter().map(|&color| format.to_wire(color, self.white_mode)));
+        }
+        self.encode_wire(buf, &self.wire);
+        power
+    }
+
+    /// Like `encode` for pixels with 16 bits per component
+    /// (`0xWWWWRRRRGGGGBBBB`), which are dithered down to 8 bits after the
+    /// colour correction, see `Dither`.
+    pub fn encode_dithered(
+        &mut self,
+        buf: &mut [u8],
+        channels: &[&[u64]],
+        dither: &mut Dither,
+    ) -> Result<PowerEstimate> {
+        self.check_channels(channels.len())?;
+        self.corrected16.resize_with(channels.len(), Vec::new);
+        for ((corrected, leds), lut) in self.corrected16.iter_mut().zip(channels).zip(&self.luts) {
+            corrected.clear();
+            corrected.extend(leds.iter().map(|&color| lut.apply16(color)));
+        }
+        let power = self.power.limit16(&mut self.corrected16);
+
+        self.wire.resize_with(channels.len(), Vec::new);
+        let channels = self.wire.iter_mut().zip(&self.corrected16).zip(&self.formats);
+        for (chan, ((wire, leds), format)) in channels.enumerate() {
+            wire.clear();
+            wire.extend(
+                leds.iter()
+                    .enumerate()
+                    .map(|(n, &color)| format.to_wire(dither.apply(chan, n, color), self.white_mode)),
+            );
+        }
+        self.encode_wire(buf, &self.wire);
+        Ok(power)
+    }
+
+    fn check_channels(&self, nchans: usize) -> Result<()> {
+        if nchans > self.nchans {
+            return Err(ConfigError::InvalidChannel(self.nchans).into());
+        }
+        Ok(())
+    }
+
+    // shifts out values already converted to the channels' pixel formats
+    fn encode_wire(&self, buf: &mut [u8], wire: &[Vec<u32>]) {
+        assert!(wire.len() <= self.nchans, "More channels than data lines");
+
+        let nleds = wire.iter().map(|leds| leds.len()).max().unwrap_or(0);
+        let len = self.tx_buff_len(nleds);
+        assert!(buf.len() >= len * self.sample_size(), "Buffer too small for LED data");
+
+        // reset gaps before and after the LED data are held low
+        for n in 0..self.led_tx_offset(0) {
+            self.write_sample(buf, n, 0);
+        }
+        for n in self.led_tx_offset(nleds)..len {
+            self.write_sample(buf, n, 0);
+        }
+
+        let BitTiming { npulses, t0_pulses, t1_pulses } = self.timing;
+        let mut off = self.led_tx_offset(0);
+        for cell in 0..nleds * self.nbits {
+            // channels still sending data in this cell, and those sending a 1
+            let mut active = 0;
+            let mut sample = 0;
+            for (chan, format) in self.formats.iter().enumerate() {
+                let bits = format.bits();
+                if cell >= nleds * bits {
+                    continue;
+                }
+                active |= 1 << chan;
+
+                let (led, bit) = (cell / bits, bits - 1 - cell % bits);
+                let value = wire.get(chan).and_then(|leds| leds.get(led)).copied();
+                if value.is_some_and(|value| value & (1 << bit) != 0) {
+                    sample |= 1 << chan;
+                }
+            }
+
+            for n in 0..t0_pulses {
+                self.write_sample(buf, off + n, active);
+            }
+            for n in t0_pulses..t1_pulses {
+                self.write_sample(buf, off + n, sample);
+            }
+            for n in t1_pulses..npulses {
+                self.write_sample(buf, off + n, 0);
+            }
+
+            off += npulses;
+        }
+    }
+
+    #[inline(always)]
+    fn write_sample(&self, buf: &mut [u8], n: usize, value: u32) {
+        match self.nchans {
+            8 => buf[n] = value as u8,
+            _ => buf[n * 2..n * 2 + 2].copy_from_slice(&(value as u16).to_le_bytes()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encodes_each_channel_on_its_own_line() {
+        let config = LedConfig::builder()
+            .channels(8)
+            .pixel_format(PixelFormat::Rgb)
+            .build()
+            .unwrap();
+        let mut encoder = Encoder::new(&config);
+        let mut buf = vec![0xAA; encoder.tx_buff_size(1)];
+        encoder.encode(&mut buf, &[&[0x800000], &[0x000000], &[0xC00000]]).unwrap();
+
+        let off = encoder.led_tx_offset(0);
+        assert_eq!(&buf[off..off + 3], &[0xFF, 0b101, 0x00]);
+        assert_eq!(&buf[off + 3..off + 6], &[0xFF, 0b100, 0x00]);
+        assert_eq!(&buf[off + 6..off + 9], &[0xFF, 0b000, 0x00]);
+        assert!(buf[encoder.led_tx_offset(1)..].iter().all(|&b| b == 0));
+        assert!(encoder.encode(&mut buf, &[&[0u32][..]; 9]).is_err());
+    }
+
+    #[test]
+    fn encodes_16_channels_as_little_endian_samples() {
+        let config = LedConfig::builder()
+            .channels(16)
+            .pixel_format(PixelFormat::Rgb)
+            .build()
+            .unwrap();
+        let mut encoder = Encoder::new(&config);
+        let mut channels = vec![&[0u32][..]; 16];
+        channels[15] = &[0xFFFFFF];
+        let mut buf = vec![0; encoder.tx_buff_size(1)];
+        encoder.encode(&mut buf, &channels).unwrap();
+
+        let off = encoder.led_tx_offset(0) * 2;
+        assert_eq!(&buf[off..off + 6], &[0xFF, 0xFF, 0x00, 0x80, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn ends_narrower_formats_early() {
+        let config = LedConfig::builder()
+            .pixel_format(PixelFormat::Rgb)
+            .channel_format(1, PixelFormat::Rgbw)
+            .build()
+            .unwrap();
+        let mut encoder = Encoder::new(&config);
+        let mut buf = vec![0xAA; encoder.tx_buff_size(1)];
+        encoder.encode(&mut buf, &[&[0x000001], &[0x01000000]]).unwrap();
+
+        // bit 23 is the last bit on D0 while D1 is only done after bit 31
+        let off = encoder.led_tx_offset(0);
+        assert_eq!(&buf[off + 23 * 3..off + 24 * 3], &[0xFF, 0b01, 0x00]);
+        assert_eq!(&buf[off + 24 * 3..off + 25 * 3], &[0b10, 0b00, 0x00]);
+        assert_eq!(&buf[off + 31 * 3..off + 32 * 3], &[0b10, 0b10, 0x00]);
+        assert_eq!(buf[encoder.led_tx_offset(1)], 0);
+    }
+
+    #[test]
+    fn applies_channel_color_correction() {
+        let config = LedConfig::builder()
+            .pixel_format(PixelFormat::Rgb)
+            .channel_correction(1, ColorCorrection::new().gain(0.5, 1.0, 0.0, 1.0))
+            .build()
+            .unwrap();
+        let mut encoder = Encoder::new(&config);
+        let mut buf = vec![0; encoder.tx_buff_size(1)];
+        encoder.encode(&mut buf, &[&[0xFFFFFF], &[0xFFFFFF]]).unwrap();
+
+        // red is halved to 0x80 on D1, blue is off
+        let bit = |n: usize| buf[encoder.led_tx_offset(0) + n * 3 + 1];
+        assert_eq!(bit(0), 0b11);
+        assert_eq!(bit(1), 0b01);
+        assert_eq!(bit(23), 0b01);
+
+        // a mapped frame goes through the same correction
+        let map = PixelMap::from_csv("1,0,0,0\n0,0,1,0\n").unwrap();
+        let mut mapped = vec![0; buf.len()];
+        encoder.encode_mapped(&mut mapped, &map, &[0xFFFFFF, 0xFFFFFF]).unwrap();
+        assert_eq!(mapped, buf);
+    }
+}
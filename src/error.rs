@@ -0,0 +1,65 @@
+use std::io;
+
+use thiserror::Error;
+
+use crate::board::BoardError;
+use crate::config::ConfigError;
+use crate::cube::LayoutError;
+use crate::daemon::DaemonError;
+use crate::dma::DmaError;
+use crate::mapping::MappingError;
+use crate::net::NetError;
+use crate::smi::SmiError;
+use crate::timing::TimingError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("mailbox error: {0}")]
+    Mailbox(#[from] rpi_mailbox::error::Error),
+    #[error("can't open {path}: {source}")]
+    Open {
+        path: &'static str,
+        #[source]
+        source: io::Error,
+    },
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Board(#[from] BoardError),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Layout(#[from] LayoutError),
+    #[error(transparent)]
+    Mapping(#[from] MappingError),
+    #[error(transparent)]
+    Net(#[from] NetError),
+    #[error(transparent)]
+    Daemon(#[from] DaemonError),
+    #[error(transparent)]
+    Timing(#[from] TimingError),
+    #[error(transparent)]
+    Dma(#[from] DmaError),
+    #[error(transparent)]
+    Smi(#[from] SmiError),
+    #[error("invalid SMI data width {0}, must be 8, 9, 16 or 18")]
+    InvalidDataWidth(usize),
+    #[error("invalid DMA channel {0}")]
+    InvalidDmaChannel(u8),
+    #[error("invalid GPIO pin {0}")]
+    InvalidPin(usize),
+    #[error("can't allocate {size} bytes aligned to {alignment}")]
+    InvalidAllocation { size: u32, alignment: u32 },
+    #[error("a DMA chain needs at least one control block")]
+    EmptyDmaChain,
+    #[error("DMA block of {length} bytes at {destination:#x} runs past the end of the bus address space")]
+    DmaAddressOverflow { destination: u32, length: u32 },
+    #[error("the SMI stops after one pass, a looped DMA chain can't feed it")]
+    LoopedSmiChain,
+    #[error("transfer of {range:?} doesn't fit in a {len} byte buffer")]
+    InvalidTransfer { range: std::ops::Range<usize>, len: usize },
+    #[error("a transfer is already in progress")]
+    TransferInProgress,
+}
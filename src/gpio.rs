@@ -1,11 +1,10 @@
 #![allow(dead_code)]
 
-use std::fs::OpenOptions;
-use std::ptr::{read_volatile, write_volatile};
+use crate::error::{Error, Result};
+use crate::hal::{Backend, RegisterBackend};
+use crate::GPIO_OFFSET;
 
-use memmap2::{MmapMut, MmapOptions};
-
-use crate::GPIO_BASE_ADDRESS;
+const GPIO_PINS: usize = 54;
 
 const GPIO_MODE0: usize     = 0x00;
 const GPIO_SET0: usize      = 0x1c;
@@ -25,33 +24,20 @@ pub enum GpioMode {
     Alt5,
 }
 
-pub struct Gpio {
-    mapping: MmapMut,
+pub struct Gpio<B: Backend> {
+    gpio_regs: B::Registers,
     configured_pins: Vec<usize>,
 }
 
-impl Gpio {
-    pub fn new() -> Self {
-        let devmem = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open("/dev/mem")
-            .expect("Failed to open /dev/mem");
-
-        let gpio_map = unsafe {
-            MmapOptions::new()
-                .offset(GPIO_BASE_ADDRESS as u64)
-                .len(0x1000)
-                .map_mut(&devmem)
-                .expect("Failed to map GPIO peripheral")
-        };
-
+impl<B: Backend> Gpio<B> {
+    pub fn new(backend: &B) -> Result<Self> {
+        let gpio_regs = backend.map_registers(GPIO_OFFSET, 0x1000)?;
         let configured_pins = Vec::new();
 
-        Gpio {
-            mapping: gpio_map,
+        Ok(Gpio {
+            gpio_regs,
             configured_pins,
-        }
+        })
     }
 
     fn set_pin_mode(&mut self, pin: usize, mode: u32) {
@@ -59,21 +45,13 @@ impl Gpio {
         let shift = (pin % 10) * 3;
         let mask = 0b111 << shift;
 
-        let reg = unsafe {
-            self.mapping
-                .as_mut_ptr()
-                .byte_add(GPIO_MODE0)
-                .add(pin_offset) as *mut u32
-        };
-
-        unsafe {
-            let mut pre = read_volatile(reg);
-            pre = (pre & !mask) | (mode << shift);
-            write_volatile(reg, pre);
-        }
+        self.gpio_regs.modify(GPIO_MODE0 + pin_offset * 4, |pre| {
+            *pre = (*pre & !mask) | (mode << shift);
+        });
     }
 
-    pub fn configure_pin(&mut self, pin: usize, mode: GpioMode) {
+    pub fn configure_pin(&mut self, pin: usize, mode: GpioMode) -> Result<()> {
+        check_pin(pin)?;
         self.configured_pins.push(pin);
         match mode {
             GpioMode::Input => self.set_pin_mode(pin, 0),
@@ -85,42 +63,36 @@ impl Gpio {
             GpioMode::Alt4 => self.set_pin_mode(pin, 3),
             GpioMode::Alt5 => self.set_pin_mode(pin, 2),
         }
+        Ok(())
     }
 
-    pub fn set_pin(&mut self, pin: usize, value: bool) {
+    pub fn set_pin(&mut self, pin: usize, value: bool) -> Result<()> {
+        check_pin(pin)?;
         let pin_offset = pin / 32;
         let shift = pin % 32;
 
-        let reg = unsafe {
-            self.mapping
-                .as_mut_ptr()
-                .byte_add(if value { GPIO_SET0 } else { GPIO_CLR0 })
-                .add(pin_offset) as *mut u32
-        };
-
-        unsafe {
-            let mut pre = read_volatile(reg);
-            pre = pre | (1 << shift);
-            write_volatile(reg, pre);
-        }
+        let reg = if value { GPIO_SET0 } else { GPIO_CLR0 } + pin_offset * 4;
+        self.gpio_regs.modify(reg, |pre| *pre |= 1 << shift);
+        Ok(())
     }
 
-    pub fn read_pin(&self, pin: usize) -> bool {
+    pub fn read_pin(&self, pin: usize) -> Result<bool> {
+        check_pin(pin)?;
         let pin_offset = pin / 32;
         let shift = pin % 32;
 
-        let reg = unsafe {
-            self.mapping
-                .as_ptr()
-                .byte_add(GPIO_LEV0)
-                .add(pin_offset) as *const u32
-        };
+        Ok(self.gpio_regs.read(GPIO_LEV0 + pin_offset * 4) & (1 << shift) != 0)
+    }
+}
 
-        unsafe { read_volatile(reg) & (1 << shift) != 0 }
+fn check_pin(pin: usize) -> Result<()> {
+    if pin >= GPIO_PINS {
+        return Err(Error::InvalidPin(pin));
     }
+    Ok(())
 }
 
-impl Drop for Gpio {
+impl<B: Backend> Drop for Gpio<B> {
     fn drop(&mut self) {
         let pins = self.configured_pins.drain(..).collect::<Vec<_>>();
         for pin in pins {
@@ -0,0 +1,84 @@
+use std::fs::{File, OpenOptions};
+use std::rc::Rc;
+
+use memmap2::{MmapMut, MmapOptions};
+
+use crate::board::Platform;
+use crate::error::{Error, Result};
+use crate::hal::{Backend, RegisterBackend};
+use crate::vc_mem::VcMem;
+
+/// Hardware backend, maps the peripherals out of `/dev/mem`.
+pub struct DevMem {
+    devmem: File,
+    platform: Platform,
+}
+
+impl DevMem {
+    /// Opens `/dev/mem` for the board this is running on, see
+    /// `Platform::detect`.
+    pub fn new() -> Result<Self> {
+        DevMem::with_platform(Platform::detect()?)
+    }
+
+    pub fn with_platform(platform: Platform) -> Result<Self> {
+        let devmem = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/mem")
+            .map_err(|source| Error::Open { path: "/dev/mem", source })?;
+
+        Ok(DevMem { devmem, platform })
+    }
+
+}
+
+impl Backend for DevMem {
+    type Registers = MmapRegisters;
+
+    fn platform(&self) -> &Platform {
+        &self.platform
+    }
+
+    fn map_registers(&self, offset: usize, len: usize) -> Result<MmapRegisters> {
+        let mapping = unsafe {
+            MmapOptions::new()
+                .offset((self.platform.peripheral_base + offset) as u64)
+                .len(len)
+                .map_mut(&self.devmem)?
+        };
+
+        Ok(MmapRegisters {
+            mapping: Rc::new(mapping),
+        })
+    }
+
+    fn alloc_vc_mem(&self, size: u32, alignment: u32) -> Result<VcMem> {
+        VcMem::new(size, alignment, &self.platform)
+    }
+}
+
+#[derive(Clone)]
+pub struct MmapRegisters {
+    mapping: Rc<MmapMut>,
+}
+
+impl MmapRegisters {
+    #[inline(always)]
+    fn reg(&self, offset: usize) -> *mut u32 {
+        assert!(offset + 4 <= self.mapping.len());
+        unsafe { self.mapping.as_ptr().byte_add(offset) as *mut u32 }
+    }
+}
+
+impl RegisterBackend for MmapRegisters {
+    #[inline(always)]
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { self.reg(offset).read_volatile() }
+    }
+
+    #[inline(always)]
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { self.reg(offset).write_volatile(value) }
+    }
+}
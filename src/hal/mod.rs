@@ -0,0 +1,46 @@
+mod devmem;
+mod sim;
+
+pub use devmem::DevMem;
+pub use sim::Simulated;
+pub(crate) use sim::SimMemory;
+
+use crate::board::Platform;
+use crate::error::Result;
+use crate::vc_mem::VcMem;
+
+/// A block of 32-bit peripheral registers, addressed by byte offset from the
+/// start of the block.
+///
+/// Handles are cheap to clone and every clone refers to the same registers,
+/// which lets each of the smi register structs hold on to its own copy.
+pub trait RegisterBackend: Clone {
+    fn read(&self, offset: usize) -> u32;
+
+    fn write(&self, offset: usize, value: u32);
+
+    #[inline(always)]
+    fn modify(&self, offset: usize, cb: impl FnOnce(&mut u32)) {
+        let mut value = self.read(offset);
+        cb(&mut value);
+        self.write(offset, value);
+    }
+}
+
+/// Source of peripheral registers and VC memory for `Smi`, `Dma` and `Gpio`.
+///
+/// `DevMem` talks to the real hardware through `/dev/mem` and the mailbox,
+/// `Simulated` keeps everything in process so the pipeline can run off a Pi.
+pub trait Backend {
+    type Registers: RegisterBackend;
+
+    /// The board the registers and memory belong to.
+    fn platform(&self) -> &Platform;
+
+    /// Maps `len` bytes of registers starting `offset` bytes into the
+    /// peripheral block.
+    fn map_registers(&self, offset: usize, len: usize) -> Result<Self::Registers>;
+
+    /// Allocates uncached memory that the DMA engine can read from.
+    fn alloc_vc_mem(&self, size: u32, alignment: u32) -> Result<VcMem>;
+}
@@ -0,0 +1,415 @@
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use log::debug;
+
+use crate::dma::{
+    DMA_CB_SRCE_INC,
+    DMA_CONBLK_AD,
+    DMA_CS,
+    DMA_CS_ACTIVE,
+    DMA_CS_END,
+    DMA_CS_RESET,
+    DMA_DEBUG,
+};
+use crate::board::{Platform, Soc};
+use crate::error::{Error, Result};
+use crate::hal::{Backend, RegisterBackend};
+use crate::smi::{CLK_BUSY, CLK_ENAB, CLK_KILL, CLK_SMI_CTL};
+use crate::vc_mem::VcMem;
+use crate::{CLK_OFFSET, DMA_OFFSET, PERIPHERAL_BUS_ADDRESS};
+
+// simulated VC memory is handed out from here upwards, the bus address gets
+// the platform's uncached alias like the firmware's allocations do
+const SIM_MEM_BASE: usize = 0x10000000;
+
+// a Zero 2 W unless told otherwise
+const SIM_REVISION: u32 = 0x902120;
+
+const DMA_CHANNELS: usize = 15;
+
+/// In-process backend with a simulated register file.
+///
+/// Registers read back whatever was last written to them, except for the few
+/// bits the driver polls on: the SMI clock reports busy while enabled and a
+/// DMA channel runs its whole control block chain as soon as it is started
+/// (unless stalled with `set_dma_stalled`, or the clock with
+/// `set_clock_stuck`). Anything the DMA engine writes to
+/// a peripheral register is captured and can be fetched with `take_fifo`.
+#[derive(Clone)]
+pub struct Simulated {
+    bus: Rc<RefCell<SimBus>>,
+    platform: Platform,
+}
+
+impl Simulated {
+    pub fn new() -> Self {
+        Simulated::with_platform(Platform::from_soc(Soc::Bcm2837, SIM_REVISION))
+    }
+
+    pub fn with_platform(platform: Platform) -> Self {
+        Simulated {
+            bus: Rc::new(RefCell::new(SimBus::default())),
+            platform,
+        }
+    }
+
+    /// Returns (and clears) every byte the DMA engine has written to the
+    /// peripheral register at `offset` from the peripheral base.
+    pub fn take_fifo(&self, offset: usize) -> Vec<u8> {
+        self.bus.borrow_mut().fifos.remove(&offset).unwrap_or_default()
+    }
+
+    /// While stalled, started DMA channels stay active without moving any
+    /// data, they run once the stall is lifted.
+    pub fn set_dma_stalled(&self, stalled: bool) {
+        let mut bus = self.bus.borrow_mut();
+        bus.dma_stalled = stalled;
+        if !stalled {
+            for cs in std::mem::take(&mut bus.stalled_channels) {
+                bus.finish_dma(cs);
+            }
+        }
+    }
+
+    /// While stuck, the SMI clock stays busy whatever is written to it.
+    pub fn set_clock_stuck(&self, stuck: bool) {
+        self.bus.borrow_mut().clock_stuck = stuck;
+    }
+
+    /// Sets the register at `offset` from the peripheral base without going
+    /// through any of the register models, e.g. to flag an error.
+    pub fn poke(&self, offset: usize, value: u32) {
+        self.bus.borrow_mut().registers.insert(offset, value);
+    }
+}
+
+impl Default for Simulated {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for Simulated {
+    type Registers = SimRegisters;
+
+    fn platform(&self) -> &Platform {
+        &self.platform
+    }
+
+    fn map_registers(&self, offset: usize, len: usize) -> Result<SimRegisters> {
+        Ok(SimRegisters {
+            bus: self.bus.clone(),
+            address: offset,
+            len,
+        })
+    }
+
+    fn alloc_vc_mem(&self, size: u32, alignment: u32) -> Result<VcMem> {
+        let layout = Layout::from_size_align(size as usize, alignment.max(1) as usize)
+            .ok()
+            .filter(|layout| layout.size() > 0)
+            .ok_or(Error::InvalidAllocation { size, alignment })?;
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return Err(Error::InvalidAllocation { size, alignment });
+        }
+
+        let mut bus = self.bus.borrow_mut();
+        let physaddr = (bus.next_physaddr + layout.align() - 1) & !(layout.align() - 1);
+        let busaddr = physaddr | self.platform.dram_bus_alias;
+        bus.next_physaddr = physaddr + layout.size();
+        bus.memory.push(SimRegion {
+            busaddr,
+            ptr,
+            len: layout.size(),
+        });
+
+        let memory = SimMemory {
+            bus: self.bus.clone(),
+            busaddr,
+            ptr,
+            layout,
+        };
+        Ok(VcMem::simulated(memory))
+    }
+}
+
+#[derive(Clone)]
+pub struct SimRegisters {
+    bus: Rc<RefCell<SimBus>>,
+    address: usize,
+    len: usize,
+}
+
+impl RegisterBackend for SimRegisters {
+    fn read(&self, offset: usize) -> u32 {
+        assert!(offset + 4 <= self.len);
+        self.bus.borrow().read(self.address + offset)
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        assert!(offset + 4 <= self.len);
+        self.bus.borrow_mut().write(self.address + offset, value);
+    }
+}
+
+/// Simulated VC memory, unregisters itself from the bus when dropped.
+pub(crate) struct SimMemory {
+    bus: Rc<RefCell<SimBus>>,
+    busaddr: usize,
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl SimMemory {
+    pub fn busaddr(&self) -> usize {
+        self.busaddr
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for SimMemory {
+    fn drop(&mut self) {
+        self.bus.borrow_mut().memory.retain(|region| region.busaddr != self.busaddr);
+        unsafe { dealloc(self.ptr, self.layout) };
+    }
+}
+
+struct SimRegion {
+    busaddr: usize,
+    ptr: *mut u8,
+    len: usize,
+}
+
+struct SimBus {
+    registers: HashMap<usize, u32>,
+    memory: Vec<SimRegion>,
+    next_physaddr: usize,
+    fifos: HashMap<usize, Vec<u8>>,
+    dma_stalled: bool,
+    clock_stuck: bool,
+    // CS register addresses of the channels started while stalled
+    stalled_channels: Vec<usize>,
+}
+
+impl Default for SimBus {
+    fn default() -> Self {
+        SimBus {
+            registers: HashMap::new(),
+            memory: Vec::new(),
+            next_physaddr: SIM_MEM_BASE,
+            fifos: HashMap::new(),
+            dma_stalled: false,
+            clock_stuck: false,
+            stalled_channels: Vec::new(),
+        }
+    }
+}
+
+impl SimBus {
+    fn read(&self, address: usize) -> u32 {
+        self.registers.get(&address).copied().unwrap_or(0)
+    }
+
+    fn write(&mut self, address: usize, value: u32) {
+        let dma_offset = address.wrapping_sub(DMA_OFFSET);
+        let value = if address == CLK_OFFSET + CLK_SMI_CTL {
+            // the password never reads back and the clock is busy while enabled
+            let value = value & 0x00FFFFFF;
+            if self.clock_stuck || (value & CLK_ENAB != 0 && value & CLK_KILL == 0) {
+                value | CLK_BUSY
+            } else {
+                value & !CLK_BUSY
+            }
+        } else if dma_offset < DMA_CHANNELS * 0x100 && dma_offset % 0x100 == DMA_CS {
+            self.write_dma_cs(address, value)
+        } else if dma_offset < DMA_CHANNELS * 0x100 && dma_offset % 0x100 == DMA_DEBUG {
+            // error bits are write 1 to clear
+            self.read(address) & !value
+        } else {
+            value
+        };
+
+        self.registers.insert(address, value);
+    }
+
+    fn write_dma_cs(&mut self, address: usize, value: u32) -> u32 {
+        if value & DMA_CS_RESET != 0 {
+            return 0;
+        }
+
+        // END is write 1 to clear, everything else just reads back
+        let end = self.read(address) & DMA_CS_END & !(value & DMA_CS_END);
+        let mut cs = (value & !(DMA_CS_ACTIVE | DMA_CS_END)) | end;
+        if value & DMA_CS_ACTIVE != 0 && self.dma_stalled {
+            self.stalled_channels.push(address);
+            cs |= DMA_CS_ACTIVE;
+        } else if value & DMA_CS_ACTIVE != 0 {
+            let conblk_ad = address - DMA_CS + DMA_CONBLK_AD;
+            self.run_dma(self.read(conblk_ad) as usize);
+            self.registers.insert(conblk_ad, 0);
+            cs |= DMA_CS_END;
+        }
+
+        cs
+    }
+
+    // runs a channel that was started while stalled
+    fn finish_dma(&mut self, cs_address: usize) {
+        let conblk_ad = cs_address - DMA_CS + DMA_CONBLK_AD;
+        self.run_dma(self.read(conblk_ad) as usize);
+        self.registers.insert(conblk_ad, 0);
+
+        let cs = (self.read(cs_address) & !DMA_CS_ACTIVE) | DMA_CS_END;
+        self.registers.insert(cs_address, cs);
+    }
+
+    fn run_dma(&mut self, mut cb_addr: usize) {
+        // a looping chain only gets run through once
+        let mut visited = HashSet::new();
+        while cb_addr != 0 && visited.insert(cb_addr) {
+            let Some(cb) = self.region(cb_addr, 0x20) else {
+                debug!("simulated DMA: control block {:x} is not in VC memory", cb_addr);
+                break;
+            };
+            let word = |n: usize| unsafe { cb.add(n * 4).cast::<u32>().read_volatile() as usize };
+            let (ti, src, dest, len, next) = (word(0), word(1), word(2), word(3), word(5));
+
+            let data = match self.region(src, if ti & DMA_CB_SRCE_INC != 0 { len } else { 4 }) {
+                Some(ptr) if ti & DMA_CB_SRCE_INC != 0 => unsafe {
+                    std::slice::from_raw_parts(ptr, len).to_vec()
+                },
+                Some(ptr) => unsafe {
+                    std::slice::from_raw_parts(ptr, 4).iter().copied().cycle().take(len).collect()
+                },
+                None => {
+                    debug!("simulated DMA: source {:x} is not in VC memory", src);
+                    break;
+                }
+            };
+
+            if let Some(ptr) = self.region(dest, len) {
+                unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, len) };
+            } else {
+                let address = dest.wrapping_sub(PERIPHERAL_BUS_ADDRESS);
+                self.fifos.entry(address).or_default().extend(data);
+            }
+
+            cb_addr = next;
+        }
+    }
+
+    fn region(&self, busaddr: usize, len: usize) -> Option<*mut u8> {
+        self.memory
+            .iter()
+            .find(|region| busaddr >= region.busaddr && busaddr + len <= region.busaddr + region.len)
+            .map(|region| unsafe { region.ptr.add(busaddr - region.busaddr) })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::LedConfig;
+    use crate::dma::DmaChain;
+    use crate::encoder::Encoder;
+    use crate::smi::{Smi, SmiError, SMI_D};
+    use crate::timing::SmiTiming;
+    use crate::{CLK_OFFSET, SMI_OFFSET};
+
+    #[test]
+    fn dma_transfer_reaches_smi_fifo() {
+        let backend = Simulated::new();
+        let mut encoder = Encoder::new(&LedConfig::default());
+        let mut smi = Smi::new(&backend, 8, &SmiTiming::default(), 10).unwrap();
+
+        let len = encoder.tx_buff_size(2);
+        let mut tx_buff = backend.alloc_vc_mem(0x1000, 0x1000).unwrap();
+        smi.setup_transfer(&tx_buff, 0..len).unwrap();
+
+        encoder.encode(&mut tx_buff, &[&[0x0000FF, 0xFF0000], &[0x123456]]).unwrap();
+        smi.start_transfer().unwrap();
+        smi.wait_transfer().unwrap();
+
+        assert_eq!(backend.take_fifo(SMI_OFFSET + SMI_D), &tx_buff[..len]);
+        assert!(backend.take_fifo(SMI_OFFSET + SMI_D).is_empty());
+    }
+
+    #[test]
+    fn chained_transfer_gathers_into_smi_fifo() {
+        let backend = Simulated::new();
+        let mut smi = Smi::new(&backend, 8, &SmiTiming::default(), 10).unwrap();
+
+        // a shared reset gap followed by the pixel data from another buffer
+        let mut gap = backend.alloc_vc_mem(0x100, 0x20).unwrap();
+        gap.fill(0);
+        let mut pixels = backend.alloc_vc_mem(0x100, 0x20).unwrap();
+        pixels.fill(0xA5);
+
+        let chain = DmaChain::builder()
+            .block(smi.transfer_block(&gap, 0..0x40).unwrap())
+            .block(smi.transfer_block(&pixels, 0x10..0x30).unwrap())
+            .block(smi.transfer_block(&gap, 0..0x40).unwrap())
+            .build(&backend)
+            .unwrap();
+        smi.start_chain(&chain).unwrap();
+        smi.wait_transfer().unwrap();
+
+        let fifo = backend.take_fifo(SMI_OFFSET + SMI_D);
+        assert_eq!(fifo.len(), 0xA0);
+        assert!(fifo[..0x40].iter().all(|&b| b == 0));
+        assert!(fifo[0x40..0x60].iter().all(|&b| b == 0xA5));
+        assert!(fifo[0x60..].iter().all(|&b| b == 0));
+
+        let looped = DmaChain::builder().block(smi.transfer_block(&gap, 0..0x40).unwrap()).looped(true);
+        assert!(matches!(smi.start_chain(&looped.build(&backend).unwrap()), Err(Error::LoopedSmiChain)));
+
+        assert!(matches!(
+            smi.transfer_block(&gap, 0xF0..0x110),
+            Err(Error::InvalidTransfer { len: 0x100, .. })
+        ));
+        assert!(matches!(
+            Smi::new(&backend, 12, &SmiTiming::default(), 10),
+            Err(Error::InvalidDataWidth(12))
+        ));
+        assert!(matches!(
+            Smi::new(&backend, 8, &SmiTiming::default(), 15),
+            Err(Error::InvalidDmaChannel(15))
+        ));
+    }
+
+    #[test]
+    fn bcm2711_keeps_sample_length() {
+        let backend = Simulated::with_platform(Platform::from_soc(Soc::Bcm2711, 0xc03111));
+        let timing = SmiTiming::default();
+        Smi::new(&backend, 8, &timing, 10).unwrap();
+
+        // 420ns samples are 105 ticks of 750MHz / 3
+        let div = backend.bus.borrow().read(CLK_OFFSET + 0xb4);
+        assert_eq!((div >> 12) & 0xfff, 3);
+        let dsw = backend.bus.borrow().read(SMI_OFFSET + 0x14);
+        assert_eq!(dsw & 0x7f, 103);
+
+        // the DMA4 channels aren't driven by `Dma`
+        assert!(matches!(
+            Smi::new(&backend, 8, &timing, 11),
+            Err(Error::InvalidDmaChannel(11))
+        ));
+
+        backend.set_clock_stuck(true);
+        assert!(matches!(
+            Smi::new(&backend, 8, &timing, 10),
+            Err(Error::Smi(SmiError::ClockTimeout(_)))
+        ));
+    }
+}
@@ -0,0 +1,57 @@
+//! Drives WS281x style LED strips from the SMI peripheral of a Raspberry Pi,
+//! up to 16 strips in parallel off a single DMA transfer.
+//!
+//! `LedDriver` is the high level entry point, the peripheral modules underneath
+//! it are public too for anyone that needs to drive the hardware directly.
+
+// register names mirror the BCM2835 datasheet and the field accessors keep
+// their `>> 0` shifts so every field reads the same way
+#![allow(clippy::upper_case_acronyms, clippy::identity_op)]
+
+pub mod board;
+pub mod color;
+pub mod config;
+pub mod cube;
+pub mod daemon;
+pub mod decoder;
+pub mod dither;
+pub mod dma;
+mod driver;
+pub mod encoder;
+pub mod error;
+pub mod gpio;
+pub mod hal;
+pub mod mapping;
+pub mod net;
+pub mod pixel;
+pub mod power;
+pub mod smi;
+pub mod timing;
+pub mod vc_mem;
+
+pub use board::{Platform, Soc};
+pub use color::ColorCorrection;
+pub use config::{ConfigError, LedConfig};
+pub use cube::{CubeLayout, CubeMap};
+pub use driver::LedDriver;
+pub use error::{Error, Result};
+pub use mapping::{LedPosition, PixelMap};
+pub use pixel::{PixelFormat, WhiteMode};
+pub use power::{PowerEstimate, PowerModel};
+pub use timing::{ChipTiming, LedTiming, SmiTiming};
+
+// peripherals are at the same offsets from the peripheral base on every SoC,
+// only the base itself moves, see `board::Platform`
+pub(crate) const PERIPHERAL_BUS_ADDRESS: usize = 0x7E000000;
+pub(crate) const DMA_OFFSET: usize = 0x007000;
+pub(crate) const CLK_OFFSET: usize = 0x101000;
+pub(crate) const GPIO_OFFSET: usize = 0x200000;
+pub(crate) const SMI_OFFSET: usize = 0x600000;
+
+// the SMI has no more data lines than this
+pub(crate) const MAX_CHANNELS: usize = 16;
+
+pub const LED_D0_PIN: usize     =  8;   // GPIO pin for D0 output
+pub const BIT_NPULSES: usize    =  3;   // Number of O/P pulses per LED bit
+pub const REQUEST_THRESH: usize =  2;   // DMA request threshold
+pub const DMA_CHAN: usize       =  10;  // DMA channel to use
@@ -0,0 +1,237 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::cube::CubeMap;
+use crate::MAX_CHANNELS;
+
+#[derive(Error, Debug)]
+pub enum MappingError {
+    #[error("can't read layout: {0}")]
+    Io(#[from] io::Error),
+    #[error("can't parse layout: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("line {line}: {message}")]
+    Csv { line: usize, message: String },
+    #[error("unknown layout file type {0:?}, must be .json or .csv")]
+    UnknownFormat(String),
+    #[error("channel {0} doesn't exist")]
+    InvalidChannel(usize),
+    #[error("LED {index} of channel {channel} is mapped more than once")]
+    Duplicate { channel: usize, index: usize },
+}
+
+/// Where a physical LED is wired and where it is in the logical space the
+/// application renders in.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LedPosition {
+    pub channel: usize,
+    pub index: usize,
+    pub x: f32,
+    pub y: f32,
+    #[serde(default)]
+    pub z: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LayoutFile {
+    Leds(Vec<LedPosition>),
+    Object { leds: Vec<LedPosition> },
+}
+
+/// Lookup table from logical pixels to the LEDs they're sent to.
+///
+/// A frame in logical space is a slice of pixels in the order the LEDs were
+/// listed in, `position` gives the coordinates of each so the application
+/// can render into it without knowing about channels and wiring. LEDs that
+/// aren't listed are kept black.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PixelMap {
+    leds: Vec<LedPosition>,
+    nchans: usize,
+    nleds: usize,
+}
+
+impl PixelMap {
+    pub fn new(leds: Vec<LedPosition>) -> Result<Self, MappingError> {
+        let mut seen = HashSet::new();
+        for led in leds.iter() {
+            if led.channel >= MAX_CHANNELS {
+                return Err(MappingError::InvalidChannel(led.channel));
+            }
+            if !seen.insert((led.channel, led.index)) {
+                return Err(MappingError::Duplicate { channel: led.channel, index: led.index });
+            }
+        }
+
+        Ok(PixelMap {
+            nchans: leds.iter().map(|led| led.channel + 1).max().unwrap_or(0),
+            nleds: leds.iter().map(|led| led.index + 1).max().unwrap_or(0),
+            leds,
+        })
+    }
+
+    /// Reads a layout file, `.json` or `.csv` by its extension.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, MappingError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => PixelMap::from_json(&contents),
+            Some("csv") => PixelMap::from_csv(&contents),
+            ext => Err(MappingError::UnknownFormat(ext.unwrap_or_default().to_string())),
+        }
+    }
+
+    /// Parses a JSON list of `LedPosition`s, either on its own or as the
+    /// `leds` of an object.
+    pub fn from_json(json: &str) -> Result<Self, MappingError> {
+        let leds = match serde_json::from_str(json)? {
+            LayoutFile::Leds(leds) | LayoutFile::Object { leds } => leds,
+        };
+        PixelMap::new(leds)
+    }
+
+    /// Parses `channel,index,x,y[,z]` lines, a header line and lines
+    /// starting with `#` are skipped.
+    pub fn from_csv(csv: &str) -> Result<Self, MappingError> {
+        let mut leds = Vec::new();
+        for (n, line) in csv.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || (leds.is_empty() && line.starts_with("channel")) {
+                continue;
+            }
+
+            let error = |message: String| MappingError::Csv { line: n + 1, message };
+            let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+            if !(4..=5).contains(&fields.len()) {
+                return Err(error(format!("expected 4 or 5 fields, found {}", fields.len())));
+            }
+
+            let int = |n: usize| fields[n].parse::<usize>().map_err(|err| error(format!("{:?}: {}", fields[n], err)));
+            let float = |n: usize| fields[n].parse::<f32>().map_err(|err| error(format!("{:?}: {}", fields[n], err)));
+            leds.push(LedPosition {
+                channel: int(0)?,
+                index: int(1)?,
+                x: float(2)?,
+                y: float(3)?,
+                z: if fields.len() == 5 { float(4)? } else { 0.0 },
+            });
+        }
+        PixelMap::new(leds)
+    }
+
+    /// Number of logical pixels.
+    pub fn len(&self) -> usize {
+        self.leds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leds.is_empty()
+    }
+
+    /// Number of channels the mapped LEDs are spread over.
+    pub fn channels(&self) -> usize {
+        self.nchans
+    }
+
+    /// Number of LEDs on the longest channel.
+    pub fn leds_per_channel(&self) -> usize {
+        self.nleds
+    }
+
+    pub fn leds(&self) -> &[LedPosition] {
+        &self.leds
+    }
+
+    /// Logical coordinates of pixel `n`.
+    pub fn position(&self, n: usize) -> [f32; 3] {
+        let led = &self.leds[n];
+        [led.x, led.y, led.z]
+    }
+
+    /// Renders a frame by calling `shader` with the position of every pixel.
+    pub fn render(&self, mut shader: impl FnMut([f32; 3]) -> u32) -> Vec<u32> {
+        (0..self.len()).map(|n| shader(self.position(n))).collect()
+    }
+
+    /// Writes a logical frame into per channel pixels, pixels missing from
+    /// the end of `frame` are left as they are.
+    pub fn scatter(&self, frame: &[u32], channels: &mut [Vec<u32>]) {
+        for (led, &color) in self.leds.iter().zip(frame) {
+            if let Some(pixel) = channels.get_mut(led.channel).and_then(|leds| leds.get_mut(led.index)) {
+                *pixel = color;
+            }
+        }
+    }
+}
+
+impl TryFrom<&CubeMap> for PixelMap {
+    type Error = MappingError;
+
+    // voxels become logical pixels x first, at their integer coordinates
+    fn try_from(cube: &CubeMap) -> Result<Self, MappingError> {
+        let leds = cube
+            .iter()
+            .map(|([x, y, z], (channel, index))| LedPosition {
+                channel,
+                index,
+                x: x as f32,
+                y: y as f32,
+                z: z as f32,
+            })
+            .collect();
+        PixelMap::new(leds)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn loads_json_and_csv_layouts() {
+        let ring = PixelMap::from_json(include_str!("../tests/fixtures/mapping/ring.json")).unwrap();
+        assert_eq!(ring.len(), 6);
+        assert_eq!(ring.channels(), 2);
+        assert_eq!(ring.leds_per_channel(), 3);
+        assert_eq!(ring.position(1), [0.5, 0.866, 0.0]);
+
+        let matrix = PixelMap::from_csv(include_str!("../tests/fixtures/mapping/matrix.csv")).unwrap();
+        assert_eq!(matrix.len(), 8);
+        assert_eq!(matrix.leds()[6], LedPosition { channel: 1, index: 2, x: 2.0, y: 2.0, z: 0.0 });
+
+        assert!(matches!(
+            PixelMap::from_csv("0,0,0,0\n0,0,1,0\n"),
+            Err(MappingError::Duplicate { channel: 0, index: 0 })
+        ));
+        assert!(matches!(
+            PixelMap::from_csv("0,0,0\n"),
+            Err(MappingError::Csv { line: 1, .. })
+        ));
+        assert!(matches!(
+            PixelMap::from_json(r#"[{ "channel": 16, "index": 0, "x": 0, "y": 0 }]"#),
+            Err(MappingError::InvalidChannel(16))
+        ));
+    }
+
+    #[test]
+    fn scatters_logical_frames() {
+        let map = PixelMap::from_csv(include_str!("../tests/fixtures/mapping/matrix.csv")).unwrap();
+        let frame = map.render(|[x, y, _]| ((y as u32) << 8) | x as u32);
+
+        let mut channels = vec![vec![0xFFFFFF; 5]; 2];
+        map.scatter(&frame, &mut channels);
+        assert_eq!(channels[0], vec![0x000, 0x001, 0x101, 0x100, 0xFFFFFF]);
+        assert_eq!(channels[1], vec![0x200, 0x201, 0x202, 0x302, 0xFFFFFF]);
+
+        let cube = CubeMap::new(&crate::cube::CubeLayout::new(2, 2, 2)).unwrap();
+        let map = PixelMap::try_from(&cube).unwrap();
+        assert_eq!((map.len(), map.channels(), map.leds_per_channel()), (8, 2, 4));
+    }
+}
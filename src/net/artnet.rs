@@ -0,0 +1,397 @@
+//! Art-Net 4 node.
+//!
+//! The node answers ArtPoll with an ArtPollReply for every 4 output ports,
+//! a port for each universe (15-bit port-address) in its `UniverseMap`, and
+//! writes ArtDmx to the LEDs those are mapped to. Data from every controller
+//! is taken as it comes, there's no merging.
+//!
+//! Frames are presented the same way as E1.31 ones, once every port has
+//! been updated, until an ArtSync comes in. From then on frames are only
+//! presented on ArtSync, until there hasn't been one for `SYNC_TIMEOUT`.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use super::{in_sequence, interface_ip, PacketError, PixelSink, UniverseFrame, UniverseMap, UNIVERSE_SIZE};
+use crate::error::Result;
+
+pub const PORT: u16 = 6454;
+
+/// How long the node stays synchronous after an ArtSync.
+pub const SYNC_TIMEOUT: Duration = Duration::from_secs(4);
+
+const ID: &[u8; 8] = b"Art-Net\0";
+const PROTOCOL_VERSION: u16 = 14;
+
+const OP_POLL: u16 = 0x2000;
+const OP_POLL_REPLY: u16 = 0x2100;
+const OP_DMX: u16 = 0x5000;
+const OP_SYNC: u16 = 0x5200;
+
+const POLL_REPLY_LEN: usize = 239;
+const DMX_HEADER_LEN: usize = 18;
+
+// ArtPollReply fields
+const PORT_TYPE_DMX_OUTPUT: u8 = 0x80;
+const GOOD_OUTPUT_DATA: u8 = 0x80;
+// indicators normal, port-addresses set on the node
+const STATUS1: u8 = 0xD0;
+// 15-bit port-addresses
+const STATUS2: u8 = 0x08;
+const STYLE_NODE: u8 = 0x00;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DmxPacket<'a> {
+    /// 0 if the sender doesn't number its packets.
+    pub sequence: u8,
+    pub physical: u8,
+    /// Net, sub-net and universe, 15 bits.
+    pub port_address: u16,
+    pub data: &'a [u8],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Packet<'a> {
+    Poll { flags: u8 },
+    Dmx(DmxPacket<'a>),
+    Sync,
+}
+
+impl<'a> Packet<'a> {
+    pub fn parse(buf: &'a [u8]) -> std::result::Result<Self, PacketError> {
+        if buf.len() < 12 {
+            return Err(PacketError::Truncated(buf.len()));
+        }
+        if &buf[0..8] != ID {
+            return Err(PacketError::InvalidHeader("Art-Net"));
+        }
+
+        let opcode = u16::from_le_bytes([buf[8], buf[9]]);
+        if opcode == OP_POLL_REPLY {
+            // replies from other nodes, they don't carry a protocol version
+            return Err(PacketError::Unsupported { protocol: "Art-Net", kind: opcode as u32 });
+        }
+        if u16::from_be_bytes([buf[10], buf[11]]) < PROTOCOL_VERSION {
+            return Err(PacketError::InvalidHeader("Art-Net 4"));
+        }
+
+        match opcode {
+            OP_POLL if buf.len() >= 14 => Ok(Packet::Poll { flags: buf[12] }),
+            OP_SYNC if buf.len() >= 14 => Ok(Packet::Sync),
+            OP_DMX if buf.len() >= DMX_HEADER_LEN => {
+                let len = u16::from_be_bytes([buf[16], buf[17]]) as usize;
+                if len == 0 || len > UNIVERSE_SIZE || buf.len() < DMX_HEADER_LEN + len {
+                    return Err(PacketError::InvalidLength);
+                }
+                Ok(Packet::Dmx(DmxPacket {
+                    sequence: buf[12],
+                    physical: buf[13],
+                    port_address: u16::from_le_bytes([buf[14], buf[15] & 0x7F]),
+                    data: &buf[DMX_HEADER_LEN..DMX_HEADER_LEN + len],
+                }))
+            }
+            OP_POLL | OP_SYNC | OP_DMX => Err(PacketError::Truncated(buf.len())),
+            _ => Err(PacketError::Unsupported { protocol: "Art-Net", kind: opcode as u32 }),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let opcode = match self {
+            Packet::Poll { .. } => OP_POLL,
+            Packet::Dmx(_) => OP_DMX,
+            Packet::Sync => OP_SYNC,
+        };
+        let mut buf = header(opcode);
+        buf.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        match self {
+            Packet::Poll { flags } => buf.extend_from_slice(&[*flags, 0]),
+            Packet::Sync => buf.extend_from_slice(&[0, 0]),
+            Packet::Dmx(dmx) => {
+                buf.extend_from_slice(&[dmx.sequence, dmx.physical]);
+                buf.extend_from_slice(&dmx.port_address.to_le_bytes());
+                buf.extend_from_slice(&(dmx.data.len() as u16).to_be_bytes());
+                buf.extend_from_slice(dmx.data);
+            }
+        }
+        buf
+    }
+}
+
+fn header(opcode: u16) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(DMX_HEADER_LEN + UNIVERSE_SIZE);
+    buf.extend_from_slice(ID);
+    buf.extend_from_slice(&opcode.to_le_bytes());
+    buf
+}
+
+// copies `name` into a nul terminated field
+fn put_name(buf: &mut [u8], name: &str) {
+    let len = name.len().min(buf.len() - 1);
+    buf[..len].copy_from_slice(&name.as_bytes()[..len]);
+}
+
+/// Listens for Art-Net and writes the universes in its `UniverseMap` to a
+/// `PixelSink`.
+pub struct ArtNetNode {
+    socket: UdpSocket,
+    map: UniverseMap,
+    universes: Vec<u16>,
+    frame: UniverseFrame,
+    short_name: String,
+    long_name: String,
+    ip: Option<Ipv4Addr>,
+    mac: [u8; 6],
+    sequences: HashMap<(IpAddr, u16), u8>,
+    // ports that have had data, reported in ArtPollReply
+    active: HashSet<u16>,
+    last_sync: Option<Instant>,
+    // data has come in since the last ArtSync
+    sync_pending: bool,
+}
+
+impl ArtNetNode {
+    /// Listens on `addr`, usually `(Ipv4Addr::UNSPECIFIED, PORT)`.
+    pub fn bind(addr: impl ToSocketAddrs, map: UniverseMap) -> Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_broadcast(true)?;
+        Ok(ArtNetNode {
+            socket,
+            universes: map.universes(),
+            frame: UniverseFrame::new(&map),
+            map,
+            short_name: "rpi-cube".to_string(),
+            long_name: "rpi-cube SMI LED driver".to_string(),
+            ip: None,
+            mac: [0; 6],
+            sequences: HashMap::new(),
+            active: HashSet::new(),
+            last_sync: None,
+            sync_pending: false,
+        })
+    }
+
+    /// Names shown by controllers, up to 17 and 63 characters.
+    pub fn names(mut self, short_name: &str, long_name: &str) -> Self {
+        self.short_name = short_name.to_string();
+        self.long_name = long_name.to_string();
+        self
+    }
+
+    /// Address announced in ArtPollReply, by default the address of the
+    /// interface the poll came in on.
+    pub fn ip(mut self, ip: Ipv4Addr) -> Self {
+        self.ip = Some(ip);
+        self
+    }
+
+    pub fn mac(mut self, mac: [u8; 6]) -> Self {
+        self.mac = mac;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Makes `recv` fail with `io::ErrorKind::WouldBlock` instead of waiting
+    /// when no packet has come in.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        Ok(self.socket.set_nonblocking(nonblocking)?)
+    }
+
+    pub fn map(&self) -> &UniverseMap {
+        &self.map
+    }
+
+    /// Waits for a packet and handles it, returns true if it completed a
+    /// frame.
+    pub fn recv(&mut self, sink: &mut impl PixelSink) -> Result<bool> {
+        let mut buf = [0; DMX_HEADER_LEN + UNIVERSE_SIZE];
+        let (len, from) = self.socket.recv_from(&mut buf)?;
+        match Packet::parse(&buf[..len]) {
+            Ok(packet) => self.handle(packet, from, Instant::now(), sink),
+            Err(err) => {
+                debug!("Art-Net: dropped packet from {}: {}", from, err);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Handles packets until receiving or presenting fails.
+    pub fn run(&mut self, sink: &mut impl PixelSink) -> Result<()> {
+        loop {
+            self.recv(sink)?;
+        }
+    }
+
+    /// Handles a packet from `from` received at `now`, returns true if it
+    /// completed a frame.
+    pub fn handle(
+        &mut self,
+        packet: Packet,
+        from: SocketAddr,
+        now: Instant,
+        sink: &mut impl PixelSink,
+    ) -> Result<bool> {
+        match packet {
+            Packet::Poll { .. } => {
+                for reply in self.poll_replies(from)? {
+                    self.socket.send_to(&reply, from)?;
+                }
+                Ok(false)
+            }
+            Packet::Sync => {
+                self.last_sync = Some(now);
+                if !self.sync_pending {
+                    return Ok(false);
+                }
+                self.sync_pending = false;
+                self.frame.present(sink)?;
+                Ok(true)
+            }
+            Packet::Dmx(dmx) => {
+                if !self.universes.contains(&dmx.port_address) {
+                    return Ok(false);
+                }
+                if dmx.sequence != 0 {
+                    let key = (from.ip(), dmx.port_address);
+                    if let Some(&last) = self.sequences.get(&key) {
+                        if !in_sequence(last, dmx.sequence) {
+                            return Ok(false);
+                        }
+                    }
+                    self.sequences.insert(key, dmx.sequence);
+                }
+                self.active.insert(dmx.port_address);
+
+                if self.last_sync.is_some_and(|sync| now.duration_since(sync) < SYNC_TIMEOUT) {
+                    self.map.write(dmx.port_address, dmx.data, sink);
+                    self.sync_pending = true;
+                    return Ok(false);
+                }
+                self.frame.write(&self.map, dmx.port_address, dmx.data, sink)
+            }
+        }
+    }
+
+    /// The ArtPollReply packets describing the node, one per 4 ports of the
+    /// same net and sub-net.
+    pub fn poll_replies(&self, to: SocketAddr) -> Result<Vec<Vec<u8>>> {
+        let ip = match self.ip {
+            Some(ip) => ip,
+            None => interface_ip(&self.socket, to)?,
+        };
+
+        let mut groups: Vec<Vec<u16>> = Vec::new();
+        for &universe in self.universes.iter() {
+            match groups.last_mut() {
+                Some(group) if group.len() < 4 && group[0] >> 4 == universe >> 4 => group.push(universe),
+                _ => groups.push(vec![universe]),
+            }
+        }
+        Ok(groups
+            .iter()
+            .enumerate()
+            .map(|(n, ports)| self.poll_reply(ip, ports, n as u8 + 1))
+            .collect())
+    }
+
+    fn poll_reply(&self, ip: Ipv4Addr, ports: &[u16], bind_index: u8) -> Vec<u8> {
+        let mut buf = header(OP_POLL_REPLY);
+        buf.resize(POLL_REPLY_LEN, 0);
+        buf[10..14].copy_from_slice(&ip.octets());
+        buf[14..16].copy_from_slice(&PORT.to_le_bytes());
+        buf[18] = (ports[0] >> 8) as u8;
+        buf[19] = (ports[0] >> 4 & 0x0F) as u8;
+        buf[23] = STATUS1;
+        put_name(&mut buf[26..44], &self.short_name);
+        put_name(&mut buf[44..108], &self.long_name);
+        put_name(&mut buf[108..172], &format!("#0001 [{:04}] OK", self.active.len()));
+        buf[172..174].copy_from_slice(&(ports.len() as u16).to_be_bytes());
+        for (n, &port) in ports.iter().enumerate() {
+            buf[174 + n] = PORT_TYPE_DMX_OUTPUT;
+            if self.active.contains(&port) {
+                buf[182 + n] = GOOD_OUTPUT_DATA;
+            }
+            buf[190 + n] = (port & 0x0F) as u8;
+        }
+        buf[200] = STYLE_NODE;
+        buf[201..207].copy_from_slice(&self.mac);
+        buf[207..211].copy_from_slice(&ip.octets());
+        buf[211] = bind_index;
+        buf[212] = STATUS2;
+        buf
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::PixelBuffer;
+
+    fn dmx(sequence: u8, port_address: u16, data: &[u8]) -> Packet<'_> {
+        Packet::Dmx(DmxPacket { sequence, physical: 0, port_address, data })
+    }
+
+    #[test]
+    fn parses_packets() {
+        let packet = dmx(1, 0x1234, &[1, 2, 3, 4]);
+        let bytes = packet.to_bytes();
+        assert_eq!(&bytes[8..18], &[0x00, 0x50, 0, 14, 1, 0, 0x34, 0x12, 0, 4]);
+        assert_eq!(Packet::parse(&bytes), Ok(packet));
+        assert_eq!(Packet::parse(&Packet::Sync.to_bytes()), Ok(Packet::Sync));
+        assert_eq!(Packet::parse(&Packet::Poll { flags: 2 }.to_bytes()), Ok(Packet::Poll { flags: 2 }));
+
+        assert_eq!(Packet::parse(&bytes[..20]), Err(PacketError::InvalidLength));
+        assert_eq!(Packet::parse(b"Art-Net\0\x00\x50\x00\x0D"), Err(PacketError::InvalidHeader("Art-Net 4")));
+    }
+
+    #[test]
+    fn answers_polls_and_syncs_frames() {
+        // 5 ports, the last on another sub-net
+        let map = UniverseMap::contiguous(0x0C, 5, 100, 3).unwrap();
+        let mut node = ArtNetNode::bind("127.0.0.1:0", map).unwrap().names("cube", "LED cube");
+        let controller = UdpSocket::bind("127.0.0.1:0").unwrap();
+        controller.send_to(&Packet::Poll { flags: 0 }.to_bytes(), node.local_addr().unwrap()).unwrap();
+        let mut sink = PixelBuffer::new(5, 100);
+        assert!(!node.recv(&mut sink).unwrap());
+
+        let mut buf = [0; 512];
+        let (len, _) = controller.recv_from(&mut buf).unwrap();
+        assert_eq!(len, POLL_REPLY_LEN);
+        assert_eq!(&buf[8..10], &[0x00, 0x21]);
+        assert_eq!(&buf[10..14], &[127, 0, 0, 1]);
+        assert_eq!(&buf[26..31], b"cube\0");
+        assert_eq!(&buf[172..174], &[0, 4]);
+        assert_eq!(&buf[190..194], &[0x0C, 0x0D, 0x0E, 0x0F]);
+        let (len, _) = controller.recv_from(&mut buf).unwrap();
+        assert_eq!(len, POLL_REPLY_LEN);
+        assert_eq!((buf[19], buf[190], buf[211]), (1, 0, 2));
+
+        // unsynchronized, the fifth port completes the frame
+        let from = controller.local_addr().unwrap();
+        let now = Instant::now();
+        for port in 0x0C..0x10 {
+            assert!(!node.handle(dmx(0, port, &[0xFF; 3]), from, now, &mut sink).unwrap());
+        }
+        assert!(node.handle(dmx(0, 0x10, &[0xFF; 3]), from, now, &mut sink).unwrap());
+        assert_eq!(sink.frames, 1);
+
+        // after an ArtSync frames wait for the next one
+        node.handle(Packet::Sync, from, now, &mut sink).unwrap();
+        for port in 0x0C..0x11 {
+            assert!(!node.handle(dmx(0, port, &[0x01; 3]), from, now, &mut sink).unwrap());
+        }
+        assert_eq!(sink.pixels[4][0], 0x010101);
+        assert!(node.handle(Packet::Sync, from, now, &mut sink).unwrap());
+        assert_eq!(sink.frames, 2);
+
+        let later = now + SYNC_TIMEOUT;
+        node.handle(dmx(0, 0x0C, &[0x02; 3]), from, later, &mut sink).unwrap();
+        assert_eq!(sink.frames, 2);
+        assert_eq!(node.poll_replies(from).unwrap()[0][182], GOOD_OUTPUT_DATA);
+    }
+}
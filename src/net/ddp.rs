@@ -0,0 +1,329 @@
+//! DDP (Distributed Display Protocol) receiver.
+//!
+//! DDP sends the whole display as one run of bytes, each packet carrying a
+//! slice of it at a byte offset, and a packet with the push flag shows what
+//! has been sent so far. The bytes are LEDs of `components` bytes each, in
+//! channel order (all of channel 0, then channel 1, ...) or in the order of a
+//! `PixelMap`.
+//!
+//! Status and config queries are answered with the JSON replies xLights and
+//! FPP expect, config writes are ignored.
+
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use serde_json::json;
+
+use super::{interface_ip, LinearDisplay, PacketError, PixelSink};
+use crate::error::Result;
+use crate::mapping::PixelMap;
+
+pub const PORT: u16 = 4048;
+
+/// Destination of pixel data.
+pub const ID_DISPLAY: u8 = 1;
+pub const ID_CONFIG: u8 = 250;
+pub const ID_STATUS: u8 = 251;
+pub const ID_ALL: u8 = 255;
+
+/// A sender quiet for this long starts its sequence afresh.
+pub const SOURCE_TIMEOUT: Duration = Duration::from_millis(2500);
+
+const VERSION_1: u8 = 0x40;
+const FLAG_TIMECODE: u8 = 0x10;
+const FLAG_REPLY: u8 = 0x04;
+const FLAG_QUERY: u8 = 0x02;
+const FLAG_PUSH: u8 = 0x01;
+
+const HEADER_LEN: usize = 10;
+// the largest packet xLights sends, 480 RGB LEDs
+const MAX_DATA_LEN: usize = 1440;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Packet<'a> {
+    pub push: bool,
+    pub query: bool,
+    pub reply: bool,
+    /// 1-15, 0 if the sender doesn't number its packets.
+    pub sequence: u8,
+    pub data_type: u8,
+    pub id: u8,
+    /// Byte offset of `data` in the display.
+    pub offset: u32,
+    pub timecode: Option<u32>,
+    pub data: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    /// Pixel data for the display.
+    pub fn data(offset: u32, data: &'a [u8], push: bool) -> Self {
+        Packet {
+            push,
+            query: false,
+            reply: false,
+            sequence: 0,
+            data_type: 0,
+            id: ID_DISPLAY,
+            offset,
+            timecode: None,
+            data,
+        }
+    }
+
+    pub fn parse(buf: &'a [u8]) -> std::result::Result<Self, PacketError> {
+        if buf.len() < HEADER_LEN {
+            return Err(PacketError::Truncated(buf.len()));
+        }
+        let flags = buf[0];
+        if flags & 0xC0 != VERSION_1 {
+            return Err(PacketError::InvalidHeader("DDP version 1"));
+        }
+
+        let (timecode, header_len) = match flags & FLAG_TIMECODE {
+            0 => (None, HEADER_LEN),
+            _ if buf.len() < HEADER_LEN + 4 => return Err(PacketError::Truncated(buf.len())),
+            _ => (Some(u32::from_be_bytes(buf[10..14].try_into().unwrap())), HEADER_LEN + 4),
+        };
+        let len = u16::from_be_bytes([buf[8], buf[9]]) as usize;
+        if buf.len() < header_len + len {
+            return Err(PacketError::InvalidLength);
+        }
+
+        Ok(Packet {
+            push: flags & FLAG_PUSH != 0,
+            query: flags & FLAG_QUERY != 0,
+            reply: flags & FLAG_REPLY != 0,
+            sequence: buf[1] & 0x0F,
+            data_type: buf[2],
+            id: buf[3],
+            offset: u32::from_be_bytes(buf[4..8].try_into().unwrap()),
+            timecode,
+            data: &buf[header_len..header_len + len],
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut flags = VERSION_1;
+        for (set, flag) in [
+            (self.timecode.is_some(), FLAG_TIMECODE),
+            (self.reply, FLAG_REPLY),
+            (self.query, FLAG_QUERY),
+            (self.push, FLAG_PUSH),
+        ] {
+            if set {
+                flags |= flag;
+            }
+        }
+
+        let mut buf = vec![flags, self.sequence & 0x0F, self.data_type, self.id];
+        buf.extend_from_slice(&self.offset.to_be_bytes());
+        buf.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+        if let Some(timecode) = self.timecode {
+            buf.extend_from_slice(&timecode.to_be_bytes());
+        }
+        buf.extend_from_slice(self.data);
+        buf
+    }
+}
+
+/// Listens for DDP and writes the display to a `PixelSink`.
+pub struct DdpReceiver {
+    socket: UdpSocket,
+    display: LinearDisplay,
+    // last sequence number seen from each sender and when
+    sequences: HashMap<SocketAddr, (u8, Instant)>,
+}
+
+impl DdpReceiver {
+    /// Listens on `addr`, usually `(Ipv4Addr::UNSPECIFIED, PORT)`, for LEDs
+    /// of 3 (RGB) or 4 (RGBW) bytes.
+    pub fn bind(addr: impl ToSocketAddrs, components: usize) -> Result<Self> {
+        Ok(DdpReceiver {
+            socket: UdpSocket::bind(addr)?,
+            display: LinearDisplay::new(components)?,
+            sequences: HashMap::new(),
+        })
+    }
+
+    /// Sends the LEDs of the display to the pixels of `map` in order instead
+    /// of channel after channel.
+    pub fn pixel_map(mut self, map: PixelMap) -> Self {
+        self.display.map = Some(map);
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Makes `recv` fail with `io::ErrorKind::WouldBlock` instead of waiting
+    /// when no packet has come in.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        Ok(self.socket.set_nonblocking(nonblocking)?)
+    }
+
+    /// Waits for a packet and handles it, returns true if it pushed a frame.
+    pub fn recv(&mut self, sink: &mut impl PixelSink) -> Result<bool> {
+        let mut buf = [0; HEADER_LEN + 4 + MAX_DATA_LEN];
+        let (len, from) = self.socket.recv_from(&mut buf)?;
+        match Packet::parse(&buf[..len]) {
+            Ok(packet) => self.handle(packet, from, Instant::now(), sink),
+            Err(err) => {
+                debug!("DDP: dropped packet from {}: {}", from, err);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Handles packets until receiving or presenting fails.
+    pub fn run(&mut self, sink: &mut impl PixelSink) -> Result<()> {
+        loop {
+            self.recv(sink)?;
+        }
+    }
+
+    /// Handles a packet from `from` received at `now`, returns true if it
+    /// pushed a frame.
+    pub fn handle(&mut self, packet: Packet, from: SocketAddr, now: Instant, sink: &mut impl PixelSink) -> Result<bool> {
+        if packet.reply {
+            return Ok(false);
+        }
+        if packet.query {
+            if let Some(reply) = self.query_reply(&packet, from, sink)? {
+                self.socket.send_to(&reply, from)?;
+            }
+            return Ok(false);
+        }
+        if packet.id != ID_DISPLAY && packet.id != ID_ALL {
+            debug!("DDP: ignored write to {} from {}", packet.id, from);
+            return Ok(false);
+        }
+
+        if packet.sequence != 0 {
+            // up to 7 packets behind the last is out of order, anything
+            // further is taken as packets lost in between
+            self.sequences.retain(|_, (_, seen)| now.duration_since(*seen) < SOURCE_TIMEOUT);
+            // sequence numbers count 1 to 15 and round again
+            if let Some(&(last, _)) = self.sequences.get(&from) {
+                if (15 + last % 15 - packet.sequence % 15) % 15 < 8 {
+                    return Ok(false);
+                }
+            }
+            self.sequences.insert(from, (packet.sequence, now));
+        }
+
+        self.display.write(packet.offset as usize, packet.data, sink);
+        if packet.push {
+            sink.present()?;
+        }
+        Ok(packet.push)
+    }
+
+    // JSON answer to a status or config query
+    fn query_reply(&self, query: &Packet, from: SocketAddr, sink: &impl PixelSink) -> Result<Option<Vec<u8>>> {
+        let json = match query.id {
+            ID_STATUS => json!({
+                "status": {
+                    "man": "rpi-cube",
+                    "mod": "SMI LED driver",
+                    "ver": env!("CARGO_PKG_VERSION"),
+                }
+            }),
+            ID_CONFIG => {
+                let nleds = sink.leds_per_channel();
+                let ports = (0..sink.channels())
+                    .map(|channel| json!({ "port": channel, "ts": 0, "l": nleds, "ss": channel * nleds }))
+                    .collect::<Vec<_>>();
+                json!({ "config": { "ip": interface_ip(&self.socket, from)?.to_string(), "ports": ports } })
+            }
+            _ => return Ok(None),
+        };
+
+        let json = json.to_string();
+        let reply = Packet {
+            push: true,
+            query: false,
+            reply: true,
+            sequence: query.sequence,
+            data_type: 0,
+            id: query.id,
+            offset: 0,
+            timecode: None,
+            data: json.as_bytes(),
+        };
+        Ok(Some(reply.to_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::PixelBuffer;
+
+    #[test]
+    fn parses_packets() {
+        let mut packet = Packet::data(300, &[1, 2, 3], true);
+        packet.sequence = 5;
+        let bytes = packet.to_bytes();
+        assert_eq!(&bytes[..10], &[0x41, 5, 0, 1, 0, 0, 0x01, 0x2C, 0, 3]);
+        assert_eq!(Packet::parse(&bytes), Ok(packet));
+
+        packet.timecode = Some(7);
+        assert_eq!(Packet::parse(&packet.to_bytes()), Ok(packet));
+        assert_eq!(Packet::parse(&bytes[..12]), Err(PacketError::InvalidLength));
+        assert_eq!(Packet::parse(&[0x81; 10]), Err(PacketError::InvalidHeader("DDP version 1")));
+    }
+
+    #[test]
+    fn pushes_frames_at_offsets() {
+        let mut receiver = DdpReceiver::bind("127.0.0.1:0", 3).unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let from = sender.local_addr().unwrap();
+        let mut sink = PixelBuffer::new(2, 4);
+        let now = Instant::now();
+
+        // the second packet starts half way through an LED
+        let data = (1..=24).collect::<Vec<u8>>();
+        assert!(!receiver.handle(Packet::data(0, &data[..10], false), from, now, &mut sink).unwrap());
+        assert!(receiver.handle(Packet::data(10, &data[10..], true), from, now, &mut sink).unwrap());
+        assert_eq!(sink.frames, 1);
+        assert_eq!(sink.pixels[0], vec![0x010203, 0x040506, 0x070809, 0x0A0B0C]);
+        assert_eq!(sink.pixels[1][3], 0x161718);
+
+        let mut late = Packet::data(0, &[0; 3], false);
+        late.sequence = 3;
+        receiver.handle(late, from, now, &mut sink).unwrap();
+        late.sequence = 2;
+        receiver.handle(Packet { data: &[0xFF; 3], ..late }, from, now, &mut sink).unwrap();
+        assert_eq!(sink.pixels[0][0], 0);
+        // another sender counts on its own
+        let other = "127.0.0.2:4048".parse().unwrap();
+        receiver.handle(Packet { data: &[0xFF; 3], ..late }, other, now, &mut sink).unwrap();
+        assert_eq!(sink.pixels[0][0], 0xFFFFFF);
+
+        // 9 is 7 behind 1 once the sequence has gone round, until the
+        // sender has been quiet long enough to start again
+        for sequence in [8, 15, 1] {
+            late.sequence = sequence;
+            receiver.handle(Packet { data: &[sequence; 3], ..late }, other, now, &mut sink).unwrap();
+        }
+        late.sequence = 9;
+        receiver.handle(Packet { data: &[9; 3], ..late }, other, now, &mut sink).unwrap();
+        assert_eq!(sink.pixels[0][0], 0x010101);
+        receiver.handle(Packet { data: &[9; 3], ..late }, other, now + SOURCE_TIMEOUT, &mut sink).unwrap();
+        assert_eq!(sink.pixels[0][0], 0x090909);
+
+        let query = Packet { query: true, id: ID_CONFIG, ..Packet::data(0, &[], false) };
+        sender.send_to(&query.to_bytes(), receiver.local_addr().unwrap()).unwrap();
+        receiver.recv(&mut sink).unwrap();
+        let mut buf = [0; 1024];
+        let len = sender.recv(&mut buf).unwrap();
+        let reply = Packet::parse(&buf[..len]).unwrap();
+        assert!(reply.reply && reply.id == ID_CONFIG);
+        let config: serde_json::Value = serde_json::from_slice(reply.data).unwrap();
+        assert_eq!(config["config"]["ports"][1]["ss"], 4);
+        assert_eq!(config["config"]["ip"], "127.0.0.1");
+    }
+}
@@ -0,0 +1,462 @@
+//! E1.31 (streaming ACN, sACN) receiver.
+//!
+//! Data packets are taken unicast or from the multicast group of each mapped
+//! universe. Each universe goes to the highest priority source sending it,
+//! the source that had it first keeps it on a tie, and a source that goes
+//! quiet for `SOURCE_TIMEOUT` or terminates its stream gives it up.
+//!
+//! Without synchronization a frame is presented once every mapped universe
+//! has been updated, or as soon as a universe comes in a second time before
+//! that. Data sent with a synchronization address is held until the sync
+//! packet for that address.
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use super::{in_sequence, PacketError, PixelSink, UniverseFrame, UniverseMap, UNIVERSE_SIZE};
+use crate::error::Result;
+
+pub const PORT: u16 = 5568;
+
+/// How long a source keeps its universes without sending, the network data
+/// loss timeout of the standard.
+pub const SOURCE_TIMEOUT: Duration = Duration::from_millis(2500);
+
+pub const DEFAULT_PRIORITY: u8 = 100;
+
+const ACN_IDENTIFIER: &[u8; 12] = b"ASC-E1.17\0\0\0";
+const VECTOR_ROOT_E131_DATA: u32 = 0x04;
+const VECTOR_ROOT_E131_EXTENDED: u32 = 0x08;
+const VECTOR_E131_DATA_PACKET: u32 = 0x02;
+const VECTOR_E131_EXTENDED_SYNCHRONIZATION: u32 = 0x01;
+const VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+
+const OPTION_PREVIEW: u8 = 0x80;
+const OPTION_TERMINATED: u8 = 0x40;
+
+// offset of the framing layer and the first slot of a data packet
+const FRAMING_OFFSET: usize = 38;
+const DMP_OFFSET: usize = 115;
+const DATA_OFFSET: usize = 126;
+const SYNC_LEN: usize = 49;
+
+/// Multicast group a universe is sent to.
+pub fn multicast_addr(universe: u16) -> Ipv4Addr {
+    let [hi, lo] = universe.to_be_bytes();
+    Ipv4Addr::new(239, 255, hi, lo)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DataPacket<'a> {
+    /// Component identifier, unique per source.
+    pub cid: [u8; 16],
+    pub source_name: &'a str,
+    pub priority: u8,
+    /// Universe of the sync packets this data waits for, 0 if it doesn't.
+    pub sync_address: u16,
+    pub sequence: u8,
+    pub preview: bool,
+    pub terminated: bool,
+    pub universe: u16,
+    /// 0 for DMX levels.
+    pub start_code: u8,
+    pub slots: &'a [u8],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SyncPacket {
+    pub cid: [u8; 16],
+    pub sequence: u8,
+    pub sync_address: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Packet<'a> {
+    Data(DataPacket<'a>),
+    Sync(SyncPacket),
+}
+
+impl<'a> Packet<'a> {
+    pub fn parse(buf: &'a [u8]) -> std::result::Result<Self, PacketError> {
+        if buf.len() < SYNC_LEN {
+            return Err(PacketError::Truncated(buf.len()));
+        }
+        if buf[0..4] != [0x00, 0x10, 0x00, 0x00] || &buf[4..16] != ACN_IDENTIFIER {
+            return Err(PacketError::InvalidHeader("E1.31"));
+        }
+        check_pdu_length(buf, 16)?;
+        check_pdu_length(buf, FRAMING_OFFSET)?;
+
+        let cid = buf[22..38].try_into().unwrap();
+        let framing_vector = u32_at(buf, 40);
+        match (u32_at(buf, 18), framing_vector) {
+            (VECTOR_ROOT_E131_DATA, VECTOR_E131_DATA_PACKET) => {}
+            (VECTOR_ROOT_E131_EXTENDED, VECTOR_E131_EXTENDED_SYNCHRONIZATION) => {
+                return Ok(Packet::Sync(SyncPacket {
+                    cid,
+                    sequence: buf[44],
+                    sync_address: u16_at(buf, 45),
+                }));
+            }
+            (root, _) => {
+                return Err(PacketError::Unsupported {
+                    protocol: "E1.31",
+                    kind: (root << 8) | framing_vector,
+                })
+            }
+        }
+
+        if buf.len() < DATA_OFFSET {
+            return Err(PacketError::Truncated(buf.len()));
+        }
+        check_pdu_length(buf, DMP_OFFSET)?;
+        if buf[117] != VECTOR_DMP_SET_PROPERTY || buf[118] != 0xA1 {
+            return Err(PacketError::Unsupported { protocol: "E1.31 DMP", kind: buf[117] as u32 });
+        }
+        // the property count includes the start code
+        let count = u16_at(buf, 123) as usize;
+        if count == 0 || count > UNIVERSE_SIZE + 1 || DATA_OFFSET - 1 + count != buf.len() {
+            return Err(PacketError::InvalidLength);
+        }
+
+        let name = &buf[44..108];
+        let name = &name[..name.iter().position(|&c| c == 0).unwrap_or(name.len())];
+        Ok(Packet::Data(DataPacket {
+            cid,
+            source_name: std::str::from_utf8(name).unwrap_or(""),
+            priority: buf[108],
+            sync_address: u16_at(buf, 109),
+            sequence: buf[111],
+            preview: buf[112] & OPTION_PREVIEW != 0,
+            terminated: buf[112] & OPTION_TERMINATED != 0,
+            universe: u16_at(buf, 113),
+            start_code: buf[125],
+            slots: &buf[DATA_OFFSET..],
+        }))
+    }
+}
+
+impl DataPacket<'_> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let len = DATA_OFFSET + self.slots.len();
+        let mut buf = Vec::with_capacity(len);
+        buf.extend_from_slice(&[0x00, 0x10, 0x00, 0x00]);
+        buf.extend_from_slice(ACN_IDENTIFIER);
+        buf.extend_from_slice(&pdu_length(len - 16));
+        buf.extend_from_slice(&VECTOR_ROOT_E131_DATA.to_be_bytes());
+        buf.extend_from_slice(&self.cid);
+
+        buf.extend_from_slice(&pdu_length(len - FRAMING_OFFSET));
+        buf.extend_from_slice(&VECTOR_E131_DATA_PACKET.to_be_bytes());
+        let mut name = [0; 64];
+        let source_name = &self.source_name.as_bytes()[..self.source_name.len().min(63)];
+        name[..source_name.len()].copy_from_slice(source_name);
+        buf.extend_from_slice(&name);
+        buf.push(self.priority);
+        buf.extend_from_slice(&self.sync_address.to_be_bytes());
+        buf.push(self.sequence);
+        let preview = if self.preview { OPTION_PREVIEW } else { 0 };
+        buf.push(preview | if self.terminated { OPTION_TERMINATED } else { 0 });
+        buf.extend_from_slice(&self.universe.to_be_bytes());
+
+        buf.extend_from_slice(&pdu_length(len - DMP_OFFSET));
+        buf.extend_from_slice(&[VECTOR_DMP_SET_PROPERTY, 0xA1, 0x00, 0x00, 0x00, 0x01]);
+        buf.extend_from_slice(&(self.slots.len() as u16 + 1).to_be_bytes());
+        buf.push(self.start_code);
+        buf.extend_from_slice(self.slots);
+        buf
+    }
+}
+
+impl SyncPacket {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(SYNC_LEN);
+        buf.extend_from_slice(&[0x00, 0x10, 0x00, 0x00]);
+        buf.extend_from_slice(ACN_IDENTIFIER);
+        buf.extend_from_slice(&pdu_length(SYNC_LEN - 16));
+        buf.extend_from_slice(&VECTOR_ROOT_E131_EXTENDED.to_be_bytes());
+        buf.extend_from_slice(&self.cid);
+        buf.extend_from_slice(&pdu_length(SYNC_LEN - FRAMING_OFFSET));
+        buf.extend_from_slice(&VECTOR_E131_EXTENDED_SYNCHRONIZATION.to_be_bytes());
+        buf.push(self.sequence);
+        buf.extend_from_slice(&self.sync_address.to_be_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        buf
+    }
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+// flags and length of a PDU running to the end of the packet
+fn pdu_length(len: usize) -> [u8; 2] {
+    (0x7000 | len as u16).to_be_bytes()
+}
+
+fn check_pdu_length(buf: &[u8], offset: usize) -> std::result::Result<(), PacketError> {
+    match u16_at(buf, offset) & 0x0FFF {
+        len if len as usize == buf.len() - offset => Ok(()),
+        _ => Err(PacketError::InvalidLength),
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Owner {
+    cid: [u8; 16],
+    priority: u8,
+    last_seen: Instant,
+}
+
+/// Listens for E1.31 and writes the universes in its `UniverseMap` to a
+/// `PixelSink`.
+pub struct E131Receiver {
+    socket: UdpSocket,
+    map: UniverseMap,
+    universes: Vec<u16>,
+    owners: HashMap<u16, Owner>,
+    sequences: HashMap<([u8; 16], u16), u8>,
+    // universes updated without synchronization
+    frame: UniverseFrame,
+    // synchronization address of data waiting for a sync packet
+    sync_pending: Option<u16>,
+    // the latest slots of every universe waiting for it
+    synced: Vec<(u16, Vec<u8>)>,
+}
+
+impl E131Receiver {
+    /// Listens on `addr`, usually `(Ipv4Addr::UNSPECIFIED, PORT)`.
+    pub fn bind(addr: impl ToSocketAddrs, map: UniverseMap) -> Result<Self> {
+        Ok(E131Receiver {
+            socket: UdpSocket::bind(addr)?,
+            universes: map.universes(),
+            frame: UniverseFrame::new(&map),
+            map,
+            owners: HashMap::new(),
+            sequences: HashMap::new(),
+            sync_pending: None,
+            synced: Vec::new(),
+        })
+    }
+
+    /// Joins the multicast group of every mapped universe on `interface`,
+    /// `Ipv4Addr::UNSPECIFIED` lets the kernel pick one.
+    pub fn join_multicast(&self, interface: Ipv4Addr) -> Result<()> {
+        for &universe in self.universes.iter() {
+            self.socket.join_multicast_v4(&multicast_addr(universe), &interface)?;
+        }
+        Ok(())
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Makes `recv` fail with `io::ErrorKind::WouldBlock` instead of waiting
+    /// when no packet has come in.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        Ok(self.socket.set_nonblocking(nonblocking)?)
+    }
+
+    pub fn map(&self) -> &UniverseMap {
+        &self.map
+    }
+
+    /// Waits for a packet and handles it, returns true if it completed a
+    /// frame.
+    pub fn recv(&mut self, sink: &mut impl PixelSink) -> Result<bool> {
+        let mut buf = [0; DATA_OFFSET + UNIVERSE_SIZE];
+        let (len, from) = self.socket.recv_from(&mut buf)?;
+        match Packet::parse(&buf[..len]) {
+            Ok(packet) => self.handle(packet, Instant::now(), sink),
+            Err(err) => {
+                debug!("E1.31: dropped packet from {}: {}", from, err);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Handles packets until receiving or presenting fails.
+    pub fn run(&mut self, sink: &mut impl PixelSink) -> Result<()> {
+        loop {
+            self.recv(sink)?;
+        }
+    }
+
+    /// Handles a packet received at `now`, returns true if it completed a
+    /// frame.
+    pub fn handle(&mut self, packet: Packet, now: Instant, sink: &mut impl PixelSink) -> Result<bool> {
+        match packet {
+            Packet::Sync(sync) => {
+                if self.sync_pending != Some(sync.sync_address) {
+                    return Ok(false);
+                }
+                self.sync_pending = None;
+                for (universe, slots) in self.synced.iter() {
+                    self.map.write(*universe, slots, sink);
+                }
+                self.synced.clear();
+                self.frame.present(sink)?;
+                Ok(true)
+            }
+            Packet::Data(data) => self.handle_data(data, now, sink),
+        }
+    }
+
+    fn handle_data(&mut self, data: DataPacket, now: Instant, sink: &mut impl PixelSink) -> Result<bool> {
+        if data.preview || data.start_code != 0 || !self.universes.contains(&data.universe) {
+            return Ok(false);
+        }
+
+        let key = (data.cid, data.universe);
+        if let Some(&last) = self.sequences.get(&key) {
+            if !in_sequence(last, data.sequence) {
+                debug!("E1.31: universe {} out of sequence ({} after {})", data.universe, data.sequence, last);
+                return Ok(false);
+            }
+        }
+        self.sequences.insert(key, data.sequence);
+
+        if !self.take_universe(&data, now) {
+            return Ok(false);
+        }
+        if data.terminated {
+            self.owners.remove(&data.universe);
+            self.sequences.remove(&key);
+            return Ok(false);
+        }
+
+        // synchronized data is held back until its sync packet, so frames
+        // presented in the meantime don't show half of it
+        if data.sync_address != 0 {
+            match self.synced.iter_mut().find(|(universe, _)| *universe == data.universe) {
+                Some((_, slots)) => {
+                    slots.clear();
+                    slots.extend_from_slice(data.slots);
+                }
+                None => self.synced.push((data.universe, data.slots.to_vec())),
+            }
+            self.sync_pending = Some(data.sync_address);
+            return Ok(false);
+        }
+
+        self.frame.write(&self.map, data.universe, data.slots, sink)
+    }
+
+    // whether the source of `data` has (or now gets) the universe
+    fn take_universe(&mut self, data: &DataPacket, now: Instant) -> bool {
+        let owner = self.owners.get(&data.universe);
+        let takes = match owner {
+            Some(owner) if owner.cid == data.cid => true,
+            Some(owner) if now.duration_since(owner.last_seen) < SOURCE_TIMEOUT => data.priority > owner.priority,
+            _ => true,
+        };
+        if takes {
+            self.owners.insert(data.universe, Owner { cid: data.cid, priority: data.priority, last_seen: now });
+        }
+        takes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::PixelBuffer;
+
+    fn data(cid: u8, universe: u16, sequence: u8, slots: &[u8]) -> DataPacket<'_> {
+        DataPacket {
+            cid: [cid; 16],
+            source_name: "test",
+            priority: DEFAULT_PRIORITY,
+            sync_address: 0,
+            sequence,
+            preview: false,
+            terminated: false,
+            universe,
+            start_code: 0,
+            slots,
+        }
+    }
+
+    #[test]
+    fn parses_data_and_sync_packets() {
+        let packet = data(1, 7, 42, &[1, 2, 3]);
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes.len(), 129);
+        assert_eq!(Packet::parse(&bytes), Ok(Packet::Data(packet)));
+
+        let sync = SyncPacket { cid: [2; 16], sequence: 3, sync_address: 9 };
+        assert_eq!(Packet::parse(&sync.to_bytes()), Ok(Packet::Sync(sync)));
+
+        assert_eq!(Packet::parse(&bytes[..100]), Err(PacketError::InvalidLength));
+        let mut bad = bytes.clone();
+        bad[4] = b'X';
+        assert_eq!(Packet::parse(&bad), Err(PacketError::InvalidHeader("E1.31")));
+        assert_eq!(multicast_addr(0x0102), Ipv4Addr::new(239, 255, 1, 2));
+    }
+
+    #[test]
+    fn merges_sources_by_priority_and_sequence() {
+        let mut receiver = E131Receiver::bind("127.0.0.1:0", UniverseMap::contiguous(1, 2, 1, 3).unwrap()).unwrap();
+        let mut sink = PixelBuffer::new(2, 1);
+        let now = Instant::now();
+
+        assert!(!receiver.handle(Packet::Data(data(1, 1, 10, &[1, 1, 1])), now, &mut sink).unwrap());
+        assert!(receiver.handle(Packet::Data(data(1, 2, 10, &[2, 2, 2])), now, &mut sink).unwrap());
+        assert_eq!(sink.pixels, vec![vec![0x010101], vec![0x020202]]);
+
+        // out of order, and a second source at the same priority
+        receiver.handle(Packet::Data(data(1, 1, 9, &[9, 9, 9])), now, &mut sink).unwrap();
+        receiver.handle(Packet::Data(data(2, 1, 0, &[8, 8, 8])), now, &mut sink).unwrap();
+        assert_eq!(sink.pixels[0][0], 0x010101);
+
+        // a higher priority takes over, until it goes quiet
+        let mut high = data(2, 1, 1, &[3, 3, 3]);
+        high.priority = 150;
+        receiver.handle(Packet::Data(high), now, &mut sink).unwrap();
+        assert_eq!(sink.pixels[0][0], 0x030303);
+        receiver.handle(Packet::Data(data(1, 1, 11, &[4, 4, 4])), now, &mut sink).unwrap();
+        assert_eq!(sink.pixels[0][0], 0x030303);
+        let later = now + SOURCE_TIMEOUT;
+        receiver.handle(Packet::Data(data(1, 1, 12, &[5, 5, 5])), later, &mut sink).unwrap();
+        assert_eq!(sink.pixels[0][0], 0x050505);
+
+        // a sync starts the next frame from scratch
+        let mut synced = data(1, 2, 11, &[6, 6, 6]);
+        synced.sync_address = 7;
+        receiver.handle(Packet::Data(synced), later, &mut sink).unwrap();
+        assert_eq!(sink.pixels[1][0], 0x020202);
+        let sync = SyncPacket { cid: [1; 16], sequence: 0, sync_address: 7 };
+        assert!(receiver.handle(Packet::Sync(sync), later, &mut sink).unwrap());
+        assert_eq!(sink.pixels[1][0], 0x060606);
+        assert!(!receiver.handle(Packet::Data(data(1, 2, 12, &[7, 7, 7])), later, &mut sink).unwrap());
+    }
+
+    #[test]
+    fn receives_over_loopback_and_waits_for_sync() {
+        let mut receiver = E131Receiver::bind("127.0.0.1:0", UniverseMap::contiguous(1, 1, 170, 3).unwrap()).unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let to = receiver.local_addr().unwrap();
+        let mut sink = PixelBuffer::new(1, 170);
+
+        let mut packet = data(1, 1, 0, &[0xFF; 510]);
+        packet.sync_address = 100;
+        sender.send_to(&packet.to_bytes(), to).unwrap();
+        assert!(!receiver.recv(&mut sink).unwrap());
+        assert_eq!(sink.frames, 0);
+        assert!(sink.pixels[0].iter().all(|&c| c == 0));
+
+        let sync = SyncPacket { cid: [1; 16], sequence: 0, sync_address: 100 };
+        sender.send_to(&sync.to_bytes(), to).unwrap();
+        assert!(receiver.recv(&mut sink).unwrap());
+        assert_eq!(sink.frames, 1);
+        assert!(sink.pixels[0].iter().all(|&c| c == 0xFFFFFF));
+    }
+}
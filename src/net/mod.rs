@@ -0,0 +1,419 @@
+//! Network protocols that feed frames to the strips.
+//!
+//! Every receiver writes the pixels it gets into a `PixelSink`, normally the
+//! `LedDriver`, and calls `present` on it once a whole frame has arrived.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+
+use thiserror::Error;
+
+use crate::color::ColorCorrection;
+use crate::driver::LedDriver;
+use crate::error::Result;
+use crate::hal::Backend;
+use crate::mapping::PixelMap;
+
+pub mod artnet;
+pub mod ddp;
+pub mod e131;
+pub mod opc;
+pub mod tpm2;
+pub mod wled;
+
+/// Number of slots in a DMX universe.
+pub const UNIVERSE_SIZE: usize = 512;
+
+/// Why a received packet was dropped, receivers log these rather than fail.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PacketError {
+    #[error("packet of {0} bytes is too short")]
+    Truncated(usize),
+    #[error("not a {0} packet")]
+    InvalidHeader(&'static str),
+    #[error("unsupported {protocol} packet type {kind:#x}")]
+    Unsupported { protocol: &'static str, kind: u32 },
+    #[error("length fields don't match the packet")]
+    InvalidLength,
+}
+
+/// Why a receiver couldn't be set up.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum NetError {
+    #[error("invalid number of components {0}, LEDs take 3 or 4")]
+    InvalidComponents(usize),
+    #[error("universes from {0} on run past the last universe")]
+    UniverseOverflow(u16),
+    #[error("a WLED node needs at least one effect")]
+    NoWledEffects,
+}
+
+/// Where the receivers put the pixels they get.
+pub trait PixelSink {
+    fn channels(&self) -> usize;
+
+    fn leds_per_channel(&self) -> usize;
+
+    /// Sets an LED as `0xWWRRGGBB`.
+    fn set_pixel(&mut self, channel: usize, index: usize, color: u32);
+
+    /// Sends out the pixels set so far as a frame.
+    fn present(&mut self) -> Result<()>;
+
+    /// The colour correction of a channel, none for sinks that don't
+    /// correct colours.
+    fn color_correction(&self, _channel: usize) -> ColorCorrection {
+        ColorCorrection::default()
+    }
+
+    /// Changes the colour correction of a channel, sinks that don't correct
+    /// colours ignore it.
+    fn set_color_correction(&mut self, _channel: usize, _correction: ColorCorrection) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<B: Backend> PixelSink for LedDriver<B> {
+    fn channels(&self) -> usize {
+        self.nchans()
+    }
+
+    fn leds_per_channel(&self) -> usize {
+        self.nleds()
+    }
+
+    fn set_pixel(&mut self, channel: usize, index: usize, color: u32) {
+        LedDriver::set_pixel(self, channel, index, color)
+    }
+
+    fn present(&mut self) -> Result<()> {
+        LedDriver::present(self)
+    }
+
+    fn color_correction(&self, channel: usize) -> ColorCorrection {
+        self.config().color_correction(channel)
+    }
+
+    fn set_color_correction(&mut self, channel: usize, correction: ColorCorrection) -> Result<()> {
+        LedDriver::set_color_correction(self, channel, correction)
+    }
+}
+
+/// Pixels kept in memory, for tests and for anything that wants to look at
+/// the frames before they go out.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PixelBuffer {
+    pub pixels: Vec<Vec<u32>>,
+    /// Number of frames presented.
+    pub frames: usize,
+    pub corrections: Vec<ColorCorrection>,
+}
+
+impl PixelBuffer {
+    pub fn new(nchans: usize, nleds: usize) -> Self {
+        PixelBuffer {
+            pixels: vec![vec![0; nleds]; nchans],
+            frames: 0,
+            corrections: vec![ColorCorrection::default(); nchans],
+        }
+    }
+}
+
+impl PixelSink for PixelBuffer {
+    fn channels(&self) -> usize {
+        self.pixels.len()
+    }
+
+    fn leds_per_channel(&self) -> usize {
+        self.pixels.first().map_or(0, |leds| leds.len())
+    }
+
+    fn set_pixel(&mut self, channel: usize, index: usize, color: u32) {
+        self.pixels[channel][index] = color;
+    }
+
+    fn present(&mut self) -> Result<()> {
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn color_correction(&self, channel: usize) -> ColorCorrection {
+        self.corrections[channel]
+    }
+
+    fn set_color_correction(&mut self, channel: usize, correction: ColorCorrection) -> Result<()> {
+        self.corrections[channel] = correction;
+        Ok(())
+    }
+}
+
+/// LEDs of a channel driven by a universe, from its first slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UniverseRange {
+    pub universe: u16,
+    pub channel: usize,
+    /// Index of the LED the first slot goes to.
+    pub start: usize,
+    pub leds: usize,
+}
+
+/// Mapping of DMX universes onto channels.
+///
+/// Every LED takes `components` consecutive slots, red, green, blue and
+/// then white if there are 4.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UniverseMap {
+    ranges: Vec<UniverseRange>,
+    components: usize,
+}
+
+impl UniverseMap {
+    pub fn new(components: usize) -> Result<Self> {
+        check_components(components)?;
+        Ok(UniverseMap {
+            ranges: Vec::new(),
+            components,
+        })
+    }
+
+    /// Every channel starts on a universe of its own and takes as many
+    /// consecutive universes as its LEDs need, from `first_universe` on.
+    pub fn contiguous(first_universe: u16, nchans: usize, nleds: usize, components: usize) -> Result<Self> {
+        let mut map = UniverseMap::new(components)?;
+        let per_universe = map.leds_per_universe();
+        let mut universe = Some(first_universe);
+        for channel in 0..nchans {
+            for start in (0..nleds).step_by(per_universe) {
+                let this = universe.ok_or(NetError::UniverseOverflow(first_universe))?;
+                map = map.range(this, channel, start, per_universe.min(nleds - start));
+                universe = this.checked_add(1);
+            }
+        }
+        Ok(map)
+    }
+
+    pub fn range(mut self, universe: u16, channel: usize, start: usize, leds: usize) -> Self {
+        self.ranges.push(UniverseRange { universe, channel, start, leds });
+        self
+    }
+
+    pub fn components(&self) -> usize {
+        self.components
+    }
+
+    /// Number of whole LEDs that fit in a universe.
+    pub fn leds_per_universe(&self) -> usize {
+        UNIVERSE_SIZE / self.components
+    }
+
+    pub fn ranges(&self) -> &[UniverseRange] {
+        &self.ranges
+    }
+
+    /// Every mapped universe, once each.
+    pub fn universes(&self) -> Vec<u16> {
+        let mut universes = self.ranges.iter().map(|range| range.universe).collect::<Vec<_>>();
+        universes.sort();
+        universes.dedup();
+        universes
+    }
+
+    pub fn contains(&self, universe: u16) -> bool {
+        self.ranges.iter().any(|range| range.universe == universe)
+    }
+
+    /// Writes the slots of a universe to the LEDs it's mapped to, LEDs that
+    /// don't exist in `sink` are skipped. Returns false for a universe that
+    /// isn't mapped.
+    pub fn write(&self, universe: u16, slots: &[u8], sink: &mut impl PixelSink) -> bool {
+        let (nchans, nleds) = (sink.channels(), sink.leds_per_channel());
+        let mut mapped = false;
+        for range in self.ranges.iter().filter(|range| range.universe == universe) {
+            mapped = true;
+            if range.channel >= nchans {
+                continue;
+            }
+            for (n, led) in slots.chunks_exact(self.components).take(range.leds).enumerate() {
+                if range.start + n < nleds {
+                    sink.set_pixel(range.channel, range.start + n, color(led));
+                }
+            }
+        }
+        mapped
+    }
+}
+
+// LEDs sent as one run of bytes, `components` bytes each, in channel order
+// or in the order of a pixel map
+#[derive(Clone, Debug)]
+pub(crate) struct LinearDisplay {
+    components: usize,
+    pub(crate) map: Option<PixelMap>,
+    // the display as sent, LEDs are updated from this so writes don't have
+    // to start or end on an LED
+    bytes: Vec<u8>,
+}
+
+impl LinearDisplay {
+    pub(crate) fn new(components: usize) -> Result<Self> {
+        check_components(components)?;
+        Ok(LinearDisplay {
+            components,
+            map: None,
+            bytes: Vec::new(),
+        })
+    }
+
+    pub(crate) fn write(&mut self, offset: usize, data: &[u8], sink: &mut impl PixelSink) {
+        let size = match &self.map {
+            Some(map) => map.len(),
+            None => sink.channels() * sink.leds_per_channel(),
+        } * self.components;
+        if offset >= size {
+            return;
+        }
+        let end = size.min(offset + data.len());
+        if self.bytes.len() < size {
+            self.bytes.resize(size, 0);
+        }
+        self.bytes[offset..end].copy_from_slice(&data[..end - offset]);
+
+        for n in offset / self.components..end.div_ceil(self.components) {
+            let led = &self.bytes[n * self.components..(n + 1) * self.components];
+            self.set_led(n, color(led), sink);
+        }
+    }
+
+    // sets LED `n` of the display, LEDs that don't exist are skipped
+    pub(crate) fn set_led(&self, n: usize, color: u32, sink: &mut impl PixelSink) {
+        let (nchans, nleds) = (sink.channels(), sink.leds_per_channel());
+        let (channel, index) = match &self.map {
+            Some(map) if n < map.len() => (map.leds()[n].channel, map.leds()[n].index),
+            Some(_) => return,
+            None => (n / nleds.max(1), n % nleds.max(1)),
+        };
+        if channel < nchans && index < nleds {
+            sink.set_pixel(channel, index, color);
+        }
+    }
+}
+
+// universes updated since the last frame of a receiver
+#[derive(Clone, Debug)]
+pub(crate) struct UniverseFrame {
+    universes: Vec<u16>,
+    pending: Vec<u16>,
+}
+
+impl UniverseFrame {
+    pub(crate) fn new(map: &UniverseMap) -> Self {
+        UniverseFrame {
+            universes: map.universes(),
+            pending: Vec::new(),
+        }
+    }
+
+    // writes a universe and presents the frame once every mapped universe
+    // has been updated, returns true if a frame was presented
+    pub(crate) fn write(
+        &mut self,
+        map: &UniverseMap,
+        universe: u16,
+        slots: &[u8],
+        sink: &mut impl PixelSink,
+    ) -> Result<bool> {
+        if !self.universes.contains(&universe) {
+            return Ok(false);
+        }
+
+        // the sender has started on the next frame without sending all of
+        // this one
+        let mut presented = false;
+        if self.pending.contains(&universe) {
+            self.present(sink)?;
+            presented = true;
+        }
+        map.write(universe, slots, sink);
+        self.pending.push(universe);
+        if self.pending.len() == self.universes.len() {
+            self.present(sink)?;
+            presented = true;
+        }
+        Ok(presented)
+    }
+
+    pub(crate) fn present(&mut self, sink: &mut impl PixelSink) -> Result<()> {
+        self.pending.clear();
+        sink.present()
+    }
+}
+
+fn check_components(components: usize) -> Result<()> {
+    match components {
+        3 | 4 => Ok(()),
+        _ => Err(NetError::InvalidComponents(components).into()),
+    }
+}
+
+// sequence numbers within this far behind the last one are out of order
+// rather than a restarted source
+pub(crate) fn in_sequence(last: u8, sequence: u8) -> bool {
+    let diff = sequence.wrapping_sub(last) as i8;
+    diff > 0 || diff <= -20
+}
+
+// address of the interface packets from `socket` to `to` go out on
+pub(crate) fn interface_ip(socket: &UdpSocket, to: SocketAddr) -> Result<Ipv4Addr> {
+    if let IpAddr::V4(ip) = socket.local_addr()?.ip() {
+        if !ip.is_unspecified() {
+            return Ok(ip);
+        }
+    }
+    let probe = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    probe.connect(to)?;
+    Ok(match probe.local_addr()?.ip() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+    })
+}
+
+// wakes up a thread blocked accepting connections on `addr` by connecting
+// to it, returns false if the listener couldn't be reached
+pub(crate) fn wake_listener(mut addr: SocketAddr) -> bool {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+        IpAddr::V6(ip) if ip.is_unspecified() => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+        _ => {}
+    }
+    TcpStream::connect(addr).is_ok()
+}
+
+/// `0xWWRRGGBB` of an LED sent as red, green, blue (and white).
+pub fn color(slots: &[u8]) -> u32 {
+    let w = slots.get(3).copied().unwrap_or(0);
+    u32::from_be_bytes([w, slots[0], slots[1], slots[2]])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn maps_universes_onto_channels() {
+        let map = UniverseMap::contiguous(1, 2, 200, 3).unwrap();
+        assert_eq!(map.universes(), vec![1, 2, 3, 4]);
+        assert_eq!(map.ranges()[1], UniverseRange { universe: 2, channel: 0, start: 170, leds: 30 });
+
+        let mut sink = PixelBuffer::new(2, 200);
+        assert!(map.write(4, &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66], &mut sink));
+        assert_eq!(sink.pixels[1][170..172], [0x112233, 0x445566]);
+        assert!(!map.write(5, &[0xFF; 3], &mut sink));
+
+        let rgbw = UniverseMap::new(4).unwrap().range(0, 0, 0, 1);
+        rgbw.write(0, &[1, 2, 3, 4, 5], &mut sink);
+        assert_eq!(sink.pixels[0][0], 0x04010203);
+
+        assert!(matches!(UniverseMap::new(5), Err(crate::Error::Net(NetError::InvalidComponents(5)))));
+        assert!(UniverseMap::contiguous(65534, 2, 1, 3).is_ok());
+        assert!(matches!(UniverseMap::contiguous(65534, 3, 1, 3), Err(crate::Error::Net(NetError::UniverseOverflow(65534)))));
+    }
+}
@@ -0,0 +1,339 @@
+//! Open Pixel Control server.
+//!
+//! Every client connection gets a thread of its own reading messages, which
+//! are handed to the thread calling `recv` or `run` so only that one touches
+//! the `PixelSink`. A client sending faster than its messages are handled
+//! is held back by TCP flow control, nothing is dropped. Channel 0 sets the LEDs of all channels, one after the other, and
+//! channel `n` the LEDs of channel `n - 1`.
+//!
+//! Of the FadeCandy system exclusive messages only the colour correction is
+//! taken, its gamma and white point replace those of every channel while the
+//! gains (and so the brightness) set up on the sink are kept. There's no
+//! linear section at the bottom of the curve.
+
+use std::collections::HashMap;
+use std::io::{self, BufReader, Read};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use log::debug;
+use serde::Deserialize;
+
+use super::{color, wake_listener, PixelSink};
+use crate::error::Result;
+
+pub const PORT: u16 = 7890;
+
+pub const SET_PIXELS: u8 = 0;
+pub const SYSTEM_EXCLUSIVE: u8 = 255;
+
+const FADECANDY_SYSTEM_ID: u16 = 0x0001;
+const FADECANDY_COLOR_CORRECTION: u16 = 0x0001;
+
+// messages waiting to be handled before clients have to wait
+const QUEUE_LEN: usize = 16;
+
+// open client connections, shut down with the server
+type Clients = Arc<Mutex<HashMap<SocketAddr, TcpStream>>>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub channel: u8,
+    pub command: u8,
+    pub data: Vec<u8>,
+}
+
+impl Message {
+    /// Reads the next message, `None` once the stream is closed between
+    /// messages.
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Option<Message>> {
+        let mut header = [0; 4];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let mut data = vec![0; u16::from_be_bytes([header[2], header[3]]) as usize];
+        reader.read_exact(&mut data)?;
+        Ok(Some(Message {
+            channel: header[0],
+            command: header[1],
+            data,
+        }))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![self.channel, self.command];
+        buf.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+}
+
+// the FadeCandy colour correction message
+#[derive(Deserialize)]
+#[serde(default)]
+struct FadecandyCorrection {
+    gamma: f32,
+    whitepoint: [f32; 3],
+}
+
+impl Default for FadecandyCorrection {
+    fn default() -> Self {
+        FadecandyCorrection {
+            gamma: 1.0,
+            whitepoint: [1.0; 3],
+        }
+    }
+}
+
+/// Listens for OPC clients and writes the pixels they send to a
+/// `PixelSink`.
+pub struct OpcServer {
+    local_addr: SocketAddr,
+    messages: Receiver<(SocketAddr, Message)>,
+    stopping: Arc<AtomicBool>,
+    clients: Clients,
+    listener: Option<JoinHandle<()>>,
+}
+
+impl OpcServer {
+    /// Listens on `addr`, usually `(Ipv4Addr::UNSPECIFIED, PORT)`.
+    ///
+    /// Dropping the server stops listening and disconnects every client.
+    pub fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let (sender, messages) = mpsc::sync_channel(QUEUE_LEN);
+        let stopping = Arc::new(AtomicBool::new(false));
+        let clients = Clients::default();
+        let listener = thread::spawn({
+            let (stopping, clients) = (stopping.clone(), clients.clone());
+            move || accept(listener, sender, &stopping, &clients)
+        });
+        Ok(OpcServer {
+            local_addr,
+            messages,
+            stopping,
+            clients,
+            listener: Some(listener),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Waits for a message and handles it along with any others that have
+    /// come in since, then presents the frame once if any of them set
+    /// pixels. Returns true if it presented a frame.
+    pub fn recv(&mut self, sink: &mut impl PixelSink) -> Result<bool> {
+        let (from, message) = self
+            .messages
+            .recv()
+            .map_err(|_| io::Error::other("OPC listener stopped"))?;
+
+        let changed = self.handle(&message, from, sink)?;
+        self.handle_queued(changed, sink)
+    }
+
+    /// Handles the messages that have come in without waiting for one, then
+    /// presents the frame once if any of them set pixels. Returns true if it
+    /// presented a frame.
+    pub fn try_recv(&mut self, sink: &mut impl PixelSink) -> Result<bool> {
+        self.handle_queued(false, sink)
+    }
+
+    /// Handles messages until presenting fails.
+    pub fn run(&mut self, sink: &mut impl PixelSink) -> Result<()> {
+        loop {
+            self.recv(sink)?;
+        }
+    }
+
+    fn handle_queued(&mut self, mut changed: bool, sink: &mut impl PixelSink) -> Result<bool> {
+        for (from, message) in self.messages.try_iter() {
+            changed |= self.handle(&message, from, sink)?;
+        }
+        if changed {
+            sink.present()?;
+        }
+        Ok(changed)
+    }
+
+    /// Handles a message from `from` without presenting, returns true if it
+    /// set pixels.
+    pub fn handle(&self, message: &Message, from: SocketAddr, sink: &mut impl PixelSink) -> Result<bool> {
+        match message.command {
+            SET_PIXELS => Ok(set_pixels(message, sink)),
+            SYSTEM_EXCLUSIVE => {
+                if let Err(err) = system_exclusive(&message.data, sink) {
+                    debug!("OPC: dropped system exclusive message from {}: {}", from, err);
+                }
+                Ok(false)
+            }
+            command => {
+                debug!("OPC: unknown command {} from {}", command, from);
+                Ok(false)
+            }
+        }
+    }
+}
+
+impl Drop for OpcServer {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        if wake_listener(self.local_addr) {
+            if let Some(listener) = self.listener.take() {
+                let _ = listener.join();
+            }
+        }
+        for stream in self.clients.lock().unwrap().values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+fn accept(listener: TcpListener, sender: SyncSender<(SocketAddr, Message)>, stopping: &AtomicBool, clients: &Clients) {
+    for stream in listener.incoming() {
+        if stopping.load(Ordering::Relaxed) {
+            return;
+        }
+        let client = stream.and_then(|stream| Ok((stream.peer_addr()?, stream.try_clone()?, stream)));
+        match client {
+            Ok((from, clone, stream)) => {
+                clients.lock().unwrap().insert(from, clone);
+                let (sender, clients) = (sender.clone(), clients.clone());
+                thread::spawn(move || {
+                    read_client(stream, from, sender);
+                    clients.lock().unwrap().remove(&from);
+                });
+            }
+            Err(err) => debug!("OPC: accept failed: {}", err),
+        }
+    }
+}
+
+fn read_client(stream: TcpStream, from: SocketAddr, sender: SyncSender<(SocketAddr, Message)>) {
+    let mut reader = BufReader::new(stream);
+    loop {
+        match Message::read_from(&mut reader) {
+            Ok(Some(message)) => {
+                // blocks while the queue is full, so the client stops being
+                // read until there's room
+                if sender.send((from, message)).is_err() {
+                    return;
+                }
+            }
+            Ok(None) => return,
+            Err(err) => {
+                debug!("OPC: dropped client {}: {}", from, err);
+                return;
+            }
+        }
+    }
+}
+
+fn set_pixels(message: &Message, sink: &mut impl PixelSink) -> bool {
+    let (nchans, nleds) = (sink.channels(), sink.leds_per_channel());
+    let leds = message.data.chunks_exact(3);
+    match message.channel as usize {
+        0 => {
+            for (n, led) in leds.take(nchans * nleds).enumerate() {
+                sink.set_pixel(n / nleds, n % nleds, color(led));
+            }
+        }
+        channel if channel <= nchans => {
+            for (n, led) in leds.take(nleds).enumerate() {
+                sink.set_pixel(channel - 1, n, color(led));
+            }
+        }
+        _ => return false,
+    }
+    true
+}
+
+fn system_exclusive(data: &[u8], sink: &mut impl PixelSink) -> Result<()> {
+    if data.len() < 4 {
+        return Ok(());
+    }
+    let system = u16::from_be_bytes([data[0], data[1]]);
+    let command = u16::from_be_bytes([data[2], data[3]]);
+    if (system, command) != (FADECANDY_SYSTEM_ID, FADECANDY_COLOR_CORRECTION) {
+        return Ok(());
+    }
+
+    let fadecandy: FadecandyCorrection = serde_json::from_slice(&data[4..]).map_err(io::Error::from)?;
+    let [r, g, b] = fadecandy.whitepoint;
+    for channel in 0..sink.channels() {
+        let correction = sink.color_correction(channel).gamma(fadecandy.gamma).white_point(r, g, b);
+        sink.set_color_correction(channel, correction)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+    use crate::color::ColorCorrection;
+    use crate::net::PixelBuffer;
+
+    #[test]
+    fn takes_pixels_from_several_clients() {
+        let mut server = OpcServer::bind("127.0.0.1:0").unwrap();
+        let mut sink = PixelBuffer::new(2, 2);
+
+        let mut first = TcpStream::connect(server.local_addr()).unwrap();
+        let mut second = TcpStream::connect(server.local_addr()).unwrap();
+        let message = Message { channel: 2, command: SET_PIXELS, data: vec![1, 2, 3, 4, 5, 6, 7, 8, 9] };
+        second.write_all(&message.to_bytes()).unwrap();
+        assert!(server.recv(&mut sink).unwrap());
+        assert_eq!(sink.pixels[1], vec![0x010203, 0x040506]);
+
+        let message = Message { channel: 0, command: SET_PIXELS, data: vec![0xFF; 9] };
+        first.write_all(&message.to_bytes()).unwrap();
+        assert!(server.recv(&mut sink).unwrap());
+        assert_eq!(sink.pixels, vec![vec![0xFFFFFF; 2], vec![0xFFFFFF, 0x040506]]);
+        assert_eq!(sink.frames, 2);
+
+        // a client running ahead is held back rather than losing messages
+        for _ in 0..QUEUE_LEN * 4 {
+            first.write_all(&message.to_bytes()).unwrap();
+        }
+        let mut data = vec![0x00, 0x01, 0x00, 0x01];
+        data.extend_from_slice(br#"{ "gamma": 2.5 }"#);
+        first.write_all(&Message { channel: 0, command: SYSTEM_EXCLUSIVE, data }.to_bytes()).unwrap();
+        while sink.corrections[0].gamma != 2.5 {
+            server.recv(&mut sink).unwrap();
+        }
+
+        // dropping the server hangs up on the clients
+        drop(server);
+        assert_eq!(first.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn applies_fadecandy_color_correction() {
+        let server = OpcServer::bind("127.0.0.1:0").unwrap();
+        let mut sink = PixelBuffer::new(2, 2);
+        sink.corrections[1] = ColorCorrection::new().gamma(2.2).brightness(0.5);
+        let from = server.local_addr();
+
+        let mut data = vec![0x00, 0x01, 0x00, 0x01];
+        data.extend_from_slice(br#"{ "gamma": 2.5, "whitepoint": [1.0, 0.5, 0.25], "linearSlope": 1.0 }"#);
+        let message = Message { channel: 0, command: SYSTEM_EXCLUSIVE, data };
+        assert!(!server.handle(&message, from, &mut sink).unwrap());
+        // the gains set up on the sink stay
+        let expected = ColorCorrection::new().gamma(2.5).white_point(1.0, 0.5, 0.25);
+        assert_eq!(sink.corrections, vec![expected, expected.brightness(0.5)]);
+
+        let bytes = message.to_bytes();
+        assert_eq!(Message::read_from(&mut &bytes[..]).unwrap(), Some(message));
+        assert_eq!(Message::read_from(&mut &bytes[..0]).unwrap(), None);
+    }
+}
@@ -0,0 +1,287 @@
+//! TPM2 over UDP (TPM2.net) and over serial lines, pipes and the like.
+//!
+//! A frame is the display as one run of LEDs, `components` bytes each, in
+//! channel order or in the order of a `PixelMap`. TPM2.net splits frames that
+//! don't fit in a packet over several, numbered from 1, which are put back
+//! together before the frame is presented. A frame that isn't complete by the
+//! time a part of the next one comes in is dropped.
+//!
+//! Only data frames are taken, commands and responses are ignored.
+
+use std::io::{self, BufReader, Read};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+use log::debug;
+
+use super::{LinearDisplay, PacketError, PixelSink};
+use crate::error::Result;
+use crate::mapping::PixelMap;
+
+pub const PORT: u16 = 65506;
+
+pub const DATA: u8 = 0xDA;
+pub const COMMAND: u8 = 0xC0;
+pub const RESPONSE: u8 = 0xAA;
+
+const SERIAL_START: u8 = 0xC9;
+const NET_START: u8 = 0x9C;
+const END: u8 = 0x36;
+
+const NET_HEADER_LEN: usize = 6;
+const MAX_DATA_LEN: usize = 1490;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NetPacket<'a> {
+    pub kind: u8,
+    /// 1 to `count`.
+    pub number: u8,
+    pub count: u8,
+    pub data: &'a [u8],
+}
+
+impl<'a> NetPacket<'a> {
+    pub fn parse(buf: &'a [u8]) -> std::result::Result<Self, PacketError> {
+        if buf.len() < NET_HEADER_LEN + 1 {
+            return Err(PacketError::Truncated(buf.len()));
+        }
+        if buf[0] != NET_START {
+            return Err(PacketError::InvalidHeader("TPM2.net"));
+        }
+        let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        if buf.len() < NET_HEADER_LEN + len + 1 || buf[NET_HEADER_LEN + len] != END {
+            return Err(PacketError::InvalidLength);
+        }
+        Ok(NetPacket {
+            kind: buf[1],
+            number: buf[4],
+            count: buf[5],
+            data: &buf[NET_HEADER_LEN..NET_HEADER_LEN + len],
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![NET_START, self.kind];
+        buf.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&[self.number, self.count]);
+        buf.extend_from_slice(self.data);
+        buf.push(END);
+        buf
+    }
+}
+
+/// A frame read from a TPM2 stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: u8,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    /// Reads the next frame, skipping anything that isn't one. `None` once
+    /// the stream ends.
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Option<Frame>> {
+        let mut byte = [0; 1];
+        loop {
+            // find the start of a frame
+            loop {
+                match reader.read_exact(&mut byte) {
+                    Ok(()) if byte[0] == SERIAL_START => break,
+                    Ok(()) => {}
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(err) => return Err(err),
+                }
+            }
+
+            let mut header = [0; 3];
+            let mut frame = Frame { kind: 0, data: Vec::new() };
+            let read = reader.read_exact(&mut header).and_then(|()| {
+                frame.kind = header[0];
+                frame.data.resize(u16::from_be_bytes([header[1], header[2]]) as usize, 0);
+                reader.read_exact(&mut frame.data)?;
+                reader.read_exact(&mut byte)
+            });
+            match read {
+                Ok(()) if byte[0] == END => return Ok(Some(frame)),
+                Ok(()) => debug!("TPM2: dropped frame without an end byte"),
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![SERIAL_START, self.kind];
+        buf.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&self.data);
+        buf.push(END);
+        buf
+    }
+}
+
+/// Listens for TPM2.net and writes the frames to a `PixelSink`.
+pub struct Tpm2NetReceiver {
+    socket: UdpSocket,
+    display: LinearDisplay,
+    // the packets of the frame being put together
+    parts: Vec<Option<Vec<u8>>>,
+}
+
+impl Tpm2NetReceiver {
+    /// Listens on `addr`, usually `(Ipv4Addr::UNSPECIFIED, PORT)`, for LEDs
+    /// of 3 (RGB) or 4 (RGBW) bytes.
+    pub fn bind(addr: impl ToSocketAddrs, components: usize) -> Result<Self> {
+        Ok(Tpm2NetReceiver {
+            socket: UdpSocket::bind(addr)?,
+            display: LinearDisplay::new(components)?,
+            parts: Vec::new(),
+        })
+    }
+
+    /// Sends the LEDs of a frame to the pixels of `map` in order instead of
+    /// channel after channel.
+    pub fn pixel_map(mut self, map: PixelMap) -> Self {
+        self.display.map = Some(map);
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Waits for a packet and handles it, returns true if it completed a
+    /// frame.
+    pub fn recv(&mut self, sink: &mut impl PixelSink) -> Result<bool> {
+        let mut buf = [0; NET_HEADER_LEN + MAX_DATA_LEN + 1];
+        let (len, from) = self.socket.recv_from(&mut buf)?;
+        match NetPacket::parse(&buf[..len]) {
+            Ok(packet) => self.handle(packet, sink),
+            Err(err) => {
+                debug!("TPM2.net: dropped packet from {}: {}", from, err);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Handles packets until receiving or presenting fails.
+    pub fn run(&mut self, sink: &mut impl PixelSink) -> Result<()> {
+        loop {
+            self.recv(sink)?;
+        }
+    }
+
+    /// Handles a packet, returns true if it completed a frame.
+    pub fn handle(&mut self, packet: NetPacket, sink: &mut impl PixelSink) -> Result<bool> {
+        if packet.kind != DATA || packet.number == 0 || packet.number > packet.count {
+            return Ok(false);
+        }
+
+        let part = packet.number as usize - 1;
+        if self.parts.len() != packet.count as usize || self.parts[part].is_some() {
+            if self.parts.iter().any(|part| part.is_some()) {
+                debug!("TPM2.net: dropped incomplete frame");
+            }
+            self.parts = vec![None; packet.count as usize];
+        }
+        self.parts[part] = Some(packet.data.to_vec());
+        if self.parts.iter().any(|part| part.is_none()) {
+            return Ok(false);
+        }
+
+        let frame = self.parts.drain(..).flatten().flatten().collect::<Vec<_>>();
+        self.display.write(0, &frame, sink);
+        sink.present()?;
+        Ok(true)
+    }
+}
+
+/// Reads TPM2 frames from a serial line, pipe or pseudo-terminal and writes
+/// them to a `PixelSink`.
+pub struct Tpm2Stream<R: Read> {
+    reader: BufReader<R>,
+    display: LinearDisplay,
+}
+
+impl<R: Read> Tpm2Stream<R> {
+    /// Reads LEDs of 3 (RGB) or 4 (RGBW) bytes from `reader`, which should
+    /// already be set up (baud rate, raw mode) if it's a serial line.
+    pub fn new(reader: R, components: usize) -> Result<Self> {
+        Ok(Tpm2Stream {
+            reader: BufReader::new(reader),
+            display: LinearDisplay::new(components)?,
+        })
+    }
+
+    /// Sends the LEDs of a frame to the pixels of `map` in order instead of
+    /// channel after channel.
+    pub fn pixel_map(mut self, map: PixelMap) -> Self {
+        self.display.map = Some(map);
+        self
+    }
+
+    /// Reads up to the next data frame and presents it, returns false once
+    /// the stream ends.
+    pub fn recv(&mut self, sink: &mut impl PixelSink) -> Result<bool> {
+        while let Some(frame) = Frame::read_from(&mut self.reader)? {
+            if frame.kind == DATA {
+                self.display.write(0, &frame.data, sink);
+                sink.present()?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Presents frames until the stream ends or presenting fails.
+    pub fn run(&mut self, sink: &mut impl PixelSink) -> Result<()> {
+        while self.recv(sink)? {}
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::PixelBuffer;
+
+    #[test]
+    fn reassembles_net_packets() {
+        let mut receiver = Tpm2NetReceiver::bind("127.0.0.1:0", 3).unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let to = receiver.local_addr().unwrap();
+        let mut sink = PixelBuffer::new(2, 2);
+
+        let data = (1..=12).collect::<Vec<u8>>();
+        let second = NetPacket { kind: DATA, number: 2, count: 2, data: &data[6..] };
+        let first = NetPacket { kind: DATA, number: 1, count: 2, data: &data[..6] };
+        assert_eq!(NetPacket::parse(&first.to_bytes()), Ok(first));
+
+        // out of order, then a lone part of the next frame
+        sender.send_to(&second.to_bytes(), to).unwrap();
+        assert!(!receiver.recv(&mut sink).unwrap());
+        sender.send_to(&first.to_bytes(), to).unwrap();
+        assert!(receiver.recv(&mut sink).unwrap());
+        assert_eq!(sink.pixels, vec![vec![0x010203, 0x040506], vec![0x070809, 0x0A0B0C]]);
+
+        receiver.handle(NetPacket { data: &[0; 6], ..first }, &mut sink).unwrap();
+        assert!(!receiver.handle(NetPacket { data: &[0xFF; 6], ..first }, &mut sink).unwrap());
+        assert!(receiver.handle(second, &mut sink).unwrap());
+        assert_eq!(sink.pixels[0][0], 0xFFFFFF);
+        assert_eq!(sink.frames, 2);
+    }
+
+    #[test]
+    fn reads_stream_frames() {
+        let mut stream = vec![0x00, 0x36, SERIAL_START];
+        stream.extend_from_slice(&Frame { kind: DATA, data: vec![1, 2, 3] }.to_bytes()[1..5]);
+        stream.push(0x00);
+        stream.extend_from_slice(&Frame { kind: COMMAND, data: vec![0] }.to_bytes());
+        stream.extend_from_slice(&Frame { kind: DATA, data: vec![4, 5, 6, 7, 8, 9] }.to_bytes());
+
+        let mut sink = PixelBuffer::new(1, 2);
+        let mut tpm2 = Tpm2Stream::new(&stream[..], 3).unwrap();
+        assert!(tpm2.recv(&mut sink).unwrap());
+        assert_eq!(sink.pixels[0], vec![0x040506, 0x070809]);
+        assert!(!tpm2.recv(&mut sink).unwrap());
+        assert_eq!(sink.frames, 1);
+    }
+}
@@ -0,0 +1,657 @@
+//! WLED compatible realtime UDP and HTTP JSON API.
+//!
+//! `WledRealtime` takes the WARLS, DRGB, DRGBW and DNRGB realtime packets,
+//! with LEDs numbered in channel order or in the order of a `PixelMap`, and
+//! stays live for the timeout in each packet so whoever runs effects knows to
+//! hold off.
+//!
+//! `WledServer` answers the subset of the JSON API the WLED app and Home
+//! Assistant use: `/json`, `/json/state`, `/json/info`, `/json/eff` and
+//! `/json/pal`, and takes state updates (on, brightness, colour, effect) to
+//! `/json/state`. `WledServer::render` shows the state on a `PixelSink`, the
+//! effects it knows are `EFFECTS`.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use serde_json::{json, Value};
+
+use super::{color, wake_listener, LinearDisplay, NetError, PacketError, PixelSink};
+use crate::daemon::Effect;
+use crate::error::Result;
+use crate::mapping::PixelMap;
+
+pub const UDP_PORT: u16 = 21324;
+pub const HTTP_PORT: u16 = 80;
+
+/// Effects `WledState::render` knows by name, what `WledInfo::new` lists.
+/// Any other name shows the primary colour.
+pub const EFFECTS: &[&str] = &["Solid", "Breathe", "Rainbow", "Chase"];
+
+pub const WARLS: u8 = 1;
+pub const DRGB: u8 = 2;
+pub const DRGBW: u8 = 3;
+pub const DNRGB: u8 = 4;
+
+// timeout byte that keeps realtime mode on until the next packet says
+// otherwise
+const NO_TIMEOUT: u8 = 255;
+const MAX_PACKET_LEN: usize = 1472;
+// the WLED release whose API this follows
+const WLED_VERSION: &str = "0.14.4";
+const MAX_BODY_LEN: usize = 64 * 1024;
+// clients that take longer than this to send a request are dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RealtimeData<'a> {
+    /// `index, r, g, b` for each LED, up to 255 of them.
+    Warls(&'a [u8]),
+    /// `r, g, b` for each LED from the first.
+    Drgb(&'a [u8]),
+    /// `r, g, b, w` for each LED from the first.
+    Drgbw(&'a [u8]),
+    /// `r, g, b` for each LED from `start`.
+    Dnrgb { start: u16, data: &'a [u8] },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RealtimePacket<'a> {
+    /// How long to stay in realtime mode after this packet, `None` for
+    /// good.
+    pub timeout: Option<Duration>,
+    pub data: RealtimeData<'a>,
+}
+
+impl<'a> RealtimePacket<'a> {
+    pub fn parse(buf: &'a [u8]) -> std::result::Result<Self, PacketError> {
+        if buf.len() < 2 {
+            return Err(PacketError::Truncated(buf.len()));
+        }
+        let timeout = match buf[1] {
+            NO_TIMEOUT => None,
+            secs => Some(Duration::from_secs(secs as u64)),
+        };
+        let data = match buf[0] {
+            WARLS => RealtimeData::Warls(&buf[2..]),
+            DRGB => RealtimeData::Drgb(&buf[2..]),
+            DRGBW => RealtimeData::Drgbw(&buf[2..]),
+            DNRGB if buf.len() >= 4 => RealtimeData::Dnrgb {
+                start: u16::from_be_bytes([buf[2], buf[3]]),
+                data: &buf[4..],
+            },
+            DNRGB => return Err(PacketError::Truncated(buf.len())),
+            protocol => return Err(PacketError::Unsupported { protocol: "WLED realtime", kind: protocol as u32 }),
+        };
+        Ok(RealtimePacket { timeout, data })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let timeout = self.timeout.map_or(NO_TIMEOUT, |timeout| timeout.as_secs().min(254) as u8);
+        let (protocol, data) = match self.data {
+            RealtimeData::Warls(data) => (WARLS, data),
+            RealtimeData::Drgb(data) => (DRGB, data),
+            RealtimeData::Drgbw(data) => (DRGBW, data),
+            RealtimeData::Dnrgb { data, .. } => (DNRGB, data),
+        };
+        let mut buf = vec![protocol, timeout];
+        if let RealtimeData::Dnrgb { start, .. } = self.data {
+            buf.extend_from_slice(&start.to_be_bytes());
+        }
+        buf.extend_from_slice(data);
+        buf
+    }
+}
+
+/// Listens for WLED realtime packets and writes them to a `PixelSink`.
+pub struct WledRealtime {
+    socket: UdpSocket,
+    display: LinearDisplay,
+    // time of the last packet and its timeout
+    live: Option<(Instant, Option<Duration>)>,
+}
+
+impl WledRealtime {
+    /// Listens on `addr`, usually `(Ipv4Addr::UNSPECIFIED, UDP_PORT)`.
+    pub fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
+        Ok(WledRealtime {
+            socket: UdpSocket::bind(addr)?,
+            display: LinearDisplay::new(3)?,
+            live: None,
+        })
+    }
+
+    /// Numbers LEDs in the order of `map` instead of channel after channel.
+    pub fn pixel_map(mut self, map: PixelMap) -> Self {
+        self.display.map = Some(map);
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Makes `recv` fail with `io::ErrorKind::WouldBlock` instead of waiting
+    /// when no packet has come in.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        Ok(self.socket.set_nonblocking(nonblocking)?)
+    }
+
+    /// Whether a packet's timeout is still running at `now`.
+    pub fn is_live(&self, now: Instant) -> bool {
+        match self.live {
+            Some((_, None)) => true,
+            Some((at, Some(timeout))) => now.duration_since(at) < timeout,
+            None => false,
+        }
+    }
+
+    /// Waits for a packet and handles it, returns true if it presented a
+    /// frame.
+    pub fn recv(&mut self, sink: &mut impl PixelSink) -> Result<bool> {
+        let mut buf = [0; MAX_PACKET_LEN];
+        let (len, from) = self.socket.recv_from(&mut buf)?;
+        match RealtimePacket::parse(&buf[..len]) {
+            Ok(packet) => self.handle(packet, Instant::now(), sink),
+            Err(err) => {
+                debug!("WLED: dropped packet from {}: {}", from, err);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Handles packets until receiving or presenting fails.
+    pub fn run(&mut self, sink: &mut impl PixelSink) -> Result<()> {
+        loop {
+            self.recv(sink)?;
+        }
+    }
+
+    /// Handles a packet received at `now` and presents it.
+    pub fn handle(&mut self, packet: RealtimePacket, now: Instant, sink: &mut impl PixelSink) -> Result<bool> {
+        self.live = Some((now, packet.timeout));
+        match packet.data {
+            RealtimeData::Warls(data) => {
+                for led in data.chunks_exact(4) {
+                    self.display.set_led(led[0] as usize, color(&led[1..]), sink);
+                }
+            }
+            RealtimeData::Drgb(data) => self.set_leds(0, data.chunks_exact(3), sink),
+            RealtimeData::Drgbw(data) => self.set_leds(0, data.chunks_exact(4), sink),
+            RealtimeData::Dnrgb { start, data } => self.set_leds(start as usize, data.chunks_exact(3), sink),
+        }
+        sink.present()?;
+        Ok(true)
+    }
+
+    fn set_leds<'a>(&self, start: usize, leds: impl Iterator<Item = &'a [u8]>, sink: &mut impl PixelSink) {
+        for (n, led) in leds.enumerate() {
+            self.display.set_led(start + n, color(led), sink);
+        }
+    }
+}
+
+/// What the JSON API controls, a single segment covering every LED.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WledState {
+    pub on: bool,
+    pub brightness: u8,
+    /// Primary colour, red, green and blue.
+    pub color: [u8; 3],
+    /// Index into `WledInfo::effects`.
+    pub effect: usize,
+    pub speed: u8,
+    pub intensity: u8,
+}
+
+impl Default for WledState {
+    // what a new WLED comes up with
+    fn default() -> Self {
+        WledState {
+            on: true,
+            brightness: 128,
+            color: [255, 160, 0],
+            effect: 0,
+            speed: 128,
+            intensity: 128,
+        }
+    }
+}
+
+impl WledState {
+    /// `color` (`0xWWRRGGBB`) at the state's brightness, black when off.
+    pub fn scale(&self, color: u32) -> u32 {
+        if !self.on {
+            return 0;
+        }
+        u32::from_be_bytes(color.to_be_bytes().map(|c| (c as u32 * self.brightness as u32 / 255) as u8))
+    }
+
+    /// The primary colour as `0x00RRGGBB`, at the state's brightness.
+    pub fn pixel(&self) -> u32 {
+        let [r, g, b] = self.color;
+        self.scale(u32::from_be_bytes([0, r, g, b]))
+    }
+
+    /// The selected effect of `effects` in the primary colour, at full
+    /// brightness and running faster the higher the speed.
+    pub fn effect(&self, effects: &[String]) -> Effect {
+        let [r, g, b] = self.color;
+        let color = u32::from_be_bytes([0, r, g, b]);
+        let speed = self.speed as f32 / 255.0;
+        match effects.get(self.effect).map(String::as_str) {
+            Some("Breathe") => Effect::Breathe { color, period: 8.0 / (1.0 + speed * 15.0) },
+            Some("Rainbow") => Effect::Rainbow { speed: 0.02 + speed },
+            Some("Chase") => Effect::Chase { color, speed: 5.0 + speed * 95.0 },
+            _ => Effect::Solid { color },
+        }
+    }
+
+    /// Sets every LED of `sink` to the state at `t` seconds and presents the
+    /// frame.
+    pub fn render(&self, effects: &[String], t: f32, sink: &mut impl PixelSink) -> Result<()> {
+        let effect = self.effect(effects);
+        let (nchans, nleds) = (sink.channels(), sink.leds_per_channel());
+        for channel in 0..nchans {
+            for index in 0..nleds {
+                let color = effect.pixel(channel * nleds + index, nchans * nleds, t);
+                sink.set_pixel(channel, index, self.scale(color));
+            }
+        }
+        sink.present()
+    }
+
+    fn json(&self, leds: usize) -> Value {
+        json!({
+            "on": self.on,
+            "bri": self.brightness,
+            "transition": 0,
+            "ps": -1,
+            "pl": -1,
+            "lor": 0,
+            "mainseg": 0,
+            "seg": [{
+                "id": 0,
+                "start": 0,
+                "stop": leds,
+                "len": leds,
+                "on": true,
+                "bri": 255,
+                "col": [self.color, [0, 0, 0], [0, 0, 0]],
+                "fx": self.effect,
+                "sx": self.speed,
+                "ix": self.intensity,
+                "pal": 0,
+                "sel": true,
+            }],
+        })
+    }
+
+    // applies a (partial) state object, unknown fields are ignored
+    fn update(&mut self, update: &Value, neffects: usize) {
+        match update.get("on") {
+            Some(Value::Bool(on)) => self.on = *on,
+            Some(Value::String(toggle)) if toggle == "t" => self.on = !self.on,
+            _ => {}
+        }
+        // brightness 0 turns the LEDs off and keeps the last brightness
+        match update.get("bri").and_then(Value::as_u64) {
+            Some(0) => self.on = false,
+            Some(bri) => self.brightness = bri.min(255) as u8,
+            None => {}
+        }
+
+        let segment = match update.get("seg") {
+            Some(Value::Array(segments)) => segments.first(),
+            segment => segment,
+        };
+        let Some(segment) = segment else {
+            return;
+        };
+        if let Some(color) = segment.get("col").and_then(|col| col.get(0)).and_then(parse_color) {
+            self.color = color;
+        }
+        if let Some(effect) = segment.get("fx").and_then(Value::as_u64) {
+            if (effect as usize) < neffects {
+                self.effect = effect as usize;
+            }
+        }
+        if let Some(speed) = segment.get("sx").and_then(Value::as_u64) {
+            self.speed = speed.min(255) as u8;
+        }
+        if let Some(intensity) = segment.get("ix").and_then(Value::as_u64) {
+            self.intensity = intensity.min(255) as u8;
+        }
+    }
+}
+
+// `[r, g, b(, w)]` or `"RRGGBB"`
+fn parse_color(value: &Value) -> Option<[u8; 3]> {
+    match value {
+        Value::Array(components) if components.len() >= 3 => {
+            let component = |n: usize| components[n].as_u64().map(|c| c.min(255) as u8);
+            Some([component(0)?, component(1)?, component(2)?])
+        }
+        Value::String(hex) if hex.len() >= 6 => {
+            let rgb = u32::from_str_radix(&hex[..6], 16).ok()?;
+            let [_, r, g, b] = rgb.to_be_bytes();
+            Some([r, g, b])
+        }
+        _ => None,
+    }
+}
+
+/// How the node describes itself in `/json/info`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WledInfo {
+    pub name: String,
+    pub leds: usize,
+    /// Names of the effects `WledState::effect` selects from.
+    pub effects: Vec<String>,
+}
+
+impl WledInfo {
+    pub fn new(name: &str, leds: usize) -> Self {
+        WledInfo {
+            name: name.to_string(),
+            leds,
+            effects: EFFECTS.iter().map(|effect| effect.to_string()).collect(),
+        }
+    }
+
+    pub fn effects(mut self, effects: &[&str]) -> Self {
+        self.effects = effects.iter().map(|effect| effect.to_string()).collect();
+        self
+    }
+}
+
+struct Shared {
+    info: WledInfo,
+    state: Mutex<WledState>,
+    live: AtomicBool,
+    started: Instant,
+    stopping: AtomicBool,
+}
+
+/// HTTP server for the WLED JSON API.
+pub struct WledServer {
+    local_addr: SocketAddr,
+    shared: Arc<Shared>,
+    listener: Option<JoinHandle<()>>,
+}
+
+impl WledServer {
+    /// Listens on `addr`, usually `(Ipv4Addr::UNSPECIFIED, HTTP_PORT)`.
+    /// Every request is served on a thread of its own, dropping the server
+    /// stops listening.
+    pub fn bind(addr: impl ToSocketAddrs, info: WledInfo) -> Result<Self> {
+        if info.effects.is_empty() {
+            return Err(NetError::NoWledEffects.into());
+        }
+        let listener = TcpListener::bind(addr)?;
+        let shared = Arc::new(Shared {
+            info,
+            state: Mutex::new(WledState::default()),
+            live: AtomicBool::new(false),
+            started: Instant::now(),
+            stopping: AtomicBool::new(false),
+        });
+
+        let local_addr = listener.local_addr()?;
+        let accepting = shared.clone();
+        let listener = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accepting.stopping.load(Ordering::Relaxed) {
+                    return;
+                }
+                match stream {
+                    Ok(stream) => {
+                        let shared = accepting.clone();
+                        thread::spawn(move || {
+                            if let Err(err) = serve(stream, &shared) {
+                                debug!("WLED: request failed: {}", err);
+                            }
+                        });
+                    }
+                    Err(err) => debug!("WLED: accept failed: {}", err),
+                }
+            }
+        });
+        Ok(WledServer {
+            local_addr,
+            shared,
+            listener: Some(listener),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn state(&self) -> WledState {
+        *self.shared.state.lock().unwrap()
+    }
+
+    pub fn set_state(&self, state: WledState) {
+        *self.shared.state.lock().unwrap() = state;
+    }
+
+    /// Shows the current state on `sink` at `t` seconds, see
+    /// `WledState::render`.
+    pub fn render(&self, t: f32, sink: &mut impl PixelSink) -> Result<()> {
+        self.state().render(&self.shared.info.effects, t, sink)
+    }
+
+    /// Shows the node as in realtime mode, see `WledRealtime::is_live`.
+    pub fn set_live(&self, live: bool) {
+        self.shared.live.store(live, Ordering::Relaxed);
+    }
+}
+
+impl Drop for WledServer {
+    fn drop(&mut self) {
+        self.shared.stopping.store(true, Ordering::Relaxed);
+        if wake_listener(self.local_addr) {
+            if let Some(listener) = self.listener.take() {
+                let _ = listener.join();
+            }
+        }
+    }
+}
+
+fn serve(stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut request = line.split_whitespace().map(str::to_string);
+    let (method, path) = (request.next().unwrap_or_default(), request.next().unwrap_or_default());
+
+    let mut len = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                len = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    // turned away before reading, so nothing too long is ever buffered
+    let (status, body) = if len > MAX_BODY_LEN {
+        ("413 Payload Too Large", json!({ "error": 9 }).to_string())
+    } else {
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body)?;
+        respond(shared, &method, &path, &body)
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+fn respond(shared: &Shared, method: &str, path: &str, body: &[u8]) -> (&'static str, String) {
+    let path = path.split('?').next().unwrap_or("").trim_end_matches('/');
+    let leds = shared.info.leds;
+    let mut state = shared.state.lock().unwrap();
+
+    let json = match (method, path) {
+        ("GET", "/json") => json!({
+            "state": state.json(leds),
+            "info": info_json(shared),
+            "effects": shared.info.effects,
+            "palettes": ["Default"],
+        }),
+        ("GET", "/json/state") => state.json(leds),
+        ("GET", "/json/info") => info_json(shared),
+        ("GET", "/json/eff") => json!(shared.info.effects),
+        ("GET", "/json/pal") => json!(["Default"]),
+        ("POST", "/json" | "/json/state") => {
+            let Ok(update) = serde_json::from_slice::<Value>(body) else {
+                return ("400 Bad Request", json!({ "error": 9 }).to_string());
+            };
+            state.update(&update, shared.info.effects.len());
+            match update.get("v") {
+                Some(Value::Bool(true)) => state.json(leds),
+                _ => json!({ "success": true }),
+            }
+        }
+        _ => return ("404 Not Found", json!({ "error": "Not implemented" }).to_string()),
+    };
+    ("200 OK", json.to_string())
+}
+
+fn info_json(shared: &Shared) -> Value {
+    json!({
+        "ver": WLED_VERSION,
+        "vid": 0,
+        "leds": {
+            "count": shared.info.leds,
+            "rgbw": false,
+            "wv": 0,
+            "cct": 0,
+            "pwr": 0,
+            "fps": 0,
+            "maxpwr": 0,
+            "maxseg": 1,
+            "seglc": [1],
+        },
+        "str": false,
+        "name": shared.info.name,
+        "udpport": UDP_PORT,
+        "live": shared.live.load(Ordering::Relaxed),
+        "fxcount": shared.info.effects.len(),
+        "palcount": 1,
+        "wifi": { "bssid": "", "rssi": 0, "signal": 100, "channel": 0 },
+        "fs": { "u": 0, "t": 0, "pmt": 0 },
+        "arch": "rpi",
+        "core": "linux",
+        "uptime": shared.started.elapsed().as_secs(),
+        "brand": "WLED",
+        "product": "rpi-cube",
+        "mac": "000000000000",
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::Error;
+    use crate::net::PixelBuffer;
+
+    fn send(server: &WledServer, request: &str) -> String {
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn request(server: &WledServer, request: &str) -> Value {
+        let response = send(server, request);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn takes_realtime_packets() {
+        let mut realtime = WledRealtime::bind("127.0.0.1:0").unwrap();
+        let mut sink = PixelBuffer::new(2, 2);
+        let now = Instant::now();
+        assert!(!realtime.is_live(now));
+
+        let packet = RealtimePacket {
+            timeout: Some(Duration::from_secs(2)),
+            data: RealtimeData::Dnrgb { start: 1, data: &[1, 2, 3, 4, 5, 6] },
+        };
+        assert_eq!(RealtimePacket::parse(&packet.to_bytes()), Ok(packet));
+        assert!(realtime.handle(packet, now, &mut sink).unwrap());
+        assert_eq!(sink.pixels, vec![vec![0, 0x010203], vec![0x040506, 0]]);
+        assert!(realtime.is_live(now + Duration::from_secs(1)));
+        assert!(!realtime.is_live(now + Duration::from_secs(2)));
+
+        let warls = RealtimePacket::parse(&[WARLS, 255, 3, 9, 9, 9, 7, 1, 1, 1]).unwrap();
+        realtime.handle(warls, now, &mut sink).unwrap();
+        assert_eq!(sink.pixels[1][1], 0x090909);
+        assert!(realtime.is_live(now + Duration::from_secs(1000)));
+        assert!(RealtimePacket::parse(&[0, 2, 0]).is_err());
+    }
+
+    #[test]
+    fn serves_json_api() {
+        let info = WledInfo::new("cube", 512).effects(&["Solid", "Rainbow"]);
+        let server = WledServer::bind("127.0.0.1:0", info).unwrap();
+
+        let all = request(&server, "GET /json HTTP/1.1\r\nHost: cube\r\n\r\n");
+        assert_eq!(all["info"]["leds"]["count"], 512);
+        assert_eq!(all["effects"][1], "Rainbow");
+        assert_eq!(all["state"]["seg"][0]["col"][0], json!([255, 160, 0]));
+
+        let body = r#"{"on":true,"bri":255,"seg":[{"col":[[0,0,255]],"fx":1}],"v":true}"#;
+        let post = format!("POST /json/state HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let state = request(&server, &post);
+        assert_eq!(state["seg"][0]["fx"], 1);
+        assert_eq!(server.state().pixel(), 0x0000FF);
+
+        let post = "POST /json/state HTTP/1.1\r\nContent-Length: 9\r\n\r\n{\"bri\":0}";
+        assert_eq!(request(&server, post), json!({ "success": true }));
+        assert_eq!(server.state().pixel(), 0);
+        assert_eq!(server.state().brightness, 255);
+
+        // what's posted is what lights up
+        let mut sink = PixelBuffer::new(2, 2);
+        let body = r#"{"on":true,"bri":128,"seg":[{"col":[[0,0,255]],"fx":0}]}"#;
+        let post = format!("POST /json/state HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        request(&server, &post);
+        server.render(0.0, &mut sink).unwrap();
+        assert_eq!(sink.pixels, vec![vec![0x000080; 2]; 2]);
+        let body = r#"{"seg":[{"col":[[255,0,0]],"fx":1}]}"#;
+        let post = format!("POST /json/state HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        request(&server, &post);
+        server.render(0.0, &mut sink).unwrap();
+        assert_eq!(sink.pixels[0][0], 0x800000);
+        request(&server, "POST /json/state HTTP/1.1\r\nContent-Length: 12\r\n\r\n{\"on\":false}");
+        server.render(0.0, &mut sink).unwrap();
+        assert_eq!((sink.pixels, sink.frames), (vec![vec![0; 2]; 2], 3));
+
+        let post = format!("POST /json/state HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_LEN + 1);
+        assert!(send(&server, &post).starts_with("HTTP/1.1 413 Payload Too Large"));
+
+        let addr = server.local_addr();
+        drop(server);
+        assert!(TcpStream::connect(addr).is_err());
+        assert!(matches!(WledServer::bind("127.0.0.1:0", WledInfo::new("cube", 1).effects(&[])), Err(Error::Net(NetError::NoWledEffects))));
+    }
+}
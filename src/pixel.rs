@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+
+/// Order and number of colour components a chip expects on the wire.
+///
+/// Pixels are always handed to the encoder as `0xWWRRGGBB` (the white byte is
+/// ignored by the 3 component formats), the format decides which bytes get
+/// shifted out and in what order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PixelFormat {
+    Rgb,
+    Rbg,
+    Grb,
+    Gbr,
+    Brg,
+    Bgr,
+    Rgbw,
+    Grbw,
+    Brgw,
+    Wrgb,
+    Wgrb,
+}
+
+/// Where the white component of a 4 component pixel comes from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum WhiteMode {
+    /// Sent as is from the top byte of the pixel.
+    #[default]
+    Passthrough,
+    /// Derived from the colour, `min(r, g, b)` is moved from the colour
+    /// components onto the white LED.
+    Extract,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Component {
+    R,
+    G,
+    B,
+    W,
+}
+
+impl Component {
+    fn shift(self) -> u32 {
+        match self {
+            Component::W => 24,
+            Component::R => 16,
+            Component::G => 8,
+            Component::B => 0,
+        }
+    }
+}
+
+impl PixelFormat {
+    fn order(self) -> &'static [Component] {
+        use Component::*;
+        match self {
+            PixelFormat::Rgb => &[R, G, B],
+            PixelFormat::Rbg => &[R, B, G],
+            PixelFormat::Grb => &[G, R, B],
+            PixelFormat::Gbr => &[G, B, R],
+            PixelFormat::Brg => &[B, R, G],
+            PixelFormat::Bgr => &[B, G, R],
+            PixelFormat::Rgbw => &[R, G, B, W],
+            PixelFormat::Grbw => &[G, R, B, W],
+            PixelFormat::Brgw => &[B, R, G, W],
+            PixelFormat::Wrgb => &[W, R, G, B],
+            PixelFormat::Wgrb => &[W, G, R, B],
+        }
+    }
+
+    /// Number of data bits per LED.
+    pub fn bits(self) -> usize {
+        self.order().len() * 8
+    }
+
+    pub fn has_white(self) -> bool {
+        self.order().len() == 4
+    }
+
+    /// Converts a `0xWWRRGGBB` pixel into the value to shift out, MSB first,
+    /// in the low `bits()` bits.
+    pub fn to_wire(self, color: u32, white: WhiteMode) -> u32 {
+        let color = match white {
+            WhiteMode::Extract if self.has_white() => {
+                let w = (color >> 16 & 0xFF).min(color >> 8 & 0xFF).min(color & 0xFF);
+                (w << 24) | ((color & 0xFFFFFF) - (w << 16 | w << 8 | w))
+            }
+            _ => color,
+        };
+
+        self.order()
+            .iter()
+            .fold(0, |wire, c| (wire << 8) | (color >> c.shift() & 0xFF))
+    }
+
+    /// Inverse of `to_wire`, a derived white component is returned as is in
+    /// the top byte rather than folded back into the colour.
+    pub fn from_wire(self, wire: u32) -> u32 {
+        let order = self.order();
+        order.iter().enumerate().fold(0, |color, (n, c)| {
+            let byte = wire >> ((order.len() - 1 - n) * 8) & 0xFF;
+            color | (byte << c.shift())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn orders_components_on_the_wire() {
+        assert_eq!(PixelFormat::Rgb.to_wire(0x00112233, WhiteMode::Passthrough), 0x112233);
+        assert_eq!(PixelFormat::Grb.to_wire(0x00112233, WhiteMode::Passthrough), 0x221133);
+        assert_eq!(PixelFormat::Brg.to_wire(0x00112233, WhiteMode::Passthrough), 0x331122);
+        assert_eq!(PixelFormat::Grbw.to_wire(0x44112233, WhiteMode::Passthrough), 0x22113344);
+        assert_eq!(PixelFormat::Wrgb.to_wire(0x44112233, WhiteMode::Passthrough), 0x44112233);
+        assert_eq!(PixelFormat::Grb.from_wire(0x221133), 0x00112233);
+        assert_eq!(PixelFormat::Grbw.from_wire(0x22113344), 0x44112233);
+    }
+
+    #[test]
+    fn extracts_white_from_colour() {
+        assert_eq!(PixelFormat::Rgbw.to_wire(0x00805040, WhiteMode::Extract), 0x40100040);
+        // the white byte of the input is replaced, 3 component formats are untouched
+        assert_eq!(PixelFormat::Rgbw.to_wire(0xFF000000, WhiteMode::Extract), 0x00000000);
+        assert_eq!(PixelFormat::Rgb.to_wire(0x00805040, WhiteMode::Extract), 0x805040);
+    }
+}
@@ -0,0 +1,213 @@
+/// Current drawn by a single LED, used to estimate the draw of a frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PowerModel {
+    /// Current of each of red, green, blue and white at full brightness.
+    pub component_ma: [f32; 4],
+    /// Current of an LED that's off.
+    pub idle_ma: f32,
+}
+
+impl Default for PowerModel {
+    // a WS2812B (or the RGBW SK6812) at 5V
+    fn default() -> Self {
+        PowerModel {
+            component_ma: [20.0; 4],
+            idle_ma: 1.0,
+        }
+    }
+}
+
+impl PowerModel {
+    pub fn is_valid(&self) -> bool {
+        self.component_ma.iter().chain([&self.idle_ma]).all(|ma| ma.is_finite() && *ma >= 0.0)
+    }
+}
+
+/// Estimated draw of a frame, from the colour corrected pixels.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PowerEstimate {
+    /// Draw of each channel as the frame was handed in.
+    pub requested_ma: Vec<f32>,
+    /// Draw of each channel after limiting.
+    pub limited_ma: Vec<f32>,
+    /// Brightness each channel was scaled by to stay in the budgets.
+    pub scale: Vec<f32>,
+}
+
+impl PowerEstimate {
+    pub fn total_requested_ma(&self) -> f32 {
+        self.requested_ma.iter().sum()
+    }
+
+    pub fn total_ma(&self) -> f32 {
+        self.limited_ma.iter().sum()
+    }
+
+    /// True if any channel had to be dimmed.
+    pub fn is_limited(&self) -> bool {
+        self.scale.iter().any(|&scale| scale < 1.0)
+    }
+}
+
+/// Scales the brightness of frames so their estimated draw stays within a
+/// budget per channel and one for all channels together.
+///
+/// The idle current of the LEDs can't be scaled away, a budget below it
+/// turns the channel off entirely.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PowerLimiter {
+    pub model: PowerModel,
+    pub channel_budget_ma: Option<f32>,
+    pub budget_ma: Option<f32>,
+}
+
+impl PowerLimiter {
+    pub fn is_valid(&self) -> bool {
+        self.model.is_valid()
+            && [self.channel_budget_ma, self.budget_ma]
+                .iter()
+                .flatten()
+                .all(|ma| ma.is_finite() && *ma >= 0.0)
+    }
+
+    /// Estimates and limits a frame of `0xWWRRGGBB` pixels in place.
+    pub fn limit(&self, channels: &mut [Vec<u32>]) -> PowerEstimate {
+        self.limit_pixels(channels)
+    }
+
+    /// Like `limit` for pixels with 16 bits per component.
+    pub fn limit16(&self, channels: &mut [Vec<u64>]) -> PowerEstimate {
+        self.limit_pixels(channels)
+    }
+
+    fn limit_pixels<P: Pixel>(&self, channels: &mut [Vec<P>]) -> PowerEstimate {
+        // the draw of each channel split into what can and can't be dimmed
+        let idle = channels
+            .iter()
+            .map(|leds| leds.len() as f32 * self.model.idle_ma)
+            .collect::<Vec<_>>();
+        let active = channels
+            .iter()
+            .map(|leds| leds.iter().map(|&pixel| self.pixel_ma(pixel)).sum::<f32>())
+            .collect::<Vec<_>>();
+
+        let mut scale = idle
+            .iter()
+            .zip(active.iter())
+            .map(|(&idle, &active)| match self.channel_budget_ma {
+                Some(budget) => fit(budget, idle, active),
+                None => 1.0,
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(budget) = self.budget_ma {
+            let idle = idle.iter().sum();
+            let active = active.iter().zip(scale.iter()).map(|(active, scale)| active * scale).sum();
+            let global = fit(budget, idle, active);
+            scale.iter_mut().for_each(|scale| *scale *= global);
+        }
+
+        for (leds, &scale) in channels.iter_mut().zip(scale.iter()) {
+            if scale < 1.0 {
+                leds.iter_mut().for_each(|pixel| *pixel = pixel.scaled(scale));
+            }
+        }
+
+        PowerEstimate {
+            requested_ma: idle.iter().zip(active.iter()).map(|(idle, active)| idle + active).collect(),
+            limited_ma: channels
+                .iter()
+                .zip(idle.iter())
+                .map(|(leds, idle)| idle + leds.iter().map(|&pixel| self.pixel_ma(pixel)).sum::<f32>())
+                .collect(),
+            scale,
+        }
+    }
+
+    fn pixel_ma<P: Pixel>(&self, pixel: P) -> f32 {
+        let [w, r, g, b] = pixel.levels();
+        let [r_ma, g_ma, b_ma, w_ma] = self.model.component_ma;
+        r * r_ma + g * g_ma + b * b_ma + w * w_ma
+    }
+}
+
+// scale of `active` that keeps `idle + active` within `budget`
+fn fit(budget: f32, idle: f32, active: f32) -> f32 {
+    if idle + active <= budget {
+        1.0
+    } else {
+        ((budget - idle) / active).clamp(0.0, 1.0)
+    }
+}
+
+trait Pixel: Copy {
+    /// White, red, green and blue as 0.0-1.0.
+    fn levels(self) -> [f32; 4];
+
+    /// Every component scaled by `scale`, rounded down.
+    fn scaled(self, scale: f32) -> Self;
+}
+
+impl Pixel for u32 {
+    fn levels(self) -> [f32; 4] {
+        self.to_be_bytes().map(|c| c as f32 / 255.0)
+    }
+
+    fn scaled(self, scale: f32) -> Self {
+        u32::from_be_bytes(self.to_be_bytes().map(|c| (c as f32 * scale) as u8))
+    }
+}
+
+impl Pixel for u64 {
+    fn levels(self) -> [f32; 4] {
+        [48, 32, 16, 0].map(|shift| (self >> shift & 0xFFFF) as f32 / 65535.0)
+    }
+
+    fn scaled(self, scale: f32) -> Self {
+        [48, 32, 16, 0]
+            .iter()
+            .fold(0, |out, shift| (out << 16) | ((self >> shift & 0xFFFF) as f32 * scale) as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn estimates_draw_without_limits() {
+        let limiter = PowerLimiter::default();
+        let mut channels = vec![vec![0x00FFFFFF; 4], vec![0xFF000000, 0x00000000]];
+        let power = limiter.limit(&mut channels);
+
+        assert_eq!(power.requested_ma, vec![4.0 * 61.0, 22.0]);
+        assert_eq!(power.limited_ma, power.requested_ma);
+        assert!(!power.is_limited());
+        assert_eq!(channels[0][0], 0x00FFFFFF);
+    }
+
+    #[test]
+    fn scales_to_channel_and_global_budgets() {
+        let limiter = PowerLimiter {
+            channel_budget_ma: Some(124.0),
+            budget_ma: Some(150.0),
+            ..Default::default()
+        };
+
+        // 4 + 240mA and 4 + 60mA, the first is halved to fit its channel
+        // budget and then both go down to fit 150mA between them
+        let mut channels = vec![vec![0x00FFFFFF; 4], vec![0x00FF0000, 0x0000FF00, 0x000000FF, 0]];
+        let power = limiter.limit(&mut channels);
+        assert_eq!(power.total_requested_ma(), 308.0);
+        assert!(power.is_limited());
+        assert!(power.total_ma() <= 150.0);
+        assert!(power.limited_ma[0] <= 124.0);
+        assert!((power.scale[0] - 0.5 * 142.0 / 180.0).abs() < 1e-6);
+        assert!((power.scale[1] - 142.0 / 180.0).abs() < 1e-6);
+
+        let mut channels = vec![vec![0x0000_FFFF_FFFF_FFFFu64; 4]];
+        let power = limiter.limit16(&mut channels);
+        assert!(power.limited_ma[0] <= 124.0);
+        assert!(channels[0][0] >> 32 & 0xFFFF < 0x8000);
+    }
+}
@@ -10,45 +10,26 @@
 REG_DEF(SMI_D_REG, SMI_D_FIELDS);
 */
 
-use std::cell::RefCell;
-use std::ptr::{read_volatile, write_volatile};
-use std::rc::Rc;
-
-use memmap2::MmapMut;
-
+use crate::hal::RegisterBackend;
 use crate::smi::SMI_D;
 
-pub struct D {
-    smi_map: Rc<RefCell<MmapMut>>,
-    smi_d_base: *mut u32,
+pub struct D<R: RegisterBackend> {
+    smi_regs: R,
 }
 
-impl D {
-    pub fn new(smi_map: Rc<RefCell<MmapMut>>) -> Self {
-        let smi_map = smi_map.clone();
-        let mapping = smi_map.borrow();
-        assert!(mapping.len() >= 4);
-
+impl<R: RegisterBackend> D<R> {
+    pub fn new(smi_regs: R) -> Self {
         // init d fields to 0
-        let smi_d_base = unsafe {
-            let smi_d_base = mapping.as_ptr().byte_add(SMI_D) as *mut u32;
-            *smi_d_base = 0;
-            smi_d_base
-        };
-
-        drop(mapping);
-        D {
-            smi_map,
-            smi_d_base,
-        }
+        smi_regs.write(SMI_D, 0);
+
+        D { smi_regs }
     }
 
     pub fn get_data(&self) -> u32 {
-        let reg = unsafe { read_volatile(self.smi_d_base) };
-        reg
+        self.smi_regs.read(SMI_D)
     }
 
     pub fn set_data(&self, data: u32) {
-        unsafe { write_volatile(self.smi_d_base, data); }
+        self.smi_regs.write(SMI_D, data);
     }
 }
\ No newline at end of file
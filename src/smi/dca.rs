@@ -10,58 +10,40 @@
 REG_DEF(SMI_DCA_REG, SMI_DCA_FIELDS);
 */
 
-use std::cell::RefCell;
-use std::ptr::{read_volatile, write_volatile};
-use std::rc::Rc;
-
-use memmap2::MmapMut;
-
+use crate::hal::RegisterBackend;
 use crate::smi::SMI_DCA;
 
-pub struct DCA {
-    smi_map: Rc<RefCell<MmapMut>>,
-    smi_dca_base: *mut u32,
+pub struct DCA<R: RegisterBackend> {
+    smi_regs: R,
 }
 
-impl DCA {
-    pub fn new(smi_map: Rc<RefCell<MmapMut>>) -> Self {
-        let smi_map = smi_map.clone();
-        let mapping = smi_map.borrow();
-        assert!(mapping.len() >= SMI_DCA + 4);
-
+impl<R: RegisterBackend> DCA<R> {
+    pub fn new(smi_regs: R) -> Self {
         // init dca fields to 0
-        let smi_dca_base = unsafe {
-            let smi_dca_base = mapping.as_ptr().byte_add(SMI_DCA) as *mut u32;
-            *smi_dca_base = 0;
-            smi_dca_base
-        };
+        smi_regs.write(SMI_DCA, 0);
 
-        drop(mapping);
-        DCA {
-            smi_map,
-            smi_dca_base,
-        }
+        DCA { smi_regs }
     }
 
     pub fn get_addr(&self) -> u8 {
-        let reg = unsafe { read_volatile(self.smi_dca_base) };
+        let reg = self.smi_regs.read(SMI_DCA);
         (reg & 0x3F) as u8
     }
 
     pub fn set_addr(&self, addr: u8) {
-        let mut reg = unsafe { read_volatile(self.smi_dca_base) };
+        let mut reg = self.smi_regs.read(SMI_DCA);
         reg = (reg & !0x3F) | (addr as u32 & 0x3F);
-        unsafe { write_volatile(self.smi_dca_base, reg); }
+        self.smi_regs.write(SMI_DCA, reg);
     }
 
     pub fn get_dev(&self) -> u8 {
-        let reg = unsafe { read_volatile(self.smi_dca_base) };
+        let reg = self.smi_regs.read(SMI_DCA);
         ((reg >> 8) & 0x3) as u8
     }
 
     pub fn set_dev(&self, dev: u8) {
-        let mut reg = unsafe { read_volatile(self.smi_dca_base) };
+        let mut reg = self.smi_regs.read(SMI_DCA);
         reg = (reg & !(0x3 << 8)) | ((dev as u32 & 0x3) << 8);
-        unsafe { write_volatile(self.smi_dca_base, reg); }
+        self.smi_regs.write(SMI_DCA, reg);
     }
 }
\ No newline at end of file
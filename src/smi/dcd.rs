@@ -10,44 +10,23 @@
 REG_DEF(SMI_DCD_REG, SMI_DCD_FIELDS);
 */
 
-use std::cell::RefCell;
-use std::ptr::{read_volatile, write_volatile};
-use std::rc::Rc;
-
-use memmap2::MmapMut;
-
+use crate::hal::RegisterBackend;
 use crate::smi::SMI_DCD;
 
-pub struct DCD {
-    smi_map: Rc<RefCell<MmapMut>>,
-    smi_dcd_base: *mut u32,
+pub struct DCD<R: RegisterBackend> {
+    smi_regs: R,
 }
 
-impl DCD {
-    pub fn new(smi_map: Rc<RefCell<MmapMut>>) -> Self {
-        let smi_map = smi_map.clone();
-        let mapping = smi_map.borrow();
-        assert!(mapping.len() >= SMI_DCD + 4);
-
-        // init dcd fields to 0
-        let smi_dcd_base = unsafe {
-            let smi_dcd_base = mapping.as_ptr().byte_add(SMI_DCD) as *mut u32;
-            smi_dcd_base
-        };
-
-        drop(mapping);
-        DCD {
-            smi_map,
-            smi_dcd_base,
-        }
+impl<R: RegisterBackend> DCD<R> {
+    pub fn new(smi_regs: R) -> Self {
+        DCD { smi_regs }
     }
 
     pub fn get_data(&self) -> u32 {
-        let reg = unsafe { read_volatile(self.smi_dcd_base) };
-        reg
+        self.smi_regs.read(SMI_DCD)
     }
 
     pub fn set_data(&self, data: u32) {
-        unsafe { write_volatile(self.smi_dcd_base, data); }
+        self.smi_regs.write(SMI_DCD, data);
     }
 }
\ No newline at end of file
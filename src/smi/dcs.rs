@@ -10,96 +10,78 @@
 REG_DEF(SMI_DCS_REG, SMI_DCS_FIELDS);
 */
 
-use std::cell::RefCell;
-use std::ptr::{read_volatile, write_volatile};
-use std::rc::Rc;
-
-use memmap2::MmapMut;
-
+use crate::hal::RegisterBackend;
 use crate::smi::SMI_DCS;
 
-pub struct DCS {
-    smi_map: Rc<RefCell<MmapMut>>,
-    smi_dcs_base: *mut u32,
+pub struct DCS<R: RegisterBackend> {
+    smi_regs: R,
 }
 
-impl DCS {
-    pub fn new(smi_map: Rc<RefCell<MmapMut>>) -> Self {
-        let smi_map = smi_map.clone();
-        let mapping = smi_map.borrow();
-        assert!(mapping.len() >= SMI_DCS + 4);
-
+impl<R: RegisterBackend> DCS<R> {
+    pub fn new(smi_regs: R) -> Self {
         // init dcs fields to 0
-        let smi_dcs_base = unsafe {
-            let smi_dcs_base = mapping.as_ptr().byte_add(SMI_DCS) as *mut u32;
-            *smi_dcs_base = 0;
-            smi_dcs_base
-        };
+        smi_regs.write(SMI_DCS, 0);
 
-        drop(mapping);
-        DCS {
-            smi_map,
-            smi_dcs_base,
-        }
+        DCS { smi_regs }
     }
 
     pub fn get_enable(&self) -> bool {
-        let reg = unsafe { read_volatile(self.smi_dcs_base) };
+        let reg = self.smi_regs.read(SMI_DCS);
         (reg & 1) != 0
     }
 
     pub fn set_enable(&self, enable: bool) {
-        let mut reg = unsafe { read_volatile(self.smi_dcs_base) };
+        let mut reg = self.smi_regs.read(SMI_DCS);
         if enable {
             reg |= 1;
         } else {
             reg &= !1;
         }
-        unsafe { write_volatile(self.smi_dcs_base, reg); }
+        self.smi_regs.write(SMI_DCS, reg);
     }
 
     pub fn get_start(&self) -> bool {
-        let reg = unsafe { read_volatile(self.smi_dcs_base) };
+        let reg = self.smi_regs.read(SMI_DCS);
         (reg & 2) != 0
     }
 
     pub fn set_start(&self, start: bool) {
-        let mut reg = unsafe { read_volatile(self.smi_dcs_base) };
+        let mut reg = self.smi_regs.read(SMI_DCS);
         if start {
             reg |= 2;
         } else {
             reg &= !2;
         }
-        unsafe { write_volatile(self.smi_dcs_base, reg); }
+        self.smi_regs.write(SMI_DCS, reg);
     }
 
     pub fn get_done(&self) -> bool {
-        let reg = unsafe { read_volatile(self.smi_dcs_base) };
+        let reg = self.smi_regs.read(SMI_DCS);
         (reg & 4) != 0
     }
 
     pub fn set_done(&self, done: bool) {
-        let mut reg = unsafe { read_volatile(self.smi_dcs_base) };
+        let mut reg = self.smi_regs.read(SMI_DCS);
         if done {
             reg |= 4;
         } else {
             reg &= !4;
         }
-        unsafe { write_volatile(self.smi_dcs_base, reg); }
+        self.smi_regs.write(SMI_DCS, reg);
     }
 
     pub fn get_write(&self) -> bool {
-        let reg = unsafe { read_volatile(self.smi_dcs_base) };
+        let reg = self.smi_regs.read(SMI_DCS);
         (reg & 8) != 0
     }
 
     pub fn set_write(&self, write: bool) {
-        let mut reg = unsafe { read_volatile(self.smi_dcs_base) };
+        let mut reg = self.smi_regs.read(SMI_DCS);
         if write {
             reg |= 8;
         } else {
             reg &= !8;
         }
-        unsafe { write_volatile(self.smi_dcs_base, reg); }
+        self.smi_regs.write(SMI_DCS, reg);
     }
 }
\ No newline at end of file
@@ -11,49 +11,34 @@
 REG_DEF(SMI_DSW_REG, SMI_DSW_FIELDS);
 */
 
-use std::cell::RefCell;
-use std::ptr::{read_volatile, write_volatile};
-use std::rc::Rc;
-
-use memmap2::MmapMut;
-
+use crate::hal::RegisterBackend;
 use crate::smi::SMI_DSW;
 
-pub struct DSW {
-    smi_map: Rc<RefCell<MmapMut>>,
-    smi_dsw_base: *mut u32,
+pub struct DSW<R: RegisterBackend> {
+    smi_regs: R,
+    offset: usize,
 }
 
-impl DSW {
-    pub fn new(smi_map: Rc<RefCell<MmapMut>>, channel: usize) -> Self {
+impl<R: RegisterBackend> DSW<R> {
+    pub fn new(smi_regs: R, channel: usize) -> Self {
         assert!(channel < 4);
 
-        let smi_map = smi_map.clone();
-        let mapping = smi_map.borrow();
-        assert!(mapping.len() >= SMI_DSW + (8 * channel) + 4);
-
         // init dsw fields to 0
-        let smi_dsw_base = unsafe {
-            let smi_dsw_base = mapping.as_ptr().byte_add(SMI_DSW + (8 * channel)) as *mut u32;
-            *smi_dsw_base = 0;
-            smi_dsw_base
-        };
+        let offset = SMI_DSW + (8 * channel);
+        smi_regs.write(offset, 0);
 
-        drop(mapping);
         DSW {
-            smi_map,
-            smi_dsw_base,
+            smi_regs,
+            offset,
         }
     }
 
     fn write(&self, value: u32) {
-        unsafe {
-            write_volatile(self.smi_dsw_base, value);
-        }
+        self.smi_regs.write(self.offset, value);
     }
 
     fn read(&self) -> u32 {
-        unsafe { read_volatile(self.smi_dsw_base) }
+        self.smi_regs.read(self.offset)
     }
 
     pub fn get_wstrobe(&self) -> u8 {
@@ -10,45 +10,26 @@
 REG_DEF(SMI_L_REG, SMI_L_FIELDS);
 */
 
-use std::cell::RefCell;
-use std::ptr::{read_volatile, write_volatile};
-use std::rc::Rc;
-
-use memmap2::MmapMut;
-
+use crate::hal::RegisterBackend;
 use crate::smi::SMI_L;
 
-pub struct L {
-    smi_map: Rc<RefCell<MmapMut>>,
-    smi_l_base: *mut u32,
+pub struct L<R: RegisterBackend> {
+    smi_regs: R,
 }
 
-impl L {
-    pub fn new(smi_map: Rc<RefCell<MmapMut>>) -> Self {
-        let smi_map = smi_map.clone();
-        let mapping = smi_map.borrow();
-        assert!(mapping.len() >= SMI_L + 4);
-
+impl<R: RegisterBackend> L<R> {
+    pub fn new(smi_regs: R) -> Self {
         // init l fields to 0
-        let smi_l_base = unsafe {
-            let smi_l_base = mapping.as_ptr().byte_add(SMI_L) as *mut u32;
-            *smi_l_base = 0;
-            smi_l_base
-        };
-
-        drop(mapping);
-        L {
-            smi_map,
-            smi_l_base,
-        }
+        smi_regs.write(SMI_L, 0);
+
+        L { smi_regs }
     }
 
     pub fn get_len(&self) -> u32 {
-        let reg = unsafe { read_volatile(self.smi_l_base) };
-        reg
+        self.smi_regs.read(SMI_L)
     }
 
     pub fn set_len(&self, len: u32) {
-        unsafe { write_volatile(self.smi_l_base, len); }
+        self.smi_regs.write(SMI_L, len);
     }
 }
@@ -10,29 +10,38 @@ mod dca;
 mod dcd;
 mod fd;
 
-use std::cell::RefCell;
-use std::fs::OpenOptions;
 use std::ops::Range;
-use std::rc::Rc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
 
 use log::debug;
-use memmap2::{MmapMut, MmapOptions};
-
-use crate::{r, rwm, w};
-use crate::dma::{Dma, DMA_CB_SRCE_INC, DMA_DEST_DREQ, DMA_WAIT_RESP};
+use thiserror::Error;
+
+use crate::dma::{
+    Completion,
+    ControlBlock,
+    Dma,
+    DmaChain,
+    DmaError,
+    DMA_CB_SRCE_INC,
+    DMA_DEST_DREQ,
+    DMA_WAIT_RESP,
+};
+use crate::error::{self, Error};
+use crate::hal::{Backend, RegisterBackend};
+use crate::timing::SmiTiming;
 use crate::vc_mem::VcMem;
 use crate::{
-    CLK_BASE_ADDRESS,
-    PERIPHERAL_BASE_ADDRESS,
+    CLK_OFFSET,
     PERIPHERAL_BUS_ADDRESS,
     REQUEST_THRESH,
-    SMI_BASE_ADDRESS
+    SMI_OFFSET
 };
 
 const SMI_CS: usize   = 0x00;    // Control & status
 const SMI_L: usize    = 0x04;    // Transfer length
 const SMI_A: usize    = 0x08;    // Address
-const SMI_D: usize    = 0x0c;    // Data
+pub(crate) const SMI_D: usize = 0x0c; // Data
 const SMI_DSR: usize  = 0x10;    // Read settings device 0
 const SMI_DSW: usize  = 0x14;    // Write settings device 0
 const SMI_DMC: usize  = 0x30;    // DMA control
@@ -42,10 +51,18 @@ const SMI_DCD: usize  = 0x3c;    // Direct data
 const SMI_FD: usize   = 0x40;    // FIFO debug
 
 // Clock registers on the clock manager, not the smi device
-const CLK_SMI_CTL: usize = 0xb0;
+pub(crate) const CLK_SMI_CTL: usize = 0xb0;
 const CLK_SMI_DIV: usize = 0xb4;
 const CLK_PASSWD: u32    = 0x5a000000;
 
+// the clock settles within microseconds, anything longer is a hung clock
+const CLOCK_TIMEOUT: Duration = Duration::from_millis(10);
+
+// Clock control bits
+pub(crate) const CLK_ENAB: u32 = 1 << 4;
+pub(crate) const CLK_KILL: u32 = 1 << 5;
+pub(crate) const CLK_BUSY: u32 = 1 << 7;
+
 // Data widths
 const SMI_8_BITS: usize =  0;
 const SMI_16_BITS: usize = 1;
@@ -55,127 +72,105 @@ const SMI_9_BITS: usize =  3;
 // DMA request
 const DMA_SMI_DREQ: usize = 4;
 
+// transfer info for memory to the SMI FIFO, paced by the SMI DREQ
+const SMI_TX_TI: usize = DMA_DEST_DREQ | (DMA_SMI_DREQ << 16) | DMA_CB_SRCE_INC | DMA_WAIT_RESP;
+
 use cs::CS;
 use l::L;
 use a::A;
 use dsr::DSR;
 use dsw::DSW;
 use dmc::DMC;
-use dcs::DCS;
-use dca::DCA;
-use dcd::DCD;
-
-
-pub struct Smi {
-    dma: Dma,
-    clk_map: MmapMut,
-    smi_map: Rc<RefCell<MmapMut>>,
-
-    cs: CS,
-    l: L,
-    a: A,
-    dmc: DMC,
-    dsr: DSR,
-    dsw: DSW,
-    dcs: DCS,
-    dca: DCA,
-    dcd: DCD,
+
+
+#[derive(Error, Debug)]
+pub enum SmiError {
+    #[error("SMI clock didn't settle within {0:?}")]
+    ClockTimeout(Duration),
 }
 
-impl Smi {
+pub struct Smi<B: Backend> {
+    dma: Dma<B>,
+
+    cs: CS<B::Registers>,
+    l: L<B::Registers>,
+    a: A<B::Registers>,
+    dmc: DMC<B::Registers>,
+}
+
+impl<B: Backend> Smi<B> {
     pub fn new(
+        backend: &B,
         width_bits: usize,
-        ns: usize,
-        setup: usize,
-        strobe: usize,
-        hold: usize,
-        pace: usize,
+        timing: &SmiTiming,
         dma_channel: u8,
-    ) -> Self {
+    ) -> error::Result<Self> {
         let width = match width_bits {
             8 => SMI_8_BITS,
             16 => SMI_16_BITS,
             18 => SMI_18_BITS,
             9 => SMI_9_BITS,
-            _ => panic!("Invalid SMI data width"),
+            _ => return Err(Error::InvalidDataWidth(width_bits)),
         };
 
-        let dma = Dma::new(dma_channel);
-
-        let devmem = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open("/dev/mem")
-            .expect("Failed to open /dev/mem");
-
-        let mut clk_map = unsafe {
-            MmapOptions::new()
-                .offset(CLK_BASE_ADDRESS as u64)
-                .len(0x1000)
-                .map_mut(&devmem)
-                .expect("Failed to map CLK memory")
-        };
-
-        let smi_map = unsafe {
-            MmapOptions::new()
-                .offset(SMI_BASE_ADDRESS as u64)
-                .len(0x1000)
-                .map_mut(&devmem)
-                .expect("Failed to map SMI memory")
-        };
-        let smi_map = Rc::new(RefCell::new(smi_map));
-
-        let cs = CS::new(smi_map.clone());
-        let l = L::new(smi_map.clone());
-        let a = A::new(smi_map.clone());
-        let dmc = DMC::new(smi_map.clone());
-        let dsr = DSR::new(smi_map.clone(), 0);
-        let dsw = DSW::new(smi_map.clone(), 0);
-        let dcs = DCS::new(smi_map.clone());
-        let dca = DCA::new(smi_map.clone());
-        let dcd = DCD::new(smi_map.clone());
-
-        let clk_smi_ctl = unsafe {
-            clk_map.as_mut_ptr().byte_add(CLK_SMI_CTL) as *mut u32
-        };
-
-        let clk_smi_div = unsafe {
-            clk_map.as_mut_ptr().byte_add(CLK_SMI_DIV) as *mut u32
-        };
+        let dma = Dma::new(backend, dma_channel)?;
+
+        let clk_regs = backend.map_registers(CLK_OFFSET, 0x1000)?;
+        let smi_regs = backend.map_registers(SMI_OFFSET, 0x1000)?;
+
+        let cs = CS::new(smi_regs.clone());
+        let l = L::new(smi_regs.clone());
+        let a = A::new(smi_regs.clone());
+        let dmc = DMC::new(smi_regs.clone());
+        let dsr = DSR::new(smi_regs.clone(), 0);
+        let dsw = DSW::new(smi_regs.clone(), 0);
+
+        // the timings may have been worked out for another board's clock,
+        // keep the sample length the same on this one
+        let source_hz = backend.platform().soc.smi_source_hz();
+        let retimed = timing.at_source(source_hz);
+        if retimed != *timing {
+            debug!(
+                "SMI timing retimed for a {}MHz source: {:.1}ns samples instead of {:.1}ns",
+                source_hz / 1_000_000,
+                retimed.sample_ns(),
+                timing.sample_ns()
+            );
+        }
 
         // set up the clocks for the smi peripheral
-        let divi = (ns / 2) as u32;
+        let SmiTiming { divider, setup, strobe, hold, pace, .. } = retimed;
 
         // kill the clock and wait for it to stop
-        w(clk_smi_ctl, CLK_PASSWD | (1 << 5));
-        while r(clk_smi_ctl) & (1 << 7) != 0 {}
+        clk_regs.write(CLK_SMI_CTL, CLK_PASSWD | CLK_KILL);
+        wait_clock(&clk_regs, false)?;
 
-        // set clock source to plld_per which should be 500MHz
-        w(clk_smi_ctl, CLK_PASSWD | 6);
+        // set clock source to plld_per, 500MHz or 750MHz on the BCM2711
+        clk_regs.write(CLK_SMI_CTL, CLK_PASSWD | 6);
 
-        // set the divisor, the smi divisor is odd in that the bottom 8 bits are
-        // fixed to 0 and can't be set, so we need to shift the divisor by 8
-        w(clk_smi_div, CLK_PASSWD | (divi << 8));
+        // set the divisor, the integer part lives in bits 12-23 and the
+        // fractional part below it is left at 0
+        clk_regs.write(CLK_SMI_DIV, CLK_PASSWD | (divider << 12));
 
         // enable the clock and wait for it to be ready
-        rwm(clk_smi_ctl, |reg| *reg |= CLK_PASSWD | (1 << 4));
-        while r(clk_smi_ctl) & (1 << 7) == 0 {}
+        clk_regs.modify(CLK_SMI_CTL, |reg| *reg |= CLK_PASSWD | CLK_ENAB);
+        wait_clock(&clk_regs, true)?;
         
         // clear any errors on the SMI peripheral
         if cs.get_seterr() {
             cs.set_seterr(true);
         }
 
-        dsr.set_rsetup(setup as u8);
-        dsw.set_wsetup(setup as u8);
-        dsr.set_rstrobe(strobe as u8);
-        dsw.set_wstrobe(strobe as u8);
-        dsr.set_rhold(hold as u8);
-        dsw.set_whold(hold as u8);
+        dsr.set_rsetup(setup);
+        dsw.set_wsetup(setup);
+        dsr.set_rstrobe(strobe);
+        dsw.set_wstrobe(strobe);
+        dsr.set_rhold(hold);
+        dsw.set_whold(hold);
         dsr.set_rwidth(width as u8);
         dsw.set_wwidth(width as u8);
-        dsr.set_rpace(pace as u8);
-        dsw.set_wpace(pace as u8);
+        dsr.set_rpace(pace);
+        dsw.set_wpace(pace);
         dmc.set_panicr(8);
         dmc.set_panicw(8);
         dmc.set_reqr(REQUEST_THRESH as u8);
@@ -184,25 +179,18 @@ impl Smi {
             dsw.set_wswap(true);
         }
 
-        Smi {
+        Ok(Smi {
             dma,
-            clk_map,
-            smi_map,
 
             cs,
             l,
             a,
             // d,
             dmc,
-            dsr,
-            dsw,
-            dcs,
-            dca,
-            dcd,
-        }
+        })
     }
 
-    pub fn setup_transfer(&mut self, source: &VcMem, range: Range<usize>) {
+    pub fn setup_transfer(&mut self, source: &VcMem, range: Range<usize>) -> error::Result<()> {
         /*
             txdata = (TXDATA_T *)(cbs+1);
             smi_dmc->dmaen = 1;
@@ -218,8 +206,12 @@ impl Smi {
             cbs[0].dest_ad = REG_BUS_ADDR(smi_regs, SMI_D);
         */
 
-        let smi_d_bus_addr = PERIPHERAL_BUS_ADDRESS + 
-            ((SMI_BASE_ADDRESS + SMI_D) - PERIPHERAL_BASE_ADDRESS);
+        check_range(source, &range)?;
+        if self.is_busy() {
+            return Err(Error::TransferInProgress);
+        }
+
+        let smi_d_bus_addr = data_busaddr();
         debug!("smi_d_bus_addr: {:x}", smi_d_bus_addr);
 
         let len = (range.end - range.start) as u32;
@@ -231,22 +223,116 @@ impl Smi {
         self.l.set_len(len);
         self.cs.set_write(true);
         let cb = self.dma.get_cb();
-        cb.set_transfer_info((
-            DMA_DEST_DREQ | (DMA_SMI_DREQ << 16) | DMA_CB_SRCE_INC | DMA_WAIT_RESP
-        ) as u32);
+        cb.set_transfer_info(SMI_TX_TI as u32);
         cb.set_transfer_length(len);
         cb.set_source_address((source.busaddr() + range.start) as u32);
         cb.set_destination_address(smi_d_bus_addr as u32);
         self.dma.enable();
+        Ok(())
+    }
+
+    /// Points the next transfer at `source`, starting `offset` bytes in, the
+    /// length stays what `setup_transfer` set up.
+    pub fn set_source(&mut self, source: &VcMem, offset: usize) -> error::Result<()> {
+        check_range(source, &(offset..offset + self.l.get_len() as usize))?;
+        if self.is_busy() {
+            return Err(Error::TransferInProgress);
+        }
+
+        let cb = self.dma.get_cb();
+        cb.set_source_address((source.busaddr() + offset) as u32);
+        Ok(())
+    }
+
+    /// Control block that sends `range` of `source` out over SMI, to build a
+    /// `DmaChain` for `start_chain` from.
+    pub fn transfer_block(&self, source: &VcMem, range: Range<usize>) -> error::Result<ControlBlock> {
+        check_range(source, &range)?;
+        Ok(ControlBlock::new(
+            SMI_TX_TI as u32,
+            (source.busaddr() + range.start) as u32,
+            data_busaddr() as u32,
+            (range.end - range.start) as u32,
+        ))
     }
 
-    pub fn start_transfer(&mut self) {
+    /// Sends a frame gathered by a chain of `transfer_block`s instead of the
+    /// buffer set up with `setup_transfer`.
+    ///
+    /// The SMI is programmed with the length of one pass and stops after it,
+    /// so looped chains are rejected.
+    pub fn start_chain(&mut self, chain: &DmaChain) -> error::Result<()> {
+        if self.is_busy() {
+            return Err(Error::TransferInProgress);
+        }
+        if chain.is_looped() {
+            return Err(Error::LoopedSmiChain);
+        }
+
+        self.l.set_len(chain.transfer_length() as u32);
+        self.dma.start_chain(chain);
+        self.cs.set_start(true);
+        Ok(())
+    }
+
+    pub fn start_transfer(&mut self) -> error::Result<()> {
+        if self.is_busy() {
+            return Err(Error::TransferInProgress);
+        }
+
         self.dma.start();
         self.cs.set_start(true);
+        Ok(())
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.dma.is_active()
     }
 
-    pub fn wait_transfer(&self) {
-        self.dma.wait();
+    pub fn wait_transfer(&self) -> error::Result<()> {
+        self.dma.wait()?;
         debug!("post-transfer value: {:32b}", self.cs.get_value());
+        Ok(())
+    }
+
+    pub fn wait_transfer_timeout(&self, timeout: Duration) -> error::Result<()> {
+        Ok(self.dma.wait_timeout(timeout)?)
+    }
+
+    pub fn wait_transfer_deadline(&self, deadline: Instant) -> error::Result<()> {
+        Ok(self.dma.wait_deadline(deadline)?)
     }
-}
\ No newline at end of file
+
+    pub fn poll_transfer(&self) -> Poll<Result<(), DmaError>> {
+        self.dma.poll()
+    }
+
+    /// Future that resolves once the transfer is done, see `Dma::completion`.
+    pub fn transfer_done(&self) -> Completion<'_, B> {
+        self.dma.completion()
+    }
+}
+
+fn check_range(source: &VcMem, range: &Range<usize>) -> error::Result<()> {
+    if range.start > range.end || range.end > source.len() {
+        return Err(Error::InvalidTransfer { range: range.clone(), len: source.len() });
+    }
+    Ok(())
+}
+
+// waits for the SMI clock to report `busy`
+fn wait_clock(clk_regs: &impl RegisterBackend, busy: bool) -> Result<(), SmiError> {
+    let deadline = Instant::now() + CLOCK_TIMEOUT;
+    while (clk_regs.read(CLK_SMI_CTL) & CLK_BUSY != 0) != busy {
+        if Instant::now() >= deadline {
+            return Err(SmiError::ClockTimeout(CLOCK_TIMEOUT));
+        }
+        std::hint::spin_loop();
+    }
+    Ok(())
+}
+
+// bus address of the SMI data register the DMA writes to
+fn data_busaddr() -> usize {
+    PERIPHERAL_BUS_ADDRESS + SMI_OFFSET + SMI_D
+}
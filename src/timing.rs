@@ -0,0 +1,360 @@
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::encoder::BitTiming;
+
+// the SMI clock runs off plld_per, this is its rate on everything before the
+// BCM2711, see `Soc::smi_source_hz`
+pub const SMI_SOURCE_HZ: u32 = 500_000_000;
+
+// limits of the SMI device settings registers
+const MAX_SETUP: usize  = 63;
+const MAX_STROBE: usize = 127;
+const MAX_HOLD: usize   = 63;
+const MAX_DIVIDER: u32  = 0xfff;
+
+// longest bit cell the solver tries, every extra pulse grows the tx buffer
+const MAX_NPULSES: usize = 8;
+
+/// Clock and strobe settings of the SMI write cycle, one SMI sample takes
+/// `setup + strobe + hold (+ pace)` ticks of the divided SMI clock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SmiTiming {
+    /// Integer divider of the SMI clock source.
+    pub divider: u32,
+    pub setup: u8,
+    pub strobe: u8,
+    pub hold: u8,
+    pub pace: u8,
+    /// Rate of the clock source the divider applies to.
+    pub source_hz: u32,
+}
+
+impl Default for SmiTiming {
+    // 100MHz SMI clock and 42 ticks per sample, 420ns samples
+    fn default() -> Self {
+        SmiTiming {
+            divider: 5,
+            setup: 1,
+            strobe: 40,
+            hold: 1,
+            pace: 0,
+            source_hz: SMI_SOURCE_HZ,
+        }
+    }
+}
+
+impl SmiTiming {
+    /// Length of a single SMI clock tick.
+    pub fn tick_ns(&self) -> f64 {
+        self.divider as f64 * 1e9 / self.source_hz as f64
+    }
+
+    /// Length of one SMI sample, i.e. one pulse of a bit cell.
+    pub fn sample_ns(&self) -> f64 {
+        let ticks = self.setup as usize
+            + self.strobe as usize
+            + self.hold as usize
+            + self.pace as usize;
+        ticks as f64 * self.tick_ns()
+    }
+
+    /// Closest settings to a sample length of `sample_ns` off a clock
+    /// source running at `source_hz`.
+    pub fn for_sample(sample_ns: f64, source_hz: u32) -> SmiTiming {
+        let source_ns = 1e9 / source_hz as f64;
+        let mut best = (f64::INFINITY, 1, 3);
+        for divider in 1..=MAX_DIVIDER {
+            let tick_ns = source_ns * divider as f64;
+            let ticks = ((sample_ns / tick_ns).round() as usize).clamp(3, MAX_SETUP + MAX_STROBE + MAX_HOLD);
+            let error = (ticks as f64 * tick_ns - sample_ns).abs();
+            if error < best.0 {
+                best = (error, divider, ticks);
+            }
+        }
+
+        // one tick of setup and hold, strobe takes the rest until it runs out
+        let (_, divider, ticks) = best;
+        let strobe = (ticks - 2).min(MAX_STROBE);
+        let rest = ticks - 2 - strobe;
+        SmiTiming {
+            divider,
+            setup: (1 + rest / 2) as u8,
+            strobe: strobe as u8,
+            hold: (1 + rest - rest / 2) as u8,
+            pace: 0,
+            source_hz,
+        }
+    }
+
+    /// The same sample length (as close as it gets) off a clock source
+    /// running at `source_hz` instead.
+    pub fn at_source(&self, source_hz: u32) -> SmiTiming {
+        if self.source_hz == source_hz {
+            *self
+        } else {
+            SmiTiming::for_sample(self.sample_ns(), source_hz)
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.source_hz > 0
+            && (1..=MAX_DIVIDER).contains(&self.divider)
+            && (1..=MAX_SETUP).contains(&(self.setup as usize))
+            && (1..=MAX_STROBE).contains(&(self.strobe as usize))
+            && self.hold as usize <= MAX_HOLD
+            && self.pace as usize <= MAX_STROBE
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum TimingError {
+    #[error("can't meet the {chip} timings within {tolerance_ns}ns, the closest is {error_ns:.0}ns off")]
+    OutOfTolerance {
+        chip: &'static str,
+        tolerance_ns: u32,
+        error_ns: f64,
+    },
+}
+
+/// Datasheet timings of an LED chip's data line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChipTiming {
+    pub name: &'static str,
+    /// High time of a 0 bit.
+    pub t0h_ns: u32,
+    /// High time of a 1 bit.
+    pub t1h_ns: u32,
+    /// Length of a whole bit.
+    pub period_ns: u32,
+    /// Low time that latches the frame.
+    pub reset_ns: u32,
+    /// How far any of the high times or the period may be off.
+    pub tolerance_ns: u32,
+}
+
+pub const WS2812B: ChipTiming = ChipTiming {
+    name: "WS2812B",
+    t0h_ns: 400,
+    t1h_ns: 800,
+    period_ns: 1250,
+    // newer (V5) batches want 280us
+    reset_ns: 50_000,
+    tolerance_ns: 150,
+};
+
+// low speed (400kHz) mode
+pub const WS2811: ChipTiming = ChipTiming {
+    name: "WS2811",
+    t0h_ns: 500,
+    t1h_ns: 1200,
+    period_ns: 2500,
+    reset_ns: 50_000,
+    tolerance_ns: 150,
+};
+
+pub const SK6812: ChipTiming = ChipTiming {
+    name: "SK6812",
+    t0h_ns: 300,
+    t1h_ns: 600,
+    period_ns: 1250,
+    reset_ns: 80_000,
+    tolerance_ns: 150,
+};
+
+pub const WS2813: ChipTiming = ChipTiming {
+    name: "WS2813",
+    t0h_ns: 300,
+    t1h_ns: 750,
+    period_ns: 1250,
+    reset_ns: 300_000,
+    tolerance_ns: 80,
+};
+
+pub const TM1814: ChipTiming = ChipTiming {
+    name: "TM1814",
+    t0h_ns: 360,
+    t1h_ns: 720,
+    period_ns: 1250,
+    reset_ns: 200_000,
+    tolerance_ns: 150,
+};
+
+/// Every profile above, for looking chips up by name.
+pub const CHIPS: [ChipTiming; 5] = [WS2812B, WS2811, SK6812, WS2813, TM1814];
+
+impl ChipTiming {
+    /// The profile called `name`, ignoring case.
+    pub fn by_name(name: &str) -> Option<ChipTiming> {
+        CHIPS.into_iter().find(|chip| chip.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Finds the SMI settings and bit layout that get closest to the
+    /// datasheet timings, preferring the fewest pulses per bit that stay
+    /// within the tolerance.
+    ///
+    /// This solves for the 500MHz SMI clock source of the BCM2835-7, use
+    /// `solve_for` with `Soc::smi_source_hz` on a Pi 4.
+    pub fn solve(&self) -> Result<LedTiming, TimingError> {
+        self.solve_for(SMI_SOURCE_HZ)
+    }
+
+    pub fn solve_for(&self, source_hz: u32) -> Result<LedTiming, TimingError> {
+        let mut best: Option<(f64, LedTiming)> = None;
+
+        for npulses in 3..=MAX_NPULSES {
+            let smi = SmiTiming::for_sample(self.period_ns as f64 / npulses as f64, source_hz);
+            let sample = smi.sample_ns();
+
+            let t0_pulses = ((self.t0h_ns as f64 / sample).round() as usize).max(1);
+            if t0_pulses + 1 >= npulses {
+                continue;
+            }
+            let t1_pulses = ((self.t1h_ns as f64 / sample).round() as usize)
+                .clamp(t0_pulses + 1, npulses - 1);
+
+            let timing = LedTiming {
+                chip: *self,
+                smi,
+                bit: BitTiming { npulses, t0_pulses, t1_pulses },
+                reset_samples: (self.reset_ns as f64 / sample).ceil() as usize,
+            };
+            let error = timing.max_error_ns();
+            if error <= self.tolerance_ns as f64 {
+                return Ok(timing);
+            }
+            if best.as_ref().is_none_or(|(best, _)| error < *best) {
+                best = Some((error, timing));
+            }
+        }
+
+        Err(TimingError::OutOfTolerance {
+            chip: self.name,
+            tolerance_ns: self.tolerance_ns,
+            error_ns: best.map_or(f64::INFINITY, |(error, _)| error),
+        })
+    }
+}
+
+/// SMI settings and bit layout solved for a chip, see `ChipTiming::solve`.
+///
+/// Formatting it with `{}` gives a report of the achieved timings against
+/// the datasheet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LedTiming {
+    pub chip: ChipTiming,
+    pub smi: SmiTiming,
+    pub bit: BitTiming,
+    /// Number of low samples needed for the reset gap.
+    pub reset_samples: usize,
+}
+
+impl LedTiming {
+    pub fn sample_ns(&self) -> f64 {
+        self.smi.sample_ns()
+    }
+
+    pub fn t0h_ns(&self) -> f64 {
+        self.bit.t0_pulses as f64 * self.sample_ns()
+    }
+
+    pub fn t1h_ns(&self) -> f64 {
+        self.bit.t1_pulses as f64 * self.sample_ns()
+    }
+
+    pub fn period_ns(&self) -> f64 {
+        self.bit.npulses as f64 * self.sample_ns()
+    }
+
+    pub fn reset_ns(&self) -> f64 {
+        self.reset_samples as f64 * self.sample_ns()
+    }
+
+    /// Largest difference between the achieved high times or period and the
+    /// datasheet values.
+    pub fn max_error_ns(&self) -> f64 {
+        [
+            self.t0h_ns() - self.chip.t0h_ns as f64,
+            self.t1h_ns() - self.chip.t1h_ns as f64,
+            self.period_ns() - self.chip.period_ns as f64,
+        ]
+        .iter()
+        .fold(0.0, |max: f64, error| max.max(error.abs()))
+    }
+}
+
+impl fmt::Display for LedTiming {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let SmiTiming { divider, setup, strobe, hold, source_hz, .. } = self.smi;
+        write!(
+            f,
+            "{}: {:.1}ns samples ({}MHz / {}, setup {}, strobe {}, hold {}), {} pulses per bit",
+            self.chip.name,
+            self.sample_ns(),
+            source_hz / 1_000_000,
+            divider,
+            setup,
+            strobe,
+            hold,
+            self.bit.npulses
+        )?;
+
+        let rows = [
+            ("T0H", self.t0h_ns(), self.chip.t0h_ns),
+            ("T1H", self.t1h_ns(), self.chip.t1h_ns),
+            ("period", self.period_ns(), self.chip.period_ns),
+            ("reset", self.reset_ns(), self.chip.reset_ns),
+        ];
+        for (name, actual, spec) in rows {
+            write!(
+                f,
+                "\n  {:<6} {:>9.1}ns (spec {}ns, {:+.1}ns)",
+                name, actual, spec, actual - spec as f64
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn solves_every_profile_within_tolerance() {
+        for chip in CHIPS {
+            let timing = chip.solve().unwrap();
+            assert!(timing.smi.is_valid(), "{:?}", timing.smi);
+            assert!(timing.max_error_ns() <= chip.tolerance_ns as f64, "{}", timing);
+            assert!(timing.reset_ns() >= chip.reset_ns as f64);
+        }
+
+        let ws2812b = WS2812B.solve().unwrap();
+        assert_eq!(ws2812b.bit, BitTiming { npulses: 3, t0_pulses: 1, t1_pulses: 2 });
+    }
+
+    #[test]
+    fn rejects_unreachable_timings() {
+        let chip = ChipTiming {
+            name: "fast",
+            t0h_ns: 10,
+            t1h_ns: 1000,
+            period_ns: 1010,
+            reset_ns: 1000,
+            tolerance_ns: 1,
+        };
+        assert!(matches!(chip.solve(), Err(TimingError::OutOfTolerance { chip: "fast", .. })));
+    }
+
+    #[test]
+    fn default_smi_timing_is_420ns() {
+        assert!((SmiTiming::default().sample_ns() - 420.0).abs() < 1e-9);
+
+        // the BCM2711 runs PLLD at 750MHz, it still gets there exactly
+        let bcm2711 = SmiTiming::default().at_source(750_000_000);
+        assert!(bcm2711.is_valid());
+        assert!((bcm2711.sample_ns() - 420.0).abs() < 1e-9);
+        assert_eq!(WS2812B.solve_for(750_000_000).unwrap().bit.npulses, 3);
+    }
+}
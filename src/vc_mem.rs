@@ -1,5 +1,3 @@
-#![allow(dead_code)]
-
 use std::fs::OpenOptions;
 use std::ops::{Deref, DerefMut};
 
@@ -13,77 +11,98 @@ use rpi_mailbox::{
     Mailbox
 };
 
+use crate::board::Platform;
+use crate::error::{Error, Result};
+use crate::hal::SimMemory;
+
 pub struct VcMem {
-    mb: Mailbox,
-    handle: u32,
     busaddr: usize,
-    physaddr: usize,
-    mapping: Option<MmapMut>,
+    memory: Memory,
+}
+
+enum Memory {
+    Mailbox {
+        mb: Mailbox,
+        handle: u32,
+        mapping: Option<MmapMut>,
+    },
+    Simulated(SimMemory),
 }
 
 impl VcMem {
-    pub fn new(size: u32, alignment: u32) -> Self {
-        let mb = Mailbox::new("/dev/vcio").expect("mailbox");
+    /// Allocates and maps `size` bytes of uncached memory from the firmware,
+    /// `platform` gives the bus alias it comes back in.
+    pub fn new(size: u32, alignment: u32, platform: &Platform) -> Result<Self> {
+        if size == 0 || !alignment.is_power_of_two() {
+            return Err(Error::InvalidAllocation { size, alignment });
+        }
+
+        let mb = Mailbox::new("/dev/vcio")?;
 
         let devmem = OpenOptions::new()
             .read(true)
             .write(true)
             .open("/dev/mem")
-            .expect("Failed to open /dev/mem");
+            .map_err(|source| Error::Open { path: "/dev/mem", source })?;
 
         let handle = mailbox_mem_alloc(
             &mb,
             size,
             alignment,
             memflag::Flags::MEM_FLAG_DIRECT | memflag::Flags::MEM_FLAG_ZERO
-        ).expect("Failed to allocate memory");
-        let busaddr = mailbox_mem_lock(&mb, handle).map_err(|err| {
+        )?;
+        let busaddr = mailbox_mem_lock(&mb, handle).inspect_err(|_| {
             mailbox_mem_free(&mb, handle).ok();
-            err
-        }).expect("Failed to lock memory");
+        })?;
 
         let busaddr = busaddr as usize;
-        let physaddr = (busaddr & !0xC0000000) as usize;
+        let physaddr = busaddr & !platform.dram_bus_alias;
+
+        // from here on dropping the VcMem unlocks and frees the allocation
+        let mut vc_mem = Self {
+            busaddr,
+            memory: Memory::Mailbox {
+                mb,
+                handle,
+                mapping: None,
+            },
+        };
 
         let mapping = unsafe {
             MmapOptions::new()
                 .offset(physaddr as u64)
                 .len(size as usize)
-                .map_mut(&devmem)
-                .expect("Failed to map memory")
+                .map_mut(&devmem)?
         };
-
-        Self {
-            mb,
-            handle,
-            busaddr,
-            physaddr,
-            mapping: Some(mapping),
+        if let Memory::Mailbox { mapping: slot, .. } = &mut vc_mem.memory {
+            *slot = Some(mapping);
         }
-    }
 
-    #[inline]
-    pub fn as_ref(&self) -> &[u8] {
-        self.mapping.as_ref().unwrap()
+        Ok(vc_mem)
     }
 
-    #[inline]
-    pub fn as_mut(&mut self) -> &mut [u8] {
-        self.mapping.as_mut().unwrap()
+    pub(crate) fn simulated(memory: SimMemory) -> Self {
+        Self {
+            busaddr: memory.busaddr(),
+            memory: Memory::Simulated(memory),
+        }
     }
 
     #[inline]
     pub fn as_ptr(&self) -> *const u8 {
-        self.mapping.as_ref().unwrap().as_ptr()
+        self.as_ref().as_ptr()
     }
 
     #[inline]
     pub fn as_mut_ptr(&mut self) -> *mut u8 {
-        self.mapping.as_mut().unwrap().as_mut_ptr()
+        self.as_mut().as_mut_ptr()
     }
 
-    pub fn flush(&self) {
-        self.mapping.as_ref().unwrap().flush().expect("Failed to flush memory");
+    pub fn flush(&self) -> Result<()> {
+        if let Memory::Mailbox { mapping: Some(mapping), .. } = &self.memory {
+            mapping.flush()?;
+        }
+        Ok(())
     }
 
     pub fn busaddr(&self) -> usize {
@@ -94,14 +113,20 @@ impl VcMem {
 impl AsRef<[u8]> for VcMem {
     #[inline]
     fn as_ref(&self) -> &[u8] {
-        self.as_ref()
+        match &self.memory {
+            Memory::Mailbox { mapping, .. } => mapping.as_ref().unwrap(),
+            Memory::Simulated(memory) => memory.as_slice(),
+        }
     }
 }
 
 impl AsMut<[u8]> for VcMem {
     #[inline]
     fn as_mut(&mut self) -> &mut [u8] {
-        self.as_mut()
+        match &mut self.memory {
+            Memory::Mailbox { mapping, .. } => mapping.as_mut().unwrap(),
+            Memory::Simulated(memory) => memory.as_mut_slice(),
+        }
     }
 }
 
@@ -123,8 +148,10 @@ impl DerefMut for VcMem {
 
 impl Drop for VcMem {
     fn drop(&mut self) {
-        self.mapping.take();
-        mailbox_mem_unlock(&self.mb, self.busaddr as u32).ok();
-        mailbox_mem_free(&self.mb, self.handle).ok();
+        if let Memory::Mailbox { mb, handle, mapping } = &mut self.memory {
+            mapping.take();
+            mailbox_mem_unlock(mb, self.busaddr as u32).ok();
+            mailbox_mem_free(mb, *handle).ok();
+        }
     }
 }
\ No newline at end of file